@@ -0,0 +1,71 @@
+use std::net::IpAddr;
+
+use crate::application::handler::error_page_handler::error_response;
+use crate::application::log::error::{self, Level};
+use crate::config::EffectiveLocation;
+use crate::http::{Response, StatusCode};
+
+/// Applies the location's `allow` and `deny` rules to the client address.
+/// The first matching rule decides; an address no rule matches is allowed.
+/// A denied request gets the 403 error page.
+pub fn check(addr: IpAddr, loc: &EffectiveLocation) -> Result<(), Response> {
+    match loc.access_rules.iter().find(|rule| rule.matches(addr)) {
+        Some(rule) if !rule.allow => {
+            error::log(Level::Error, "access forbidden by rule");
+            Err(error_response(StatusCode::Forbidden, loc))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::parse_config;
+    use std::path::Path;
+
+    fn allowed(rules: &str, addr: &str) -> bool {
+        let cfg = parse_config(&format!("server {{ listen 8080; {rules} }}"), Path::new("."), "test.conf").unwrap();
+        check(addr.parse().unwrap(), &cfg.servers[0].effective).is_ok()
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let rules = "deny 10.1.2.3; allow 10.0.0.0/8; deny 10.2.0.0/16; deny all;";
+        assert!(!allowed(rules, "10.1.2.3"));
+        assert!(allowed(rules, "10.1.2.4"));
+        // Already allowed by the /8
+        assert!(allowed(rules, "10.2.0.1"));
+        assert!(!allowed(rules, "11.0.0.1"));
+        assert!(!allowed(rules, "::1"));
+        // Rules apply to IPv4 clients of an IPv6 socket as IPv4
+        assert!(!allowed(rules, "::ffff:10.1.2.3"));
+        assert!(allowed(rules, "::ffff:10.9.9.9"));
+    }
+
+    #[test]
+    fn test_no_match_is_allowed() {
+        assert!(allowed("", "192.0.2.1"));
+        assert!(allowed("deny 10.0.0.0/8;", "192.0.2.1"));
+        assert!(allowed("deny ::1;", "127.0.0.1"));
+    }
+
+    #[test]
+    fn test_whole_families() {
+        let rules = "allow 0.0.0.0/0; deny all;";
+        assert!(allowed(rules, "203.0.113.9"));
+        assert!(allowed(rules, "::ffff:203.0.113.9"));
+        assert!(!allowed(rules, "2001:db8::1"));
+        let rules = "deny ::/0; allow all;";
+        assert!(!allowed(rules, "2001:db8::1"));
+        assert!(allowed(rules, "::ffff:203.0.113.9"));
+        assert!(allowed(rules, "203.0.113.9"));
+    }
+
+    #[test]
+    fn test_denied_response() {
+        let cfg = parse_config("server { listen 8080; deny 192.0.2.1/32; }", Path::new("."), "test.conf").unwrap();
+        let resp = check("192.0.2.1".parse().unwrap(), &cfg.servers[0].effective).unwrap_err();
+        assert_eq!(resp.status.as_u16(), 403);
+    }
+}
@@ -0,0 +1,154 @@
+use super::htpasswd;
+use crate::application::handler::error_page_handler::error_response;
+use crate::application::log::error::{self, Level};
+use crate::config::{AuthBasic, EffectiveLocation};
+use crate::http::headers::Headers;
+use crate::http::{Response, StatusCode};
+
+/// The user name and password of an `Authorization: Basic` header, whether
+/// or not they are valid.
+pub fn credentials(headers: &Headers) -> Option<(String, String)> {
+    let (_, value) = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("Authorization"))?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(decode_base64(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Checks a request's credentials against the location's user file. Returns
+/// the user, or the response to send instead: 401 with a challenge for the
+/// realm, or 500 if the user file cannot be read.
+pub fn check(auth: &AuthBasic, headers: &Headers, loc: &EffectiveLocation) -> Result<String, Response> {
+    let Some(user_file) = &auth.user_file else {
+        error::log(Level::Error, "auth_basic without auth_basic_user_file");
+        return Err(error_response(StatusCode::InternalServerError, loc));
+    };
+    let Some((user, password)) = credentials(headers) else {
+        return Err(challenge(auth, loc));
+    };
+    let hash = match htpasswd::lookup(user_file, &user) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => {
+            error::log(Level::Error, &format!("user {user:?} was not found in {}", user_file.display()));
+            return Err(challenge(auth, loc));
+        }
+        Err(e) => {
+            error::log(Level::Error, &e);
+            return Err(error_response(StatusCode::InternalServerError, loc));
+        }
+    };
+    match htpasswd::verify(&password, &hash) {
+        Some(true) => Ok(user),
+        Some(false) => {
+            error::log(Level::Error, &format!("user {user:?}: password mismatch"));
+            Err(challenge(auth, loc))
+        }
+        None => {
+            error::log(Level::Error, &format!("user {user:?} in {} has a password hash of an unsupported format", user_file.display()));
+            Err(challenge(auth, loc))
+        }
+    }
+}
+
+fn challenge(auth: &AuthBasic, loc: &EffectiveLocation) -> Response {
+    let mut resp = error_response(StatusCode::Unauthorized, loc);
+    let realm = auth.realm.replace('\\', "\\\\").replace('"', "\\\"");
+    resp.headers.insert("WWW-Authenticate".into(), format!("Basic realm=\"{realm}\""));
+    resp
+}
+
+/// Standard base64, padding optional.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::parse_config;
+    use std::path::Path;
+
+    fn headers(authorization: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.insert("Authorization".into(), authorization.into());
+        headers
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("").as_deref(), Some(&b""[..]));
+        assert_eq!(decode_base64("TQ==").as_deref(), Some(&b"M"[..]));
+        assert_eq!(decode_base64("TWE=").as_deref(), Some(&b"Ma"[..]));
+        assert_eq!(decode_base64("TWFu").as_deref(), Some(&b"Man"[..]));
+        // Padding is optional
+        assert_eq!(decode_base64("TWE").as_deref(), Some(&b"Ma"[..]));
+        assert_eq!(decode_base64("+/+/").as_deref(), Some(&[0xfb, 0xff, 0xbf][..]));
+        for bad in ["TW E", "TW-_", "TW=E", "é"] {
+            assert_eq!(decode_base64(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_credentials() {
+        // RFC 7617's example
+        let expected = Some(("Aladdin".to_string(), "open sesame".to_string()));
+        assert_eq!(credentials(&headers("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")), expected);
+        assert_eq!(credentials(&headers("  basic   QWxhZGRpbjpvcGVuIHNlc2FtZQ ")), expected);
+        let mut lower = Headers::new();
+        lower.insert("authorization".into(), "BASIC QWxhZGRpbjpvcGVuIHNlc2FtZQ==".into());
+        assert_eq!(credentials(&lower), expected);
+
+        // Only the first colon separates them; either may be empty
+        assert_eq!(credentials(&headers("Basic YTpiOmM=")), Some(("a".into(), "b:c".into())));
+        assert_eq!(credentials(&headers("Basic Og==")), Some((String::new(), String::new())));
+
+        assert_eq!(credentials(&Headers::new()), None);
+        for bad in ["Bearer QWxhZGRpbjpvcGVuIHNlc2FtZQ==", "Basic", "Basic bm9jb2xvbg==", "Basic !!!!", "Basic /w=="] {
+            assert_eq!(credentials(&headers(bad)), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let path = std::env::temp_dir().join(format!("localhost-basic-{}", std::process::id()));
+        std::fs::write(&path, "Aladdin:$apr1$rOioh4Wh$RlCC6EfkvF0SZLBHbae4Q.\n").unwrap();
+        let cfg = parse_config("server { listen 8080; }", Path::new("."), "test.conf").unwrap();
+        let loc = &cfg.servers[0].effective;
+        let auth = AuthBasic { realm: "Say \"hi\"".into(), user_file: Some(path.clone()) };
+
+        // Aladdin:myPassword
+        assert_eq!(check(&auth, &headers("Basic QWxhZGRpbjpteVBhc3N3b3Jk"), loc).unwrap(), "Aladdin");
+        for authorization in ["", "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==", "Basic Ym9iOm15UGFzc3dvcmQ="] {
+            let resp = check(&auth, &headers(authorization), loc).unwrap_err();
+            assert_eq!(resp.status.as_u16(), 401, "{authorization}");
+            assert_eq!(resp.headers.get("WWW-Authenticate").map(String::as_str), Some("Basic realm=\"Say \\\"hi\\\"\""));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        let resp = check(&auth, &headers("Basic QWxhZGRpbjpteVBhc3N3b3Jk"), loc).unwrap_err();
+        assert_eq!(resp.status.as_u16(), 500);
+        let no_file = AuthBasic { realm: "x".into(), user_file: None };
+        assert_eq!(check(&no_file, &headers(""), loc).unwrap_err().status.as_u16(), 500);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{bcrypt, md5, sha256};
+
+/// A user file as last read, with what tells whether it changed since.
+struct UserFile {
+    version: (i64, i64, u64, u64),
+    /// Password hash by user name
+    users: HashMap<String, String>,
+}
+
+static FILES: Mutex<Vec<(PathBuf, UserFile)>> = Mutex::new(Vec::new());
+
+/// The password hash of `user` in the htpasswd file at `path`. The file is
+/// read again whenever its modification time, size or inode changed, so
+/// edits apply without a reload.
+pub fn lookup(path: &Path, user: &str) -> Result<Option<String>, String> {
+    let meta = fs::metadata(path).map_err(|e| format!("cannot read user file {}: {e}", path.display()))?;
+    let version = (meta.mtime(), meta.mtime_nsec(), meta.len(), meta.ino());
+    let mut files = FILES.lock().unwrap_or_else(|e| e.into_inner());
+    let index = match files.iter().position(|(p, _)| p == path) {
+        Some(index) if files[index].1.version == version => index,
+        found => {
+            let text = fs::read_to_string(path).map_err(|e| format!("cannot read user file {}: {e}", path.display()))?;
+            let file = UserFile { version, users: parse(&text) };
+            match found {
+                Some(index) => {
+                    files[index].1 = file;
+                    index
+                }
+                None => {
+                    files.push((path.to_path_buf(), file));
+                    files.len() - 1
+                }
+            }
+        }
+    };
+    Ok(files[index].1.users.get(user).cloned())
+}
+
+/// `user:hash` lines; blank lines and `#` comments are skipped, and so is
+/// anything after a second `:`.
+fn parse(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            Some((fields.next()?.to_string(), fields.next()?.to_string()))
+        })
+        .collect()
+}
+
+/// Whether `password` matches `hash`, a bcrypt (`$2a$`, `$2b$`, `$2y$`),
+/// SHA-256-crypt (`$5$`) or apr1 (`$apr1$`) hash. `None` for any other
+/// format.
+pub fn verify(password: &str, hash: &str) -> Option<bool> {
+    let password = password.as_bytes();
+    let (computed, expected) = if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let (salt, expected) = rest.split_once('$')?;
+        (md5::apr1(password, salt.as_bytes()), expected)
+    } else if let Some(rest) = hash.strip_prefix("$5$") {
+        let (setting, expected) = rest.rsplit_once('$')?;
+        (sha256::sha256_crypt(password, setting), expected)
+    } else if let Some(rest) = ["$2a$", "$2b$", "$2y$"].iter().find_map(|prefix| hash.strip_prefix(prefix)) {
+        let expected = rest.split_once('$')?.1.get(22..)?;
+        (bcrypt::bcrypt(password, rest)?, expected)
+    } else {
+        return None;
+    };
+    // Compares every byte, so the time taken does not tell how much matched
+    let same = computed.len() == expected.len()
+        && computed.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    Some(same)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let hashes = [
+            "$apr1$rOioh4Wh$RlCC6EfkvF0SZLBHbae4Q.",
+            "$5$saltstring$MkP5qKcKBbjj8bsVcXLwsVnoyIfhVxvKRnDs04znYE3",
+            "$2a$05$abcdefghijklmnopqrstuugwV8qBBvnFAgKU/1lAQTjzoU4mNz2BW",
+            "$2b$05$abcdefghijklmnopqrstuugwV8qBBvnFAgKU/1lAQTjzoU4mNz2BW",
+            "$2y$05$abcdefghijklmnopqrstuugwV8qBBvnFAgKU/1lAQTjzoU4mNz2BW",
+        ];
+        for hash in hashes {
+            assert_eq!(verify("myPassword", hash), Some(true), "{hash}");
+            assert_eq!(verify("mypassword", hash), Some(false), "{hash}");
+            assert_eq!(verify("", hash), Some(false), "{hash}");
+        }
+    }
+
+    #[test]
+    fn test_malformed_hashes() {
+        for hash in [
+            "",
+            "myPassword",
+            "{SHA}VBPuJHI7uixaa6LQGWx4s+5GKNE=",
+            "$1$rOioh4Wh$RlCC6EfkvF0SZLBHbae4Q.",
+            "$6$saltstring$x",
+            "$apr1$rOioh4Wh",
+            "$5$saltstring",
+            "$2b$05",
+            "$2b$05$abcdefghijklmnopqrstu",
+            "$2b$99$abcdefghijklmnopqrstuugwV8qBBvnFAgKU/1lAQTjzoU4mNz2BW",
+            "$2b$05$abcdefghijklmnopqrstu!gwV8qBBvnFAgKU/1lAQTjzoU4mNz2BW",
+        ] {
+            assert_eq!(verify("myPassword", hash), None, "{hash}");
+        }
+        // Well-formed up to a hash that is cut short, which matches nothing
+        assert_eq!(verify("myPassword", "$apr1$rOioh4Wh$RlCC6EfkvF0SZ"), Some(false));
+        assert_eq!(verify("myPassword", "$2b$05$abcdefghijklmnopqrstuugwV8qBB"), Some(false));
+        assert_eq!(verify("myPassword", "$apr1$rOioh4Wh$"), Some(false));
+    }
+
+    #[test]
+    fn test_parse() {
+        let users = parse("# users\n\n  alice:$apr1$a$b  \nbob:hash:extra\nnocolon\n:empty\n");
+        assert_eq!(users.len(), 3);
+        assert_eq!(users["alice"], "$apr1$a$b");
+        assert_eq!(users["bob"], "hash");
+        assert_eq!(users[""], "empty");
+    }
+
+    #[test]
+    fn test_lookup_reads_changes() {
+        let path = std::env::temp_dir().join(format!("localhost-htpasswd-{}", std::process::id()));
+        fs::write(&path, "alice:one\n").unwrap();
+        assert_eq!(lookup(&path, "alice").unwrap().as_deref(), Some("one"));
+        assert_eq!(lookup(&path, "bob").unwrap(), None);
+        // A different size, so read again even within the same mtime
+        fs::write(&path, "alice:one\nbob:two\n").unwrap();
+        assert_eq!(lookup(&path, "bob").unwrap().as_deref(), Some("two"));
+        fs::remove_file(&path).unwrap();
+        let err = lookup(&path, "alice").unwrap_err();
+        assert!(err.starts_with("cannot read user file"), "{err}");
+    }
+}
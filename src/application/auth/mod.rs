@@ -0,0 +1,19 @@
+pub mod address;
+pub mod basic;
+pub mod htpasswd;
+mod bcrypt;
+mod md5;
+mod sha256;
+
+/// Alphabet of the base64 that MD5- and SHA-crypt encode their digests with
+const CRYPT_ALPHABET: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Appends `chars` characters encoding the 24 bits `a b c`, least significant
+/// first.
+fn crypt_base64(out: &mut String, a: u8, b: u8, c: u8, chars: usize) {
+    let mut bits = (a as u32) << 16 | (b as u32) << 8 | c as u32;
+    for _ in 0..chars {
+        out.push(CRYPT_ALPHABET[(bits & 63) as usize] as char);
+        bits >>= 6;
+    }
+}
@@ -0,0 +1,180 @@
+use super::crypt_base64;
+
+/// Fractional parts of the cube roots of the first 64 primes
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 of `data` (FIPS 180-4).
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (bytes, word) in out.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+/// Rounds of SHA-256-crypt without a `rounds=` parameter, and its bounds
+const DEFAULT_ROUNDS: u32 = 5000;
+const MIN_ROUNDS: u32 = 1000;
+const MAX_ROUNDS: u32 = 999_999_999;
+
+/// The hash part of a `$5$[rounds=N$]salt$hash` password (Ulrich Drepper's
+/// SHA-crypt), `setting` being what comes between `$5$` and the hash.
+pub fn sha256_crypt(password: &[u8], setting: &str) -> String {
+    let (rounds, salt) = match setting.strip_prefix("rounds=").and_then(|r| r.split_once('$')) {
+        Some((n, salt)) => {
+            let rounds = n.parse::<u64>().map_or(DEFAULT_ROUNDS, |n| n.clamp(MIN_ROUNDS.into(), MAX_ROUNDS.into()) as u32);
+            (rounds, salt)
+        }
+        None => (DEFAULT_ROUNDS, setting),
+    };
+    let salt = salt.as_bytes();
+    let salt = &salt[..salt.len().min(16)];
+
+    let alternate = sha256(&[password, salt, password].concat());
+    let mut input = [password, salt].concat();
+    let mut left = password.len();
+    while left > 32 {
+        input.extend_from_slice(&alternate);
+        left -= 32;
+    }
+    input.extend_from_slice(&alternate[..left]);
+    let mut bits = password.len();
+    while bits > 0 {
+        if bits & 1 == 1 {
+            input.extend_from_slice(&alternate);
+        } else {
+            input.extend_from_slice(password);
+        }
+        bits >>= 1;
+    }
+    let mut digest = sha256(&input);
+
+    let p = repeat_to(&sha256(&password.repeat(password.len())), password.len());
+    let s = repeat_to(&sha256(&salt.repeat(16 + digest[0] as usize)), salt.len());
+
+    for round in 0..rounds {
+        let mut input = Vec::with_capacity(p.len() * 2 + s.len() + 32);
+        if round & 1 == 1 {
+            input.extend_from_slice(&p);
+        } else {
+            input.extend_from_slice(&digest);
+        }
+        if round % 3 != 0 {
+            input.extend_from_slice(&s);
+        }
+        if round % 7 != 0 {
+            input.extend_from_slice(&p);
+        }
+        if round & 1 == 1 {
+            input.extend_from_slice(&digest);
+        } else {
+            input.extend_from_slice(&p);
+        }
+        digest = sha256(&input);
+    }
+
+    let d = digest;
+    let mut out = String::with_capacity(43);
+    for (a, b, c) in [
+        (0, 10, 20), (21, 1, 11), (12, 22, 2), (3, 13, 23), (24, 4, 14),
+        (15, 25, 5), (6, 16, 26), (27, 7, 17), (18, 28, 8), (9, 19, 29),
+    ] {
+        crypt_base64(&mut out, d[a], d[b], d[c], 4);
+    }
+    crypt_base64(&mut out, 0, d[31], d[30], 3);
+    out
+}
+
+/// `digest` repeated, and cut, to `len` bytes.
+fn repeat_to(digest: &[u8], len: usize) -> Vec<u8> {
+    digest.iter().copied().cycle().take(len).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // Two blocks
+        assert_eq!(
+            hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_sha256_crypt() {
+        // The examples of Drepper's specification
+        let cases = [
+            ("saltstring", "Hello world!", "5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5"),
+            ("rounds=10000$saltstringsaltstring", "Hello world!", "3xv.VbSHBb41AL9AvLeujZkZRBAwqFMz2.opqey6IcA"),
+            ("rounds=5000$toolongsaltstring", "This is just a test", "Un/5jzAHMgOGZ5.mWJpuVolil07guHPvOW8mGRcvxa5"),
+            (
+                "rounds=1400$anotherlongsaltstring",
+                "a very much longer text to encrypt.  This one even stretches over morethan one line.",
+                "Rx.j8H.h8HjEDGomFU8bDkXm3XIUnzyxf12oP84Bnq1",
+            ),
+            ("rounds=77777$short", "we have a short salt string but not a short password", "JiO1O3ZpDAxGJeaDIuqCoEFysAe1mZNJRs3pw0KQRd/"),
+            ("rounds=123456$asaltof16chars..", "a short string", "gP3VQ/6X7UUEW3HkBn2w1/Ptq2jxPyzV/cZKmF/wJvD"),
+            // Below the minimum, counted as 1000 rounds
+            ("rounds=10$roundstoolow", "the minimum number is still observed", "yfvwcWrQ8l/K0DAWyuPMDNHpIVlTQebY9l/gL972bIC"),
+        ];
+        for (setting, password, hash) in cases {
+            assert_eq!(sha256_crypt(password.as_bytes(), setting), hash, "{setting}");
+        }
+        assert_eq!(sha256_crypt(b"Hello world!", "rounds=1000$roundstoolow"), sha256_crypt(b"Hello world!", "rounds=10$roundstoolow"));
+        // Not a number, so the default
+        assert_eq!(sha256_crypt(b"Hello world!", "rounds=x$saltstring"), cases[0].2);
+    }
+}
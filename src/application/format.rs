@@ -0,0 +1,163 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// `0.012`, seconds with millisecond precision.
+pub fn seconds(d: Duration) -> String {
+    format!("{}.{:03}", d.as_secs(), d.subsec_millis())
+}
+
+/// `secs` since the epoch in the local time zone, or in UTC.
+pub fn broken_down_time(secs: i64, localtime: bool) -> libc::tm {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        if localtime {
+            libc::localtime_r(&t, &mut tm);
+        } else {
+            libc::gmtime_r(&t, &mut tm);
+        }
+    }
+    tm
+}
+
+fn local_tm(now: SystemTime) -> libc::tm {
+    broken_down_time(now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64), true)
+}
+
+/// `+0200`, or `+02:00` with `colon`.
+fn utc_offset(tm: &libc::tm, colon: bool) -> String {
+    let offset = tm.tm_gmtoff / 60;
+    let sign = if offset < 0 { '-' } else { '+' };
+    let sep = if colon { ":" } else { "" };
+    format!("{sign}{:02}{sep}{:02}", offset.abs() / 60, offset.abs() % 60)
+}
+
+/// `18/Oct/2026:14:05:09 +0200`
+pub fn time_local(now: SystemTime) -> String {
+    let tm = local_tm(now);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} {}",
+        tm.tm_mday,
+        MONTHS[tm.tm_mon as usize % 12],
+        tm.tm_year + 1900,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        utc_offset(&tm, false)
+    )
+}
+
+/// `2026-10-18T14:05:09+02:00`
+pub fn time_iso8601(now: SystemTime) -> String {
+    let tm = local_tm(now);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}{}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        utc_offset(&tm, true)
+    )
+}
+
+/// RFC 7231 date, always in GMT.
+pub fn http_date(secs: i64) -> String {
+    let tm = broken_down_time(secs, false);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[tm.tm_wday as usize % 7],
+        tm.tm_mday,
+        MONTHS[tm.tm_mon as usize % 12],
+        tm.tm_year + 1900,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+pub fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// A JSON string literal, quotes included.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seconds() {
+        assert_eq!(seconds(Duration::from_micros(12_999)), "0.012");
+        assert_eq!(seconds(Duration::from_millis(61_005)), "61.005");
+    }
+
+    #[test]
+    fn test_utc_offset() {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_gmtoff = -(3 * 3600 + 30 * 60);
+        assert_eq!((utc_offset(&tm, false), utc_offset(&tm, true)), ("-0330".to_string(), "-03:30".to_string()));
+        tm.tm_gmtoff = 5 * 3600 + 45 * 60;
+        assert_eq!(utc_offset(&tm, true), "+05:45");
+        tm.tm_gmtoff = 0;
+        assert_eq!(utc_offset(&tm, false), "+0000");
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string(""), "\"\"");
+        assert_eq!(json_string("a\"b\\c/é"), "\"a\\\"b\\\\c/é\"");
+        assert_eq!(json_string("\n\r\t\u{0}\u{1f}\u{7f}"), "\"\\n\\r\\t\\u0000\\u001f\u{7f}\"");
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(1_784_375_109), "Sat, 18 Jul 2026 11:45:09 GMT");
+    }
+
+    #[test]
+    fn test_html_escape() {
+        assert_eq!(html_escape("<a href=\"x\">Tom & Jerry's</a>"), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;");
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let time = regex::Regex::new(r"^\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4}$").unwrap();
+        let iso = regex::Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}[+-]\d{2}:\d{2}$").unwrap();
+        let now = SystemTime::now();
+        assert!(time.is_match(&time_local(now)), "{}", time_local(now));
+        assert!(iso.is_match(&time_iso8601(now)), "{}", time_iso8601(now));
+    }
+}
@@ -0,0 +1,337 @@
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::application::format::{broken_down_time, html_escape, http_date, json_string, MONTHS};
+use crate::application::handler::error_page_handler::error_response;
+use crate::application::log::error::{self, Level};
+use crate::config::{AutoindexFormat, AutoindexOptions, EffectiveLocation};
+use crate::http::{Response, StatusCode};
+
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    mtime: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortColumn {
+    Name,
+    Modified,
+    Size,
+}
+
+/// Apache-style `?C=N;O=D` sort request. Unknown keys are ignored.
+#[derive(Debug, Clone, Copy)]
+struct SortOrder {
+    column: SortColumn,
+    descending: bool,
+}
+
+impl SortOrder {
+    fn from_query(query: &str) -> Self {
+        let mut order = SortOrder { column: SortColumn::Name, descending: false };
+        for pair in query.split([';', '&']) {
+            match pair.split_once('=') {
+                Some(("C", "N")) => order.column = SortColumn::Name,
+                Some(("C", "M")) => order.column = SortColumn::Modified,
+                Some(("C", "S")) => order.column = SortColumn::Size,
+                Some(("O", "A")) => order.descending = false,
+                Some(("O", "D")) => order.descending = true,
+                _ => {}
+            }
+        }
+        order
+    }
+
+    /// Query string for a column header: clicking the active column flips the order.
+    fn link_for(self, column: SortColumn) -> String {
+        let key = match column {
+            SortColumn::Name => 'N',
+            SortColumn::Modified => 'M',
+            SortColumn::Size => 'S',
+        };
+        let dir = if column == self.column && !self.descending { 'D' } else { 'A' };
+        format!("?C={key};O={dir}")
+    }
+}
+
+pub fn serve_autoindex(loc: &EffectiveLocation, req_path: &str, query: &str, dir_path: &Path, opts: &AutoindexOptions) -> Response {
+    let dir = match fs::read_dir(dir_path) {
+        Ok(e) => e,
+        Err(e) => {
+            error::log(Level::Error, &format!("cannot list {}: {e}", dir_path.display()));
+            return error_response(StatusCode::InternalServerError, loc);
+        }
+    };
+
+    let mut entries = Vec::new();
+    for entry in dir.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !opts.show_hidden && name.starts_with('.') {
+            continue;
+        }
+        // Follow symlinks so a link to a directory is listed as a directory
+        let meta = match fs::metadata(entry.path()) {
+            Ok(m) => m,
+            // A dangling symlink, typically
+            Err(e) => {
+                error::log(Level::Info, &format!("leaving {} out of the listing: {e}", entry.path().display()));
+                continue;
+            }
+        };
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        entries.push(Entry { name, is_dir: meta.is_dir(), size: meta.len(), mtime });
+    }
+
+    let order = SortOrder::from_query(query);
+    sort_entries(&mut entries, order);
+
+    let (body, content_type) = match opts.format {
+        AutoindexFormat::Html => match render_html(req_path, &entries, order, opts) {
+            Some(html) => (html, "text/html; charset=utf-8"),
+            None => return error_response(StatusCode::InternalServerError, loc),
+        },
+        AutoindexFormat::Json => (render_json(&entries), "application/json"),
+        AutoindexFormat::Xml => (render_xml(&entries), "text/xml; charset=utf-8"),
+    };
+
+    let mut resp = Response::new(StatusCode::Ok);
+    resp.body = body.into_bytes();
+    resp.headers.insert("Content-Type".into(), content_type.into());
+    resp.set_cookie("served=static; Path=/; HttpOnly");
+    resp
+}
+
+fn sort_entries(entries: &mut [Entry], order: SortOrder) {
+    entries.sort_by(|a, b| {
+        // Directories always come first, whatever the column
+        let by_column = match order.column {
+            SortColumn::Name => a.name.cmp(&b.name),
+            SortColumn::Modified => a.mtime.cmp(&b.mtime).then_with(|| a.name.cmp(&b.name)),
+            SortColumn::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+        };
+        let by_column = if order.descending { by_column.reverse() } else { by_column };
+        b.is_dir.cmp(&a.is_dir).then(by_column)
+    });
+}
+
+fn render_html(req_path: &str, entries: &[Entry], order: SortOrder, opts: &AutoindexOptions) -> Option<String> {
+    let base = req_path.trim_end_matches('/');
+    let title = format!("Index of {}/", html_escape(base));
+
+    let mut rows = format!(
+        "<tr><th><a href=\"{}\">Name</a></th><th><a href=\"{}\">Last modified</a></th><th><a href=\"{}\">Size</a></th></tr>",
+        order.link_for(SortColumn::Name),
+        order.link_for(SortColumn::Modified),
+        order.link_for(SortColumn::Size),
+    );
+    if !base.is_empty() {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td>-</td></tr>");
+    }
+    for e in entries {
+        let slash = if e.is_dir { "/" } else { "" };
+        let size = if e.is_dir {
+            "-".to_string()
+        } else if opts.exact_size {
+            e.size.to_string()
+        } else {
+            human_size(e.size)
+        };
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}/{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            html_escape(base),
+            percent_encode(&e.name),
+            slash,
+            html_escape(&e.name),
+            slash,
+            format_listing_time(e.mtime, opts.localtime),
+            size,
+        ));
+    }
+
+    match &opts.template {
+        Some(path) => {
+            // {{title}} and {{path}} are escaped, {{rows}} is the prebuilt table body
+            let tpl = fs::read_to_string(path).ok()?;
+            Some(
+                tpl.replace("{{title}}", &title)
+                    .replace("{{path}}", &html_escape(req_path))
+                    .replace("{{rows}}", &rows),
+            )
+        }
+        None => Some(format!(
+            "<html><head><title>{title}</title></head><body><h1>{title}</h1><hr><table>{rows}</table><hr></body></html>"
+        )),
+    }
+}
+
+fn render_json(entries: &[Entry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            let kind = if e.is_dir { "directory" } else { "file" };
+            let mut item = format!(
+                "{{\"name\":{},\"type\":\"{}\",\"mtime\":\"{}\"",
+                json_string(&e.name),
+                kind,
+                http_date(e.mtime)
+            );
+            if !e.is_dir {
+                item.push_str(&format!(",\"size\":{}", e.size));
+            }
+            item.push('}');
+            item
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+fn render_xml(entries: &[Entry]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<list>");
+    for e in entries {
+        let mtime = http_date(e.mtime);
+        if e.is_dir {
+            xml.push_str(&format!("<directory mtime=\"{}\">{}</directory>", mtime, html_escape(&e.name)));
+        } else {
+            xml.push_str(&format!("<file mtime=\"{}\" size=\"{}\">{}</file>", mtime, e.size, html_escape(&e.name)));
+        }
+    }
+    xml.push_str("</list>");
+    xml
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{:.1}{}", value, UNITS[unit])
+    } else {
+        format!("{:.0}{}", value, UNITS[unit])
+    }
+}
+
+/// `18-Oct-2026 14:05`, the same layout nginx uses for its listings.
+fn format_listing_time(secs: i64, localtime: bool) -> String {
+    let tm = broken_down_time(secs, localtime);
+    format!(
+        "{:02}-{}-{} {:02}:{:02}",
+        tm.tm_mday,
+        MONTHS[tm.tm_mon as usize % 12],
+        tm.tm_year + 1900,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, is_dir: bool, size: u64, mtime: i64) -> Entry {
+        Entry { name: name.into(), is_dir, size, mtime }
+    }
+
+    fn options(format: AutoindexFormat) -> AutoindexOptions {
+        AutoindexOptions { format, exact_size: false, localtime: false, show_hidden: false, template: None }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let mut entries = vec![entry("b", false, 10, 3), entry("z", true, 0, 1), entry("a", false, 20, 2), entry("c", true, 0, 5)];
+        sort_entries(&mut entries, SortOrder::from_query(""));
+        assert_eq!(names(&entries), ["c", "z", "a", "b"]);
+        // Directories stay first in descending order too
+        sort_entries(&mut entries, SortOrder::from_query("C=S;O=D"));
+        assert_eq!(names(&entries), ["z", "c", "a", "b"]);
+        sort_entries(&mut entries, SortOrder::from_query("C=M&O=A"));
+        assert_eq!(names(&entries), ["z", "c", "a", "b"]);
+        sort_entries(&mut entries, SortOrder::from_query("C=X;O=D;junk"));
+        assert_eq!(names(&entries), ["z", "c", "b", "a"]);
+
+        let order = SortOrder::from_query("C=S");
+        assert_eq!(order.link_for(SortColumn::Size), "?C=S;O=D");
+        assert_eq!(order.link_for(SortColumn::Name), "?C=N;O=A");
+    }
+
+    #[test]
+    fn test_html_escapes_names() {
+        let entries = [entry("<script>&\"x\".txt", false, 2048, 0), entry("my dir", true, 0, 0)];
+        let html = render_html("/files/<b>/", &entries, SortOrder::from_query(""), &options(AutoindexFormat::Html)).unwrap();
+        assert!(html.contains("<title>Index of /files/&lt;b&gt;/</title>"), "{html}");
+        assert!(html.contains("<a href=\"/files/&lt;b&gt;/%3Cscript%3E%26%22x%22.txt\">&lt;script&gt;&amp;&quot;x&quot;.txt</a>"), "{html}");
+        assert!(html.contains("<a href=\"/files/&lt;b&gt;/my%20dir/\">my dir/</a>"), "{html}");
+        assert!(html.contains("<td>01-Jan-1970 00:00</td><td>2.0K</td>"), "{html}");
+        assert!(html.contains("<a href=\"../\">"));
+        let root = render_html("/", &[], SortOrder::from_query(""), &options(AutoindexFormat::Html)).unwrap();
+        assert!(!root.contains("../"), "{root}");
+    }
+
+    #[test]
+    fn test_json_and_xml() {
+        let entries = [entry("sub", true, 4096, 0), entry("a\"b\\\n.txt", false, 7, 86400)];
+        assert_eq!(
+            render_json(&entries),
+            "[{\"name\":\"sub\",\"type\":\"directory\",\"mtime\":\"Thu, 01 Jan 1970 00:00:00 GMT\"},\
+             {\"name\":\"a\\\"b\\\\\\n.txt\",\"type\":\"file\",\"mtime\":\"Fri, 02 Jan 1970 00:00:00 GMT\",\"size\":7}]"
+        );
+        let xml = render_xml(&[entry("<x>", false, 1, 0)]);
+        assert_eq!(xml, "<?xml version=\"1.0\"?>\n<list><file mtime=\"Thu, 01 Jan 1970 00:00:00 GMT\" size=\"1\">&lt;x&gt;</file></list>");
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(1023), "1023");
+        assert_eq!(human_size(1536), "1.5K");
+        assert_eq!(human_size(20 * 1024 * 1024), "20M");
+        assert_eq!(human_size(5 * 1024u64.pow(5)), "5120T");
+    }
+
+    #[test]
+    fn test_serve_hides_dotfiles() {
+        let dir = std::env::temp_dir().join(format!("localhost-autoindex-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join(".secret"), "x").unwrap();
+        std::fs::write(dir.join("visible"), "x").unwrap();
+        let loc = EffectiveLocation::default();
+        let mut opts = options(AutoindexFormat::Json);
+        let body = String::from_utf8(serve_autoindex(&loc, "/d/", "", &dir, &opts).body).unwrap();
+        assert!(body.starts_with("[{\"name\":\"sub\"") && body.contains("visible") && !body.contains(".secret"), "{body}");
+        opts.show_hidden = true;
+        let body = String::from_utf8(serve_autoindex(&loc, "/d/", "", &dir, &opts).body).unwrap();
+        assert!(body.contains(".secret"), "{body}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
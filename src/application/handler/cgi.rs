@@ -2,19 +2,30 @@ use std::ffi::CString;
 use std::os::fd::RawFd;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-use crate::config::{Server, Cgi};
+use crate::application::auth::basic;
+use crate::application::log::error::{self, Level};
+use crate::application::metrics::{self, Counter};
+use crate::config::{Cgi, EffectiveLocation};
 use crate::http::{method::Method, request::Request, response::Response, status::StatusCode};
 
+/// Held from creating a CGI process's pipes until it is forked.
+static FORK_LOCK: Mutex<()> = Mutex::new(());
+
 pub struct CgiProcess {
     pub pid: i32,
     pub input: Option<RawFd>,
     pub output: RawFd,
+    /// The script's stderr, logged under the request's ID
+    pub errors: RawFd,
 }
 
-pub fn start_cgi(_server: &Server, root: &Path, req: &Request, cgi_config: &Cgi) -> Result<CgiProcess, Response> {
+/// Starts the script `req` asks for, with `extra_env` added to its
+/// environment.
+pub fn start_cgi(loc: &EffectiveLocation, req: &Request, cgi_config: &Cgi, extra_env: &[(String, String)]) -> Result<CgiProcess, Response> {
     let (path_no_q, query) = split_path_query(&req.path);
-    let script = match resolve_script(root, path_no_q) {
+    let script = match resolve_script(&loc.root, path_no_q) {
         Some(p) => p,
         None => return Err(Response::new(StatusCode::NotFound)),
     };
@@ -24,23 +35,65 @@ pub fn start_cgi(_server: &Server, root: &Path, req: &Request, cgi_config: &Cgi)
          return Err(Response::new(StatusCode::NotFound));
     }
 
+    // Built before forking: with worker threads another thread may hold the
+    // allocator's lock at fork time, so the child must not allocate
+    let dir = script.parent().map(path_cstr);
+    let interpreter = path_cstr(&cgi_config.interpreter);
+    let script_path = path_cstr(&script);
+    let argv_cstr = [interpreter, script_path];
+    let mut argv: Vec<*const i8> = argv_cstr.iter().map(|s| s.as_ptr()).collect();
+    argv.push(std::ptr::null());
+    let env_cstr = build_env(loc, req, &script, query, extra_env);
+    let mut envp: Vec<*const i8> = env_cstr.iter().map(|s| s.as_ptr()).collect();
+    envp.push(std::ptr::null());
+
+    // Other CGI children must not inherit these pipes, or this child's EOF is
+    // delayed until they exit. macOS has no `pipe2`, so they only become
+    // close-on-exec once `fcntl` ran, and no other CGI may fork before then
+    let fork_guard = FORK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     let mut in_pipe: [RawFd; 2] = [0; 2];
     let mut out_pipe: [RawFd; 2] = [0; 2];
+    let mut err_pipe: [RawFd; 2] = [0; 2];
     unsafe {
         if libc::pipe(in_pipe.as_mut_ptr()) != 0 {
+            error::log(Level::Crit, &format!("cannot create a pipe for CGI: {}", std::io::Error::last_os_error()));
+            metrics::add(Counter::CgiFailures, 1);
             return Err(Response::new(StatusCode::InternalServerError));
         }
         if libc::pipe(out_pipe.as_mut_ptr()) != 0 {
+            error::log(Level::Crit, &format!("cannot create a pipe for CGI: {}", std::io::Error::last_os_error()));
+            metrics::add(Counter::CgiFailures, 1);
+            libc::close(in_pipe[0]);
+            libc::close(in_pipe[1]);
+            return Err(Response::new(StatusCode::InternalServerError));
+        }
+        if libc::pipe(err_pipe.as_mut_ptr()) != 0 {
+            error::log(Level::Crit, &format!("cannot create a pipe for CGI: {}", std::io::Error::last_os_error()));
+            metrics::add(Counter::CgiFailures, 1);
             cleanup_pipes(in_pipe, out_pipe);
             return Err(Response::new(StatusCode::InternalServerError));
         }
         set_nonblock(in_pipe[1]);
         set_nonblock(out_pipe[0]);
+        set_nonblock(err_pipe[0]);
+        // dup2 onto stdin/stdout/stderr clears the flag
+        for fd in in_pipe.into_iter().chain(out_pipe).chain(err_pipe) {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
     }
 
     let pid = unsafe { libc::fork() };
+    if pid != 0 {
+        drop(fork_guard);
+    }
     if pid < 0 {
+        error::log(Level::Crit, &format!("cannot start CGI {}: fork: {}", script.display(), std::io::Error::last_os_error()));
+        metrics::add(Counter::CgiFailures, 1);
         cleanup_pipes(in_pipe, out_pipe);
+        unsafe {
+            libc::close(err_pipe[0]);
+            libc::close(err_pipe[1]);
+        }
         return Err(Response::new(StatusCode::InternalServerError));
     }
 
@@ -48,22 +101,21 @@ pub fn start_cgi(_server: &Server, root: &Path, req: &Request, cgi_config: &Cgi)
         unsafe {
             libc::dup2(in_pipe[0], libc::STDIN_FILENO);
             libc::dup2(out_pipe[1], libc::STDOUT_FILENO);
+            libc::dup2(err_pipe[1], libc::STDERR_FILENO);
             cleanup_pipes(in_pipe, out_pipe);
-            if let Some(dir) = script.parent() {
-                let _ = libc::chdir(path_cstr(dir).as_ptr());
+            libc::close(err_pipe[0]);
+            libc::close(err_pipe[1]);
+            if let Some(dir) = &dir {
+                let _ = libc::chdir(dir.as_ptr());
             }
-            
-            let interpreter = path_cstr(&cgi_config.interpreter);
-            let script_path = path_cstr(&script);
-            let argv_cstr = vec![interpreter, script_path];
-            let mut argv: Vec<*const i8> = argv_cstr.iter().map(|s| s.as_ptr()).collect();
-            argv.push(std::ptr::null());
-
-            let env_cstr = build_env(req, &script, query);
-            let mut envp: Vec<*const i8> = env_cstr.iter().map(|s| s.as_ptr()).collect();
-            envp.push(std::ptr::null());
-
-            libc::execve(cgi_config.interpreter.as_os_str().as_bytes().as_ptr() as *const i8, argv.as_ptr(), envp.as_ptr());
+            // Threads other than the main one run with signals blocked, and
+            // the mask survives exec
+            let mut none: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut none);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &none, std::ptr::null_mut());
+
+            // argv[0] is the NUL-terminated interpreter path
+            libc::execve(argv[0], argv.as_ptr(), envp.as_ptr());
             libc::_exit(127);
         }
     }
@@ -72,7 +124,9 @@ pub fn start_cgi(_server: &Server, root: &Path, req: &Request, cgi_config: &Cgi)
     unsafe {
         libc::close(in_pipe[0]);
         libc::close(out_pipe[1]);
+        libc::close(err_pipe[1]);
     }
+    metrics::add(Counter::CgiSpawns, 1);
 
     let input = if matches!(req.method, Method::Post | Method::Delete) && !req.body.is_empty() {
         Some(in_pipe[1])
@@ -85,6 +139,7 @@ pub fn start_cgi(_server: &Server, root: &Path, req: &Request, cgi_config: &Cgi)
         pid,
         input,
         output: out_pipe[0],
+        errors: err_pipe[0],
     })
 }
 
@@ -112,9 +167,11 @@ pub fn parse_cgi_response(out: &[u8]) -> Response {
 
 fn resolve_script(root: &Path, req_path: &str) -> Option<PathBuf> {
     let clean = req_path.trim_start_matches('/');
-    let path = Path::new(clean);
-    if path.starts_with("cgi-bin") {
-        root.join(path).canonicalize().ok()
+    let script = root.join(clean).canonicalize().ok()?;
+    // Any location may run CGI now, so keep the script inside its root
+    let root = root.canonicalize().ok()?;
+    if script.starts_with(&root) {
+        Some(script)
     } else {
         None
     }
@@ -138,7 +195,7 @@ fn path_cstr(p: &Path) -> CString {
         .unwrap_or_else(|_| CString::new("").unwrap())
 }
 
-fn build_env(req: &Request, script: &Path, query: &str) -> Vec<CString> {
+fn build_env(loc: &EffectiveLocation, req: &Request, script: &Path, query: &str, extra_env: &[(String, String)]) -> Vec<CString> {
     let mut env = Vec::new();
     env.push(safe_cstr(&format!("REQUEST_METHOD={}", method_to_str(&req.method))));
     env.push(safe_cstr(&format!("QUERY_STRING={}", query)));
@@ -148,6 +205,26 @@ fn build_env(req: &Request, script: &Path, query: &str) -> Vec<CString> {
     if let Some(ct) = req.headers.get("Content-Type") {
         env.push(safe_cstr(&format!("CONTENT_TYPE={}", ct)));
     }
+    // Request headers as HTTP_*, except those already passed above, the
+    // password checked by auth_basic and `Proxy`, which scripts would take
+    // for their HTTP_PROXY setting
+    for (name, value) in &req.headers {
+        let skip = ["Content-Type", "Content-Length", "Proxy"].iter().any(|h| name.eq_ignore_ascii_case(h))
+            || (loc.auth_basic.is_some() && name.eq_ignore_ascii_case("Authorization"));
+        if !skip && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            env.push(safe_cstr(&format!("HTTP_{}={}", name.to_ascii_uppercase().replace('-', "_"), value)));
+        }
+    }
+    // Checked by dispatch before the script was started
+    if loc.auth_basic.is_some()
+        && let Some((user, _)) = basic::credentials(&req.headers)
+    {
+        env.push(safe_cstr(&format!("REMOTE_USER={}", user)));
+        env.push(safe_cstr("AUTH_TYPE=Basic"));
+    }
+    for (name, value) in extra_env {
+        env.push(safe_cstr(&format!("{name}={value}")));
+    }
     let full = script.canonicalize().unwrap_or_else(|_| script.to_path_buf());
     env.push(safe_cstr(&format!("PATH_INFO={}", full.display())));
     env
@@ -201,17 +278,9 @@ fn split_headers_body(buf: &[u8]) -> (Vec<String>, &[u8]) {
     }
 }
 
+/// Codes this server cannot send become a 500.
 fn map_status(code: u16) -> StatusCode {
-    match code {
-        200 => StatusCode::Ok,
-        400 => StatusCode::BadRequest,
-        403 => StatusCode::Forbidden,
-        404 => StatusCode::NotFound,
-        405 => StatusCode::MethodNotAllowed,
-        413 => StatusCode::PayloadTooLarge,
-        500 => StatusCode::InternalServerError,
-        _ => StatusCode::InternalServerError,
-    }
+    StatusCode::from_u16(code).unwrap_or(StatusCode::InternalServerError)
 }
 
 fn method_to_str(method: &Method) -> &'static str {
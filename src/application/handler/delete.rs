@@ -1,18 +1,24 @@
 use std::fs;
-use std::path::Path;
 
+use crate::application::handler::static_file::resolve_path;
+use crate::application::log::error::{self, Level};
 use crate::http::request::Request;
 use crate::http::response::Response;
 use crate::http::status::StatusCode;
-use crate::config::Server;
+use crate::config::EffectiveLocation;
 
-pub fn handle_delete(_server: &Server, root: &Path, req: &Request, location_prefix: &str) -> Response {
-    let rel_path = req.path.strip_prefix(location_prefix).unwrap_or("").trim_start_matches('/');
-    let full_path = root.join(rel_path);
+pub fn handle_delete(loc: &EffectiveLocation, req: &Request) -> Response {
+    let path = req.path.split('?').next().unwrap_or("");
+    let Some(full_path) = resolve_path(loc, path) else {
+        return Response::new(StatusCode::Forbidden);
+    };
     match fs::remove_file(&full_path) {
         Ok(_) => Response::new(StatusCode::Ok),
         Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Response::new(StatusCode::NotFound),
         Err(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied => Response::new(StatusCode::Forbidden),
-        Err(_) => Response::new(StatusCode::InternalServerError),
+        Err(e) => {
+            error::log(Level::Error, &format!("cannot delete {}: {e}", full_path.display()));
+            Response::new(StatusCode::InternalServerError)
+        }
     }
 }
\ No newline at end of file
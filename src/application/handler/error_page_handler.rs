@@ -1,31 +1,17 @@
 use std::fs;
-use std::path::{Path, PathBuf};
 use crate::http::{Response, StatusCode};
-use crate::config::Server; // Import your config types
+use crate::application::log::error::{self, Level};
+use crate::config::EffectiveLocation; // Import your config types
 
-pub fn error_response(status: StatusCode, server: &Server, root: &Path) -> Response {
+pub fn error_response(status: StatusCode, loc: &EffectiveLocation) -> Response {
     let code = status.as_u16();
+    let root = &loc.root;
 
     // Look for a custom error page in config
-    let custom_path = server.errors.iter()
-        .find(|e| e.code == code)
-        .map(|e| e.path.clone());
-
-    let file = if let Some(custom) = custom_path {
-        // If custom path is absolute, check existence or treat as relative to root
-        let custom_pb = PathBuf::from(&custom);
-        if custom_pb.is_absolute() {
-            if custom_pb.exists() {
-                custom_pb
-            } else {
-                let relative = custom.trim_start_matches('/');
-                root.join(relative)
-            }
-        } else {
-            root.join(custom_pb)
-        }
-    } else {
-        root.join("errors").join(format!("{}.html", code))
+    let page = loc.errors.iter().find(|e| e.code == code);
+    let file = match page {
+        Some(page) => page.file(root),
+        None => root.join("errors").join(format!("{}.html", code)),
     };
 
     let mut resp = Response::new(status);
@@ -35,7 +21,11 @@ pub fn error_response(status: StatusCode, server: &Server, root: &Path) -> Respo
             resp.body = bytes;
             resp.headers.insert("Content-Type".into(), "text/html; charset=utf-8".into());
         }
-        Err(_) => {
+        Err(e) => {
+            // Without an error_page the built-in page is expected
+            if page.is_some() {
+                error::log(Level::Warn, &format!("cannot read error page {}: {e}", file.display()));
+            }
             resp.body = format!(
                 "<html><head><title>{code} {}</title></head>\
                  <body><h1>{code} {}</h1><p>{}</p></body></html>",
@@ -52,11 +42,14 @@ pub fn error_response(status: StatusCode, server: &Server, root: &Path) -> Respo
 fn default_message(code: u16) -> &'static str {
     match code {
         400 => "Bad Request",
+        401 => "Unauthorized",
         403 => "Forbidden",
         404 => "Not Found",
         405 => "Method Not Allowed",
+        410 => "Gone",
         413 => "Payload Too Large",
         500 => "Internal Server Error",
+        503 => "Service Unavailable",
         _ => "Unknown Error",
     }
 }
\ No newline at end of file
@@ -0,0 +1,10 @@
+use crate::application::metrics;
+use crate::http::{response::Response, status::StatusCode};
+
+/// Serves the counters of every worker in the Prometheus text format.
+pub fn handle_metrics() -> Response {
+    let mut resp = Response::new(StatusCode::Ok);
+    resp.body = metrics::render().into_bytes();
+    resp.headers.insert("Content-Type".into(), "text/plain; version=0.0.4; charset=utf-8".into());
+    resp
+}
@@ -1,5 +1,8 @@
 pub mod static_file;
+pub mod autoindex;
 pub mod error_page_handler;
 pub mod cgi;
 pub mod upload;
-pub mod delete;
\ No newline at end of file
+pub mod delete;
+pub mod metrics;
+pub mod status;
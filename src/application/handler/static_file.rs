@@ -2,8 +2,10 @@ use std::fs::{self, File};
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use crate::http::{Response, StatusCode};
+use crate::application::handler::autoindex::serve_autoindex;
 use crate::application::handler::error_page_handler::error_response;
-use crate::config::Server;
+use crate::application::log::error::{self, Level};
+use crate::config::EffectiveLocation;
 
 const MAX_STATIC_BYTES: u64 = 8 * 1024 * 1024;
 
@@ -20,6 +22,26 @@ fn safe_join(root: &Path, req_path: &str) -> Option<PathBuf> {
     Some(root.join(out))
 }
 
+/// Decodes `%XX` escapes so links produced by the autoindex resolve to real names.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = |b: u8| (b as char).to_digit(16);
+            if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
 fn mime_for(path: &Path) -> &'static str {
     match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
         "html" | "htm" => "text/html; charset=utf-8",
@@ -34,22 +56,65 @@ fn mime_for(path: &Path) -> &'static str {
     }
 }
 
-pub fn serve_static(server: &Server, root: &Path, path: &str, location_prefix: &str, strip_prefix: bool, index: &[String], autoindex: bool) -> Response {
-    // Optionally strip the location prefix when the location has its own root
-    let rel_path = if strip_prefix {
-        path.strip_prefix(location_prefix).unwrap_or(path)
-    } else {
-        path
+/// Outcome of `serve_static`: a response, or an internal redirect requested by
+/// the `try_files` fallback (a URI or a `@named` location).
+pub enum StaticResult {
+    Response(Response),
+    InternalRedirect(String),
+}
+
+/// Maps a request path onto the location's root, stripping the location
+/// prefix when the location has its own root. `None` for traversal attempts.
+pub fn resolve_path(loc: &EffectiveLocation, path: &str) -> Option<PathBuf> {
+    let rel_path = match &loc.strip_prefix {
+        Some(prefix) => path.strip_prefix(prefix.as_str()).unwrap_or(path),
+        None => path,
+    };
+    safe_join(&loc.root, &percent_decode(rel_path.trim_start_matches('/')))
+}
+
+pub fn serve_static(loc: &EffectiveLocation, path: &str) -> StaticResult {
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let Some((fallback, candidates)) = loc.try_files.as_deref().and_then(|t| t.split_last()) else {
+        return StaticResult::Response(serve_path(loc, path, query));
     };
-    let rel_path = rel_path.trim_start_matches('/');
-    let full_path = match safe_join(root, rel_path) {
+
+    // try_files: the first candidate that exists is served, `$uri/` only matches directories
+    for candidate in candidates {
+        let uri = candidate.replace("$uri", path);
+        let Some(full_path) = resolve_path(loc, &uri) else {
+            continue;
+        };
+        let found = if uri.ends_with('/') { full_path.is_dir() } else { full_path.is_file() };
+        if found {
+            return StaticResult::Response(serve_path(loc, &uri, query));
+        }
+    }
+
+    if let Some(code) = fallback.strip_prefix('=') {
+        let status = code.parse().ok().and_then(StatusCode::from_u16).unwrap_or(StatusCode::NotFound);
+        return StaticResult::Response(error_response(status, loc));
+    }
+    StaticResult::InternalRedirect(fallback.replace("$uri", path))
+}
+
+fn serve_path(loc: &EffectiveLocation, path: &str, query: &str) -> Response {
+    let full_path = match resolve_path(loc, path) {
         Some(p) => p,
-        None => return error_response(StatusCode::NotFound, server, root),
+        None => return error_response(StatusCode::NotFound, loc),
     };
 
+    // Directories are only served under their slash-terminated URL so relative links resolve
+    if full_path.is_dir() && !path.ends_with('/') {
+        let mut resp = Response::new(StatusCode::MovedPermanently);
+        let location = if query.is_empty() { format!("{path}/") } else { format!("{path}/?{query}") };
+        resp.headers.insert("Location".into(), location);
+        return resp;
+    }
+
     let mut target = full_path.clone();
     if target.is_dir() {
-        for idx in index {
+        for idx in &loc.index {
             let candidate = target.join(idx);
             if candidate.is_file() {
                 target = candidate;
@@ -59,10 +124,10 @@ pub fn serve_static(server: &Server, root: &Path, path: &str, location_prefix: &
     }
 
     if target.is_dir() {
-        if autoindex {
-            return serve_autoindex(server, root, path, &target);
+        if let Some(opts) = &loc.autoindex {
+            return serve_autoindex(loc, path, query, &target, opts);
         } else {
-            return error_response(StatusCode::Forbidden, server, root);
+            return error_response(StatusCode::Forbidden, loc);
         }
     }
 
@@ -70,31 +135,38 @@ pub fn serve_static(server: &Server, root: &Path, path: &str, location_prefix: &
         Ok(m) => m,
         Err(e) => {
             return match e.kind() {
-                io::ErrorKind::NotFound => error_response(StatusCode::NotFound, server, root),
-                io::ErrorKind::PermissionDenied => error_response(StatusCode::Forbidden, server, root),
-                _ => error_response(StatusCode::InternalServerError, server, root),
+                io::ErrorKind::NotFound => error_response(StatusCode::NotFound, loc),
+                io::ErrorKind::PermissionDenied => error_response(StatusCode::Forbidden, loc),
+                _ => {
+                    error::log(Level::Error, &format!("cannot stat {}: {e}", target.display()));
+                    error_response(StatusCode::InternalServerError, loc)
+                }
             }
         }
     };
     
     if meta.len() > MAX_STATIC_BYTES {
-        return error_response(StatusCode::PayloadTooLarge, server, root);
+        return error_response(StatusCode::PayloadTooLarge, loc);
     }
 
     let mut f = match File::open(&target) {
         Ok(f) => f,
         Err(e) => {
             return match e.kind() {
-                io::ErrorKind::NotFound => error_response(StatusCode::NotFound, server, root),
-                io::ErrorKind::PermissionDenied => error_response(StatusCode::Forbidden, server, root),
-                _ => error_response(StatusCode::InternalServerError, server, root),
+                io::ErrorKind::NotFound => error_response(StatusCode::NotFound, loc),
+                io::ErrorKind::PermissionDenied => error_response(StatusCode::Forbidden, loc),
+                _ => {
+                    error::log(Level::Error, &format!("cannot open {}: {e}", target.display()));
+                    error_response(StatusCode::InternalServerError, loc)
+                }
             }
         }
     };
 
     let mut bytes = Vec::with_capacity(meta.len() as usize);
-    if let Err(_) = f.read_to_end(&mut bytes) {
-        return error_response(StatusCode::InternalServerError, server, root);
+    if let Err(e) = f.read_to_end(&mut bytes) {
+        error::log(Level::Error, &format!("cannot read {}: {e}", target.display()));
+        return error_response(StatusCode::InternalServerError, loc);
     }
 
     let mut resp = Response::new(StatusCode::Ok);
@@ -104,25 +176,87 @@ pub fn serve_static(server: &Server, root: &Path, path: &str, location_prefix: &
     resp
 }
 
-fn serve_autoindex(server: &Server, root: &Path, req_path: &str, dir_path: &Path) -> Response {
-    let entries = match fs::read_dir(dir_path) {
-        Ok(e) => e,
-        Err(_) => return error_response(StatusCode::InternalServerError, server, root),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut html = format!("<html><head><title>Index of {req_path}</title></head><body><h1>Index of {req_path}</h1><hr><ul>");
-    for entry in entries {
-        if let Ok(entry) = entry {
-            let name = entry.file_name().to_string_lossy().into_owned();
-            let slash = if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) { "/" } else { "" };
-            html.push_str(&format!("<li><a href=\"{}/{}{}\">{}{}</a></li>", req_path.trim_end_matches('/'), name, slash, name, slash));
+    fn site(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("localhost-static-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("index.html"), "home").unwrap();
+        fs::write(dir.join("a b.txt"), "spaced").unwrap();
+        fs::write(dir.join("docs/index.html"), "docs").unwrap();
+        dir
+    }
+
+    fn response(result: StaticResult) -> Response {
+        match result {
+            StaticResult::Response(resp) => resp,
+            StaticResult::InternalRedirect(uri) => panic!("unexpected redirect to {uri}"),
         }
     }
-    html.push_str("</ul><hr></body></html>");
 
-    let mut resp = Response::new(StatusCode::Ok);
-    resp.body = html.into_bytes();
-    resp.headers.insert("Content-Type".into(), "text/html; charset=utf-8".into());
-    resp.set_cookie("served=static; Path=/; HttpOnly");
-    resp
-}
\ No newline at end of file
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b%2fc"), "a b/c");
+        assert_eq!(percent_decode("%E2%82%AC"), "€");
+        // Malformed and truncated escapes are kept as written
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn test_traversal_is_rejected() {
+        let root = Path::new("/srv/www");
+        assert_eq!(safe_join(root, "/a/./b.txt"), Some(PathBuf::from("/srv/www/a/b.txt")));
+        assert_eq!(safe_join(root, "a/../../etc/passwd"), None);
+        assert_eq!(safe_join(root, "//etc/passwd"), Some(PathBuf::from("/srv/www/etc/passwd")));
+
+        let loc = EffectiveLocation { root: root.into(), ..Default::default() };
+        assert_eq!(resolve_path(&loc, "/%2e%2e/etc/passwd"), None);
+        assert_eq!(resolve_path(&loc, "/a/%2E%2E%2F%2E%2E%2Fetc"), None);
+        assert_eq!(resolve_path(&loc, "/%2Fetc%2Fpasswd"), Some(PathBuf::from("/srv/www/etc/passwd")));
+        assert_eq!(resolve_path(&loc, "/a%20b.txt"), Some(PathBuf::from("/srv/www/a b.txt")));
+
+        let files = EffectiveLocation { root: "/data".into(), strip_prefix: Some("/files".into()), ..Default::default() };
+        assert_eq!(resolve_path(&files, "/files/x/y"), Some(PathBuf::from("/data/x/y")));
+    }
+
+    #[test]
+    fn test_directory_redirect_and_index() {
+        let dir = site("dirs");
+        let loc = EffectiveLocation { root: dir.clone(), index: vec!["index.html".into()], ..Default::default() };
+        let resp = response(serve_static(&loc, "/docs?x=1"));
+        assert_eq!(resp.status.as_u16(), 301);
+        assert_eq!(resp.headers["Location"], "/docs/?x=1");
+        assert_eq!(response(serve_static(&loc, "/docs/")).body, b"docs");
+        assert_eq!(response(serve_static(&loc, "/a%20b.txt")).body, b"spaced");
+        assert_eq!(response(serve_static(&loc, "/missing")).status.as_u16(), 404);
+        assert_eq!(response(serve_static(&loc, "/../index.html")).status.as_u16(), 404);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_try_files() {
+        let dir = site("try");
+        let mut loc = EffectiveLocation { root: dir.clone(), index: vec!["index.html".into()], ..Default::default() };
+        loc.try_files = Some(vec!["$uri".into(), "$uri/".into(), "/index.html".into()]);
+        assert_eq!(response(serve_static(&loc, "/a%20b.txt")).body, b"spaced");
+        assert_eq!(response(serve_static(&loc, "/docs")).body, b"docs");
+        assert!(matches!(serve_static(&loc, "/app/route?q=1"), StaticResult::InternalRedirect(uri) if uri == "/index.html"));
+
+        loc.try_files = Some(vec!["$uri".into(), "@backend".into()]);
+        assert!(matches!(serve_static(&loc, "/nope"), StaticResult::InternalRedirect(uri) if uri == "@backend"));
+        loc.try_files = Some(vec!["$uri".into(), "=418".into()]);
+        assert_eq!(response(serve_static(&loc, "/nope")).status.as_u16(), 404);
+        loc.try_files = Some(vec!["$uri".into(), "=403".into()]);
+        assert_eq!(response(serve_static(&loc, "/nope")).status.as_u16(), 403);
+        // `$uri` without a trailing slash only matches files
+        loc.try_files = Some(vec!["$uri".into(), "=404".into()]);
+        assert_eq!(response(serve_static(&loc, "/docs")).status.as_u16(), 404);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
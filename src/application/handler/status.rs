@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use crate::application::format::{html_escape, json_string, seconds};
+use crate::application::metrics::{self, ConnInfo, Summary};
+use crate::config::StatusPage;
+use crate::core::net::connection::ConnState;
+use crate::http::request::Request;
+use crate::http::{Response, StatusCode};
+
+/// Live totals of every worker as HTML, or as JSON for `?format=json` or an
+/// `Accept: application/json` request. The connection list only covers this
+/// process and is up to a second old.
+pub fn handle_status(opts: StatusPage, req: &Request) -> Response {
+    let Some(summary) = metrics::summary() else {
+        return Response::new(StatusCode::ServiceUnavailable);
+    };
+    let query = req.path.split_once('?').map_or("", |(_, q)| q);
+    let accept = req.header("Accept");
+    let json = query.split('&').any(|pair| pair == "format=json") || accept.is_some_and(|a| a.contains("application/json"));
+
+    let (body, content_type) = metrics::with_connections(|conns| {
+        let mut conns: Vec<&ConnInfo> = if opts.connections { conns.collect() } else { Vec::new() };
+        conns.sort_by_key(|c| c.opened);
+        if json {
+            (render_json(&summary, opts.connections.then_some(&conns[..])), "application/json")
+        } else {
+            (render_html(&summary, opts.connections.then_some(&conns[..])), "text/html; charset=utf-8")
+        }
+    });
+    let mut resp = Response::new(StatusCode::Ok);
+    resp.body = body.into_bytes();
+    resp.headers.insert("Content-Type".into(), content_type.into());
+    resp.headers.insert("Cache-Control".into(), "no-store".into());
+    resp
+}
+
+const CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+fn render_json(s: &Summary, conns: Option<&[&ConnInfo]>) -> String {
+    let states: Vec<String> = ConnState::NAMES.iter().zip(s.states).map(|(name, n)| format!("\"{name}\":{n}")).collect();
+    let vhosts: Vec<String> = s
+        .vhosts
+        .iter()
+        .map(|v| {
+            let classes: Vec<String> = CLASSES.iter().zip(v.classes).map(|(class, n)| format!("\"{class}\":{n}")).collect();
+            format!("{{\"vhost\":{},\"requests\":{},{}}}", json_string(&v.vhost), v.requests, classes.join(","))
+        })
+        .collect();
+    let mut out = format!(
+        "{{\"uptime\":{},\"workers\":{},\"connections\":{{\"active\":{},{}}},\"accepted\":{},\"handled\":{},\"requests\":{},\
+         \"requests_per_second\":{{\"1m\":{:.3},\"5m\":{:.3},\"15m\":{:.3}}},\"vhosts\":[{}]",
+        s.uptime.as_secs(),
+        s.workers,
+        s.states.iter().sum::<u64>(),
+        states.join(","),
+        s.accepted,
+        s.handled,
+        s.requests,
+        s.rates[0],
+        s.rates[1],
+        s.rates[2],
+        vhosts.join(",")
+    );
+    if let Some(conns) = conns {
+        let items: Vec<String> = conns
+            .iter()
+            .map(|c| {
+                format!(
+                    "{{\"client\":{},\"state\":\"{}\",\"path\":{},\"age\":{}}}",
+                    json_string(&c.client.to_string()),
+                    c.state,
+                    json_string(&c.path),
+                    seconds(c.opened.elapsed())
+                )
+            })
+            .collect();
+        out.push_str(&format!(",\"connection_list\":[{}]", items.join(",")));
+    }
+    out.push('}');
+    out
+}
+
+fn render_html(s: &Summary, conns: Option<&[&ConnInfo]>) -> String {
+    let mut body = format!(
+        "<p>Up {}, {} worker{}</p>\n<table>\
+         <tr><th>Active connections</th><td>{}</td></tr>\
+         <tr><th>Accepted</th><td>{}</td></tr><tr><th>Handled</th><td>{}</td></tr><tr><th>Requests</th><td>{}</td></tr>\
+         <tr><th>Requests/s (1m, 5m, 15m)</th><td>{:.2}, {:.2}, {:.2}</td></tr></table>\n",
+        uptime(s.uptime),
+        s.workers,
+        if s.workers == 1 { "" } else { "s" },
+        s.states.iter().sum::<u64>(),
+        s.accepted,
+        s.handled,
+        s.requests,
+        s.rates[0],
+        s.rates[1],
+        s.rates[2]
+    );
+
+    body.push_str("<h2>Connections by state</h2>\n<table><tr>");
+    for name in ConnState::NAMES {
+        body.push_str(&format!("<th>{name}</th>"));
+    }
+    body.push_str("</tr><tr>");
+    for n in s.states {
+        body.push_str(&format!("<td>{n}</td>"));
+    }
+    body.push_str("</tr></table>\n");
+
+    body.push_str("<h2>Virtual hosts</h2>\n<table><tr><th>vhost</th><th>requests</th>");
+    for class in CLASSES {
+        body.push_str(&format!("<th>{class}</th>"));
+    }
+    body.push_str("</tr>");
+    for v in &s.vhosts {
+        let name = if v.vhost.is_empty() { "-" } else { &v.vhost };
+        body.push_str(&format!("<tr><td>{}</td><td>{}</td>", html_escape(name), v.requests));
+        for n in v.classes {
+            body.push_str(&format!("<td>{n}</td>"));
+        }
+        body.push_str("</tr>");
+    }
+    body.push_str("</table>\n");
+
+    if let Some(conns) = conns {
+        body.push_str("<h2>Connections of this worker</h2>\n<table><tr><th>client</th><th>state</th><th>path</th><th>age</th></tr>");
+        for c in conns {
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                c.client,
+                c.state,
+                html_escape(&c.path),
+                uptime(c.opened.elapsed())
+            ));
+        }
+        body.push_str("</table>\n");
+    }
+    format!("<html><head><title>Status</title></head><body><h1>Status</h1>\n{body}</body></html>")
+}
+
+/// `3d 4h 5m 6s`, leaving out the leading zero units.
+fn uptime(d: Duration) -> String {
+    let secs = d.as_secs();
+    let parts = [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m"), (secs % 60, "s")];
+    let first = parts.iter().position(|&(n, _)| n > 0).unwrap_or(parts.len() - 1);
+    parts[first..].iter().map(|(n, unit)| format!("{n}{unit}")).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::application::metrics::VhostTotals;
+
+    fn summary() -> Summary {
+        Summary {
+            uptime: Duration::from_secs(3725),
+            workers: 2,
+            accepted: 10,
+            handled: 8,
+            requests: 30,
+            states: [1, 0, 1, 0, 0],
+            rates: [0.5, 0.25, 1.0 / 3.0],
+            vhosts: vec![
+                VhostTotals { vhost: String::new(), requests: 4, classes: [0, 4, 0, 0, 0] },
+                VhostTotals { vhost: "a\"<b>".into(), requests: 26, classes: [0, 20, 1, 4, 1] },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let json = render_json(&summary(), None);
+        assert_eq!(
+            json,
+            "{\"uptime\":3725,\"workers\":2,\"connections\":{\"active\":2,\"reading\":1,\"dispatching\":0,\"cgi\":1,\"writing\":0,\"closing\":0},\
+             \"accepted\":10,\"handled\":8,\"requests\":30,\"requests_per_second\":{\"1m\":0.500,\"5m\":0.250,\"15m\":0.333},\"vhosts\":[\
+             {\"vhost\":\"\",\"requests\":4,\"1xx\":0,\"2xx\":4,\"3xx\":0,\"4xx\":0,\"5xx\":0},\
+             {\"vhost\":\"a\\\"<b>\",\"requests\":26,\"1xx\":0,\"2xx\":20,\"3xx\":1,\"4xx\":4,\"5xx\":1}]}"
+        );
+
+        let conn = ConnInfo { client: "192.0.2.7:5000".parse().unwrap(), state: "cgi", path: "/a\"b".into(), opened: Instant::now() };
+        let json = render_json(&summary(), Some(&[&conn]));
+        assert!(json.ends_with(",\"connection_list\":[{\"client\":\"192.0.2.7:5000\",\"state\":\"cgi\",\"path\":\"/a\\\"b\",\"age\":0.000}]}"), "{json}");
+        assert!(render_json(&summary(), Some(&[])).ends_with(",\"connection_list\":[]}"));
+    }
+
+    #[test]
+    fn test_html() {
+        let html = render_html(&summary(), None);
+        assert!(html.contains("<p>Up 1h 2m 5s, 2 workers</p>"), "{html}");
+        assert!(html.contains("<tr><th>Active connections</th><td>2</td></tr>"));
+        assert!(html.contains("<td>0.50, 0.25, 0.33</td>"));
+        assert!(html.contains("<tr><td>-</td><td>4</td>"));
+        assert!(html.contains("<tr><td>a&quot;&lt;b&gt;</td><td>26</td><td>0</td><td>20</td><td>1</td><td>4</td><td>1</td></tr>"), "{html}");
+        assert!(!html.contains("Connections of this worker"));
+
+        let conn = ConnInfo { client: "192.0.2.7:5000".parse().unwrap(), state: "reading", path: "/<x>".into(), opened: Instant::now() };
+        let html = render_html(&summary(), Some(&[&conn]));
+        assert!(html.contains("<tr><td>192.0.2.7:5000</td><td>reading</td><td>/&lt;x&gt;</td><td>0s</td></tr>"), "{html}");
+    }
+
+    #[test]
+    fn test_uptime() {
+        assert_eq!(uptime(Duration::ZERO), "0s");
+        assert_eq!(uptime(Duration::from_secs(59)), "59s");
+        assert_eq!(uptime(Duration::from_secs(3600)), "1h 0m 0s");
+        assert_eq!(uptime(Duration::from_secs(3 * 86400 + 4 * 3600 + 5 * 60 + 6)), "3d 4h 5m 6s");
+    }
+}
@@ -1,22 +1,19 @@
 use std::fs;
-use std::path::Path;
 
-use crate::config::Server;
+use crate::application::log::error::{self, Level};
+use crate::config::EffectiveLocation;
 use crate::http::{response::Response, status::StatusCode};
 use crate::http::request::Request;
 
-pub fn handle_upload(server: &Server, root: &Path, req: &Request) -> Response {
-    println!("Upload hit: method={:?}, len={}", req.method, req.body.len());
+pub fn handle_upload(loc: &EffectiveLocation, req: &Request) -> Response {
     // Enforce method
     if req.method != crate::http::method::Method::Post {
         return Response::new(StatusCode::MethodNotAllowed);
     }
 
     // Enforce size (client_max_body_size)
-    if let Some(max) = server.client_max_body_size {
-        if req.body.len() as u64 > max {
-            return Response::new(StatusCode::PayloadTooLarge);
-        }
+    if req.body.len() as u64 > loc.client_max_body_size {
+        return Response::new(StatusCode::PayloadTooLarge);
     }
 
     // Get boundary
@@ -36,8 +33,9 @@ pub fn handle_upload(server: &Server, root: &Path, req: &Request) -> Response {
     };
 
     // Ensure uploads dir
-    let upload_dir = root.join("uploads");
-    if let Err(_) = fs::create_dir_all(&upload_dir) {
+    let upload_dir = loc.root.join("uploads");
+    if let Err(e) = fs::create_dir_all(&upload_dir) {
+        error::log(Level::Error, &format!("cannot create {}: {e}", upload_dir.display()));
         return Response::new(StatusCode::InternalServerError);
     }
 
@@ -51,7 +49,8 @@ pub fn handle_upload(server: &Server, root: &Path, req: &Request) -> Response {
         .unwrap_or("upload.bin");
     let path = upload_dir.join(fname);
 
-    if let Err(_) = fs::write(&path, data) {
+    if let Err(e) = fs::write(&path, data) {
+        error::log(Level::Error, &format!("cannot write {}: {e}", path.display()));
         return Response::new(StatusCode::InternalServerError);
     }
 
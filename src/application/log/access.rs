@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::error::{self, Context, Level};
+use crate::application::auth::basic::credentials;
+use crate::application::format::{json_string, seconds, time_iso8601, time_local};
+use crate::application::server::variables::{self, Variables};
+use crate::config::{AccessLog, EffectiveLocation, ErrorLog, LogTemplate, Server};
+use crate::core::pool::BlockingPool;
+use crate::http::headers::Headers;
+use crate::http::request::Request;
+
+/// Buffered bytes of one file that are written out without waiting for the timer.
+const FLUSH_SIZE: usize = 64 * 1024;
+
+/// How often buffered lines are written out.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Variables about the response and its timing, which only the access log
+/// has. The request's own variables come from `Variables`.
+const LOG_VARIABLES: &[&str] = &[
+    "remote_user", "time_local", "time_iso8601", "request", "request_uri", "server_name", "location",
+    "server_protocol", "status", "body_bytes_sent", "bytes_sent", "request_time", "upstream_response_time",
+    "request_id",
+];
+
+/// The first `$name` in a log format that is neither a log variable, a
+/// built-in one nor among `assigned` by `set` and `auth_request_set`, if any.
+pub fn unknown_variable<'t>(template: &'t str, assigned: &[String]) -> Option<&'t str> {
+    template
+        .split('$')
+        .skip(1)
+        .map(|rest| &rest[..variables::name_len(rest)])
+        .find(|name| !LOG_VARIABLES.contains(name) && !variables::is_builtin(name) && !assigned.iter().any(|a| a == name))
+}
+
+/// Source of unique request ids within the process.
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+/// Header carrying a request's ID, in requests to CGI and in responses
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+/// 32 hex digits, random enough to tell requests apart across processes and
+/// restarts.
+fn new_request_id() -> String {
+    let state = RandomState::new();
+    let seq = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    let mut id = String::with_capacity(32);
+    for half in 0..2u8 {
+        id.push_str(&format!("{:016x}", state.hash_one((half, seq, now, std::process::id()))));
+    }
+    id
+}
+
+/// The client's or a proxy's `X-Request-ID`, kept if it is short and safe
+/// to put in logs and headers as is.
+fn incoming_request_id(headers: &Headers) -> Option<String> {
+    let (_, id) = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case(REQUEST_ID_HEADER))?;
+    let valid = (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+    valid.then(|| id.clone())
+}
+
+/// What the access log records about one request, filled in while it is
+/// served and logged once the response is written.
+pub struct Entry {
+    /// Files of the location that served it
+    pub logs: Vec<AccessLog>,
+    pub request_id: String,
+    remote_addr: SocketAddr,
+    /// First server_name of the server that served it
+    pub vhost: String,
+    pub location: String,
+    /// The server's own `error_log`, for messages about this request
+    error_log: Option<ErrorLog>,
+    pub method: String,
+    /// As requested, with the query string
+    pub path: String,
+    version: String,
+    /// The request's variables, as routing left them once it is routed
+    vars: Option<Variables>,
+    /// When the first byte of the request arrived
+    pub started: Instant,
+    parsed: Instant,
+    /// When the handler returned a response or started CGI
+    handled: Option<Instant>,
+    upstream_started: Option<Instant>,
+    pub upstream_time: Option<Duration>,
+    /// When the response was queued for writing
+    responded: Option<Instant>,
+    pub status: u16,
+    body_bytes_sent: usize,
+    bytes_sent: usize,
+}
+
+impl Entry {
+    /// `req` is `None` for a request that could not be parsed.
+    pub fn new(srv: &Server, loc: &EffectiveLocation, remote_addr: SocketAddr, req: Option<&Request>, started: Instant) -> Self {
+        Self {
+            logs: loc.access_logs.clone(),
+            request_id: req.and_then(|r| incoming_request_id(&r.headers)).unwrap_or_else(new_request_id),
+            remote_addr,
+            vhost: srv.server_names.first().cloned().unwrap_or_default(),
+            location: loc.location.clone(),
+            error_log: srv.error_log.clone(),
+            method: req.map(|r| r.method.as_str().to_string()).unwrap_or_default(),
+            path: req.map(|r| r.path.clone()).unwrap_or_default(),
+            version: req.map(|r| r.version.clone()).unwrap_or_default(),
+            vars: req.map(|r| Variables::new(srv, r, remote_addr)),
+            started,
+            parsed: Instant::now(),
+            handled: None,
+            upstream_started: None,
+            upstream_time: None,
+            responded: None,
+            status: 0,
+            body_bytes_sent: 0,
+            bytes_sent: 0,
+        }
+    }
+
+    /// Error log context for messages about this request on connection `fd`.
+    pub fn context(&self, fd: i32) -> Context {
+        Context {
+            fd: Some(fd),
+            client: Some(self.remote_addr),
+            request_id: Some(self.request_id.clone()),
+            vhost: Some(self.vhost.clone()).filter(|v| !v.is_empty()),
+            location: Some(self.location.clone()).filter(|l| !l.is_empty()),
+            error_log: self.error_log.clone(),
+        }
+    }
+
+    /// Keeps the variables of the routed request, with `set` values and regex
+    /// captures.
+    pub fn routed(&mut self, vars: Variables) {
+        self.vars = Some(vars);
+    }
+
+    /// Records the response once it is serialized.
+    pub fn responded(&mut self, status: u16, body_bytes_sent: usize, bytes_sent: usize) {
+        let now = Instant::now();
+        self.handled.get_or_insert(now);
+        self.responded = Some(now);
+        self.status = status;
+        self.body_bytes_sent = body_bytes_sent;
+        self.bytes_sent = bytes_sent;
+    }
+
+    /// Marks the start of a CGI process, for `$upstream_response_time`.
+    pub fn upstream_started(&mut self) {
+        let now = Instant::now();
+        self.handled.get_or_insert(now);
+        self.upstream_started = Some(now);
+    }
+
+    /// Marks the end of the CGI output.
+    pub fn upstream_finished(&mut self) {
+        self.upstream_time = self.upstream_started.map(|t| t.elapsed());
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.vars.as_ref()?.header(name)
+    }
+
+    fn uri_and_args(&self) -> (&str, &str) {
+        self.path.split_once('?').unwrap_or((&self.path, ""))
+    }
+
+    fn get(&self, name: &str, now: SystemTime) -> String {
+        match name {
+            "remote_addr" => self.remote_addr.ip().to_string(),
+            "request" if self.method.is_empty() => String::new(),
+            "request" => format!("{} {} {}", self.method, self.path, self.version),
+            "request_uri" => self.path.clone(),
+            "request_id" => self.request_id.clone(),
+            "server_name" => self.vhost.clone(),
+            "location" => self.location.clone(),
+            "server_protocol" => self.version.clone(),
+            "status" => self.status.to_string(),
+            "body_bytes_sent" => self.body_bytes_sent.to_string(),
+            "bytes_sent" => self.bytes_sent.to_string(),
+            "request_time" => seconds(self.started.elapsed()),
+            "upstream_response_time" => self.upstream_time.map(seconds).unwrap_or_default(),
+            "time_local" => time_local(now),
+            "time_iso8601" => time_iso8601(now),
+            // As sent, whether or not it was accepted
+            "remote_user" => self.vars.as_ref().and_then(|v| credentials(v.headers())).map(|(user, _)| user).unwrap_or_default(),
+            _ => self.vars.as_ref().and_then(|v| v.get(name)).map(String::from).unwrap_or_default(),
+        }
+    }
+
+    fn render(&self, template: &LogTemplate, now: SystemTime) -> String {
+        match template {
+            LogTemplate::Text(template) => self.render_text(template, now),
+            LogTemplate::Json => self.render_json(now),
+        }
+    }
+
+    /// The line for `template`. Empty values are written as `-`, and quotes,
+    /// backslashes and bytes outside printable ASCII as `\xHH`.
+    fn render_text(&self, template: &str, now: SystemTime) -> String {
+        let mut out = String::with_capacity(template.len() * 2);
+        let mut rest = template;
+        while let Some(pos) = rest.find('$') {
+            out.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+            let len = variables::name_len(after);
+            match self.get(&after[..len], now) {
+                value if value.is_empty() => out.push('-'),
+                value => escape(&value, &mut out),
+            }
+            rest = &after[len..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// One object per request. Durations are in seconds: `read` until the
+    /// request was parsed, `handler` until it produced a response or started
+    /// CGI, `cgi` until the CGI output ended (null without CGI) and `write`
+    /// until the response was sent. There is no TLS, so `tls` is null.
+    fn render_json(&self, now: SystemTime) -> String {
+        let (uri, args) = self.uri_and_args();
+        let handled = self.handled.unwrap_or(self.parsed);
+        let responded = self.responded.unwrap_or(handled);
+        format!(
+            "{{\"timestamp\":{},\"request_id\":{},\"vhost\":{},\"location\":{},\"method\":{},\"path\":{},\"query\":{},\
+             \"status\":{},\"bytes\":{},\"body_bytes\":{},\"durations\":{{\"read\":{},\"handler\":{},\"cgi\":{},\"write\":{},\
+             \"total\":{}}},\"client\":{},\"user_agent\":{},\"tls\":null}}",
+            json_string(&time_iso8601(now)),
+            json_string(&self.request_id),
+            json_string(&self.vhost),
+            json_string(&self.location),
+            json_string(&self.method),
+            json_string(uri),
+            json_string(args),
+            self.status,
+            self.bytes_sent,
+            self.body_bytes_sent,
+            seconds(self.parsed.duration_since(self.started)),
+            seconds(handled.duration_since(self.parsed)),
+            self.upstream_time.map_or_else(|| "null".to_string(), seconds),
+            seconds(responded.elapsed()),
+            seconds(self.started.elapsed()),
+            json_string(&self.remote_addr.to_string()),
+            json_string(self.header("User-Agent").unwrap_or("")),
+        )
+    }
+}
+
+fn escape(value: &str, out: &mut String) {
+    for &b in value.as_bytes() {
+        if b == b'"' || b == b'\\' || !(0x20..0x7f).contains(&b) {
+            out.push_str(&format!("\\x{b:02X}"));
+        } else {
+            out.push(b as char);
+        }
+    }
+}
+
+/// An access log file, opened on its first write.
+struct LogFile {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl LogFile {
+    fn write(&self, data: &[u8]) {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if file.is_none() {
+            match OpenOptions::new().append(true).create(true).open(&self.path) {
+                Ok(f) => *file = Some(f),
+                Err(e) => {
+                    error::log(Level::Error, &format!("cannot open access log {}: {e}", self.path.display()));
+                    return;
+                }
+            }
+        }
+        if let Some(f) = file.as_mut()
+            && let Err(e) = f.write_all(data)
+        {
+            error::log(Level::Error, &format!("cannot write access log {}: {e}", self.path.display()));
+        }
+    }
+}
+
+/// Buffers the access log lines of one event loop thread. Buffers are
+/// handed to `writer` when they fill up and once a second, so the event loop
+/// never waits on the disk. `writer` has a single thread, which keeps the
+/// lines in order.
+pub struct AccessLogWriter {
+    writer: Arc<BlockingPool>,
+    files: HashMap<PathBuf, (Arc<LogFile>, Vec<u8>)>,
+    last_flush: Instant,
+}
+
+impl AccessLogWriter {
+    pub fn new(writer: Arc<BlockingPool>) -> Self {
+        Self { writer, files: HashMap::new(), last_flush: Instant::now() }
+    }
+
+    pub fn log(&mut self, entry: &Entry) {
+        let now = SystemTime::now();
+        for log in &entry.logs {
+            let (file, buf) = self.files.entry(log.path.clone()).or_insert_with(|| {
+                (Arc::new(LogFile { path: log.path.clone(), file: Mutex::new(None) }), Vec::new())
+            });
+            buf.extend_from_slice(entry.render(&log.template, now).as_bytes());
+            buf.push(b'\n');
+            if buf.len() >= FLUSH_SIZE {
+                let (file, data) = (Arc::clone(file), std::mem::take(buf));
+                self.writer.run(move || file.write(&data));
+            }
+        }
+    }
+
+    /// Writes out what is buffered if a second has passed since the last time.
+    pub fn tick(&mut self) {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for (file, buf) in self.files.values_mut() {
+            if !buf.is_empty() {
+                let (file, data) = (Arc::clone(file), std::mem::take(buf));
+                self.writer.run(move || file.write(&data));
+            }
+        }
+        self.last_flush = Instant::now();
+    }
+
+    /// Writes out what is buffered and closes the files; the next line
+    /// reopens each under its configured path.
+    pub fn reopen(&mut self) {
+        self.flush();
+        self.files.clear();
+    }
+
+    /// Writes out what is buffered and waits until every write queued so far
+    /// is done, before the process exits.
+    pub fn close(mut self) {
+        self.flush();
+        let (done, wait) = mpsc::channel();
+        self.writer.run(move || {
+            let _ = done.send(());
+        });
+        let _ = wait.recv();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::merge::PREDEFINED_LOG_FORMATS;
+    use crate::config::parser::parse_config;
+    use std::borrow::Cow;
+    use std::path::Path;
+
+    fn entry(path: &str, headers: &[(&str, &str)]) -> Entry {
+        let cfg = parse_config(
+            "server { listen 8080; server_name site.test; location /api { access_log /dev/null; } }",
+            Path::new("."),
+            "test.conf",
+        )
+        .unwrap();
+        let srv = &cfg.servers[0];
+        let loc = srv.find_location(path).unwrap_or(Cow::Borrowed(&srv.effective));
+        let req = Request::get(path, headers);
+        Entry::new(srv, &loc, "192.0.2.7:5000".parse().unwrap(), Some(&req), Instant::now())
+    }
+
+    #[test]
+    fn test_text_formats() {
+        let mut e = entry("/api/x?a=1", &[("Host", "Site.Test:8080"), ("User-Agent", "curl \"8\"\\é"), ("Referer", "http://r/")]);
+        e.responded(201, 5, 120);
+        let now = SystemTime::now();
+        let combined = PREDEFINED_LOG_FORMATS.iter().find(|(name, _)| *name == "combined").unwrap().1;
+        assert_eq!(
+            e.render_text(combined, now),
+            format!(
+                "192.0.2.7 - - [{}] \"GET /api/x?a=1 HTTP/1.1\" 201 5 \"http://r/\" \"curl \\x228\\x22\\x5C\\xC3\\xA9\"",
+                time_local(now)
+            )
+        );
+        assert_eq!(
+            e.render_text("$host|$uri|$args|$server_name|$location|$request_method $server_protocol|$bytes_sent|$http_x_none|$upstream_response_time", now),
+            "site.test|/api/x|a=1|site.test|/api|GET HTTP/1.1|120|-|-"
+        );
+        // A lone `$` is an empty value; the text around variables is not escaped
+        assert_eq!(e.render_text("\"$status\" $ 100%", now), "\"201\" - 100%");
+
+        let cfg = parse_config("server { listen 8080; }", Path::new("."), "test.conf").unwrap();
+        let srv = &cfg.servers[0];
+        let unparsed = Entry::new(srv, &srv.effective, "192.0.2.7:5000".parse().unwrap(), None, Instant::now());
+        assert_eq!(unparsed.render_text("\"$request\" $status", now), "\"-\" 0");
+    }
+
+    #[test]
+    fn test_json_format() {
+        let mut e = entry("/api/x?a=\"1\"", &[("User-Agent", "ua\n")]);
+        e.request_id = "id1".into();
+        e.upstream_started();
+        e.upstream_finished();
+        e.responded(200, 3, 90);
+        let line = e.render_json(SystemTime::now());
+        let expected = regex::Regex::new(concat!(
+            r#"^\{"timestamp":"[^"]+","request_id":"id1","vhost":"site.test","location":"/api","method":"GET","path":"/api/x","#,
+            r#""query":"a=\\"1\\"","status":200,"bytes":90,"body_bytes":3,"#,
+            r#""durations":\{"read":\d+\.\d{3},"handler":\d+\.\d{3},"cgi":\d+\.\d{3},"write":\d+\.\d{3},"total":\d+\.\d{3}\},"#,
+            r#""client":"192.0.2.7:5000","user_agent":"ua\\n","tls":null\}$"#,
+        ))
+        .unwrap();
+        assert!(expected.is_match(&line), "{line}");
+        // Without CGI there is no cgi duration
+        assert!(entry("/", &[]).render_json(SystemTime::now()).contains("\"cgi\":null"));
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let ids: Vec<String> = (0..100).map(|_| new_request_id()).collect();
+        for id in &ids {
+            assert!(id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()), "{id}");
+        }
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+
+        // Kept from the client, whatever the case of the header name
+        assert_eq!(entry("/", &[("x-request-id", "abc-123_X.y:z")]).request_id, "abc-123_X.y:z");
+        assert_eq!(entry("/", &[("X-Request-ID", &"a".repeat(128))]).request_id, "a".repeat(128));
+        for bad in ["", "has space", "quote\"", "new\nline", "caf\u{e9}", &"a".repeat(129)] {
+            let id = entry("/", &[("X-Request-ID", bad)]).request_id;
+            assert!(id != bad && id.len() == 32, "{bad:?} kept as {id:?}");
+        }
+        assert_eq!(entry("/", &[]).render_text("$request_id", SystemTime::now()).len(), 32);
+    }
+
+    #[test]
+    fn test_unknown_variable() {
+        let assigned = ["site".to_string()];
+        assert_eq!(unknown_variable("$remote_addr $http_x_forwarded_for $status", &[]), None);
+        assert_eq!(unknown_variable("$arg_q $cookie_sid $upstream_http_x_user $1 $site", &assigned), None);
+        assert_eq!(unknown_variable("$status $nope $also", &assigned), Some("nope"));
+        assert_eq!(unknown_variable("$site", &[]), Some("site"));
+    }
+
+    #[test]
+    fn test_routed_variables() {
+        let cfg = parse_config(
+            "server { listen 8080; location ~ ^/u/(\\w+)$ { set $site main; } }",
+            Path::new("."),
+            "test.conf",
+        )
+        .unwrap();
+        let srv = &cfg.servers[0];
+        let req = Request::get("/u/ann?q=1", &[("Cookie", "sid=42")]);
+        let mut e = Entry::new(srv, &srv.effective, "192.0.2.7:5000".parse().unwrap(), Some(&req), Instant::now());
+        let template = "$uri $arg_q $cookie_sid [$site] [$1]";
+        assert_eq!(e.render_text(template, SystemTime::now()), "/u/ann 1 42 [-] [-]");
+        let (_, vars) = crate::application::server::dispatch::dispatch(srv, &req, "192.0.2.7:5000".parse().unwrap());
+        e.routed(vars);
+        assert_eq!(e.render_text(template, SystemTime::now()), "/u/ann 1 42 [main] [ann]");
+    }
+
+    #[test]
+    fn test_writer_keeps_lines_in_order() {
+        let dir = std::env::temp_dir().join(format!("localhost-access-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = AccessLog { path: dir.join("a.log"), format: "t".into(), template: LogTemplate::Text("$uri $status".into()) };
+        let mut writer = AccessLogWriter::new(Arc::new(BlockingPool::new(1).unwrap()));
+        for i in 0..3 {
+            let mut e = entry(&format!("/r{i}"), &[]);
+            e.logs = vec![log.clone()];
+            e.responded(200, 0, 0);
+            writer.log(&e);
+        }
+        writer.reopen();
+        let mut e = entry("/last", &[]);
+        e.logs = vec![log.clone()];
+        writer.log(&e);
+        writer.close();
+        assert_eq!(std::fs::read_to_string(&log.path).unwrap(), "/r0 200\n/r1 200\n/r2 200\n/last 0\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
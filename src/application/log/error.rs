@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::application::format::{json_string, time_iso8601};
+use crate::config::ErrorLog;
+
+/// Severity of an error log message, least severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Notice,
+    Warn,
+    Error,
+    Crit,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Notice => "notice",
+            Level::Warn => "warn",
+            Level::Error => "error",
+            Level::Crit => "crit",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Level> {
+        match name {
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "notice" => Some(Level::Notice),
+            "warn" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            "crit" => Some(Level::Crit),
+            _ => None,
+        }
+    }
+}
+
+/// Where messages go: the top-level `error_log`, plus the files opened so
+/// far, which may also belong to a server's own `error_log`.
+struct Logger {
+    default: ErrorLog,
+    files: Vec<(PathBuf, File)>,
+}
+
+static LOGGER: Mutex<Logger> =
+    Mutex::new(Logger { default: crate::config::merge::DEFAULT_ERROR_LOG, files: Vec::new() });
+
+/// Where a message comes from: the connection and, once it is parsed, the
+/// request it was serving.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub fd: Option<i32>,
+    pub client: Option<SocketAddr>,
+    pub request_id: Option<String>,
+    pub vhost: Option<String>,
+    pub location: Option<String>,
+    /// The server's `error_log`, if it has its own
+    pub error_log: Option<ErrorLog>,
+}
+
+thread_local! {
+    /// Context of the request being handled on this thread
+    static CURRENT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `ctx` attached to everything it logs with `log`, so that
+/// handlers can report errors without knowing about the connection.
+pub fn scoped<T>(ctx: Context, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT.with(|c| c.replace(Some(ctx)));
+    let result = f();
+    CURRENT.with(|c| *c.borrow_mut() = previous);
+    result
+}
+
+/// Makes `default` the log for messages without a server of their own, on
+/// startup and on every reload. Files are reopened on their next message.
+pub fn configure(default: ErrorLog) {
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    logger.default = default;
+    logger.files.clear();
+}
+
+/// Closes every error log file, so that the next message reopens it under
+/// its configured path after it has been rotated.
+pub fn reopen() {
+    LOGGER.lock().unwrap_or_else(|e| e.into_inner()).files.clear();
+}
+
+/// Logs `message` with the context of the request being handled, if any.
+pub fn log(level: Level, message: &str) {
+    CURRENT.with(|c| write(level, message, c.borrow().as_ref()));
+}
+
+pub fn log_with(level: Level, message: &str, ctx: &Context) {
+    write(level, message, Some(ctx));
+}
+
+/// Writes the line on the server's error log or the default one. A file
+/// that cannot be opened is reported, and the line written, on stderr.
+fn write(level: Level, message: &str, ctx: Option<&Context>) {
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    let target = ctx.and_then(|c| c.error_log.clone()).unwrap_or_else(|| logger.default.clone());
+    if level < target.level {
+        return;
+    }
+    let line = render(level, message, ctx, &time_iso8601(SystemTime::now()));
+    if let Some(path) = target.path {
+        let index = match logger.files.iter().position(|(p, _)| *p == path) {
+            Some(index) => Some(index),
+            None => match OpenOptions::new().append(true).create(true).open(&path) {
+                Ok(file) => {
+                    logger.files.push((path.clone(), file));
+                    Some(logger.files.len() - 1)
+                }
+                Err(e) => {
+                    eprintln!("cannot open error log {}: {e}", path.display());
+                    None
+                }
+            },
+        };
+        if let Some(index) = index
+            && logger.files[index].1.write_all(line.as_bytes()).is_ok()
+        {
+            return;
+        }
+    }
+    // Nowhere left to report a failure to
+    let _ = std::io::stderr().write_all(line.as_bytes());
+}
+
+/// One JSON object per line, with the context fields that are known.
+fn render(level: Level, message: &str, ctx: Option<&Context>, timestamp: &str) -> String {
+    let mut line = format!(
+        "{{\"timestamp\":{},\"level\":\"{}\",\"pid\":{},\"message\":{}",
+        json_string(timestamp),
+        level.as_str(),
+        std::process::id(),
+        json_string(message)
+    );
+    if let Some(ctx) = ctx {
+        if let Some(fd) = ctx.fd {
+            line.push_str(&format!(",\"fd\":{fd}"));
+        }
+        let fields = [
+            ("client", ctx.client.map(|c| c.to_string())),
+            ("request_id", ctx.request_id.clone()),
+            ("vhost", ctx.vhost.clone()),
+            ("location", ctx.location.clone()),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                line.push_str(&format!(",\"{name}\":{}", json_string(&value)));
+            }
+        }
+    }
+    line.push_str("}\n");
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let pid = std::process::id();
+        assert_eq!(
+            render(Level::Warn, "disk \"full\"\n", None, "2026-10-18T14:05:09+02:00"),
+            format!("{{\"timestamp\":\"2026-10-18T14:05:09+02:00\",\"level\":\"warn\",\"pid\":{pid},\"message\":\"disk \\\"full\\\"\\n\"}}\n")
+        );
+        let ctx = Context {
+            fd: Some(7),
+            client: Some("[::1]:5000".parse().unwrap()),
+            request_id: Some("abc".into()),
+            vhost: None,
+            location: Some("/api".into()),
+            error_log: None,
+        };
+        assert_eq!(
+            render(Level::Error, "x", Some(&ctx), "t"),
+            format!("{{\"timestamp\":\"t\",\"level\":\"error\",\"pid\":{pid},\"message\":\"x\",\"fd\":7,\"client\":\"[::1]:5000\",\"request_id\":\"abc\",\"location\":\"/api\"}}\n")
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        let levels = [Level::Debug, Level::Info, Level::Notice, Level::Warn, Level::Error, Level::Crit];
+        for level in levels {
+            assert_eq!(Level::parse(level.as_str()), Some(level));
+        }
+        assert!(levels.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(Level::parse("warning"), None);
+    }
+
+    #[test]
+    fn test_server_log_and_reopen() {
+        let dir = std::env::temp_dir().join(format!("localhost-error-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("error.log");
+        let ctx = Context { error_log: Some(ErrorLog { path: Some(path.clone()), level: Level::Warn }), ..Default::default() };
+
+        log_with(Level::Info, "below the level", &ctx);
+        log_with(Level::Error, "first", &ctx);
+        scoped(ctx.clone(), || log(Level::Warn, "scoped"));
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(!written.contains("below the level") && written.contains("\"first\"") && written.contains("\"scoped\""), "{written}");
+
+        // After a rotation the old file keeps its lines until the logs are reopened
+        std::fs::rename(&path, dir.join("error.log.1")).unwrap();
+        log_with(Level::Error, "before reopen", &ctx);
+        reopen();
+        log_with(Level::Error, "after reopen", &ctx);
+        assert!(std::fs::read_to_string(dir.join("error.log.1")).unwrap().contains("before reopen"));
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("after reopen") && !written.contains("before reopen"), "{written}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,455 @@
+use std::cell::UnsafeCell;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::application::log::access::Entry;
+use crate::core::net::connection::ConnState;
+use crate::core::workers::{map_shared, WorkerTable, STATES};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Request series, one per virtual host, location, method and status class
+/// served. Locations come from the config, so this is only reached by very
+/// large configs; requests of series beyond it are not counted.
+const SERIES: usize = 1024;
+/// Longest label set of a series, in bytes
+const LABELS_LEN: usize = 240;
+
+/// Seconds of requests kept for the request rates.
+const RATE_WINDOW: usize = 15 * 60;
+
+/// States of a series slot
+const FREE: u32 = 0;
+const WRITING: u32 = 1;
+const READY: u32 = 2;
+
+/// Totals kept besides the request series.
+#[derive(Clone, Copy)]
+pub enum Counter {
+    Accepted,
+    Handled,
+    BytesReceived,
+    BytesSent,
+    AcceptErrors,
+    Timeouts,
+    CgiSpawns,
+    CgiFailures,
+    Reloads,
+    ReloadFailures,
+}
+
+/// Name and help of each `Counter`, in order.
+const COUNTERS: [(&str, &str); 10] = [
+    ("http_accepted_connections_total", "Client connections accepted."),
+    ("http_handled_connections_total", "Client connections closed after being handled."),
+    ("http_received_bytes_total", "Bytes read from clients."),
+    ("http_sent_bytes_total", "Bytes written to clients."),
+    ("http_accept_errors_total", "Connections that could not be accepted."),
+    ("http_timeouts_total", "Connections closed by the timeout sweep."),
+    ("cgi_spawns_total", "CGI processes started."),
+    ("cgi_failures_total", "CGI processes that could not be started, failed or wrote nothing."),
+    ("config_reloads_total", "Configuration reloads."),
+    ("config_reload_failures_total", "Configuration reloads rejected, which kept the running configuration."),
+];
+
+#[repr(C)]
+struct Histogram {
+    count: AtomicU64,
+    /// In microseconds
+    sum: AtomicU64,
+    /// Observations up to each bound and above the previous one
+    buckets: [AtomicU64; BUCKETS.len()],
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        // Counted first, so that `+Inf` is never below the other buckets
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            out.push_str(&format!("{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {cumulative}\n"));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1e6;
+        out.push_str(&format!("{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}\n"));
+        out.push_str(&format!("{name}_sum{} {sum}\n", braces(labels)));
+        out.push_str(&format!("{name}_count{} {count}\n", braces(labels)));
+    }
+}
+
+/// Requests and their latencies for one label set.
+#[repr(C)]
+struct Series {
+    state: AtomicU32,
+    len: AtomicU32,
+    /// Written once, by the process that moved `state` from FREE to WRITING
+    labels: UnsafeCell<[u8; LABELS_LEN]>,
+    latency: Histogram,
+}
+
+impl Series {
+    /// Only called once `state` is READY.
+    fn labels(&self) -> &str {
+        let len = self.len.load(Ordering::Relaxed) as usize;
+        // SAFETY: no longer written once READY, and only valid UTF-8 is stored
+        unsafe { std::str::from_utf8_unchecked(&(&*self.labels.get())[..len]) }
+    }
+}
+
+/// Requests finished in each of the last `RATE_WINDOW` seconds.
+#[repr(C)]
+struct RequestRate {
+    /// Unix time each count is for
+    seconds: [AtomicU64; RATE_WINDOW],
+    counts: [AtomicU64; RATE_WINDOW],
+}
+
+impl RequestRate {
+    fn record(&self, now: u64) {
+        let i = now as usize % RATE_WINDOW;
+        let second = self.seconds[i].load(Ordering::Relaxed);
+        // A request counted by another process between these two steps is
+        // lost, which the rates can afford
+        if second != now && self.seconds[i].compare_exchange(second, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            self.counts[i].store(0, Ordering::Relaxed);
+        }
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Requests per second over the last `window` seconds, or since `since`
+    /// if that is shorter.
+    fn per_second(&self, window: u64, now: u64, since: u64) -> f64 {
+        let total: u64 = self
+            .seconds
+            .iter()
+            .zip(&self.counts)
+            .filter(|(second, _)| now - second.load(Ordering::Relaxed).min(now) < window)
+            .map(|(_, count)| count.load(Ordering::Relaxed))
+            .sum();
+        total as f64 / window.min(now - since.min(now)).max(1) as f64
+    }
+}
+
+#[repr(C)]
+struct Shared {
+    /// Unix time the server started
+    started: AtomicU64,
+    counters: [AtomicU64; COUNTERS.len()],
+    rate: RequestRate,
+    cgi: Histogram,
+    /// An open addressing table keyed by the label set
+    series: [Series; SERIES],
+}
+
+// SAFETY: the only non-atomic data, the labels of a series, is written
+// before its READY state is published and never after
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    /// The series for `labels`, added if there is none yet.
+    fn series(&self, labels: &str) -> Option<&Series> {
+        let bytes = labels.as_bytes();
+        if bytes.len() > LABELS_LEN {
+            return None;
+        }
+        let start = fnv1a(bytes) as usize % SERIES;
+        for i in 0..SERIES {
+            let series = &self.series[(start + i) % SERIES];
+            loop {
+                match series.state.compare_exchange(FREE, WRITING, Ordering::Acquire, Ordering::Acquire) {
+                    Ok(_) => {
+                        // SAFETY: WRITING keeps every other thread and process out
+                        unsafe { (&mut *series.labels.get())[..bytes.len()].copy_from_slice(bytes) };
+                        series.len.store(bytes.len() as u32, Ordering::Relaxed);
+                        series.state.store(READY, Ordering::Release);
+                        return Some(series);
+                    }
+                    // Another process is adding it, a matter of instructions
+                    Err(WRITING) => std::hint::spin_loop(),
+                    Err(_) => break,
+                }
+            }
+            if series.labels() == labels {
+                return Some(series);
+            }
+        }
+        None
+    }
+}
+
+/// The counters, in memory shared with the workers, and the worker table the
+/// connection gauges are read from.
+static METRICS: OnceLock<(&'static Shared, WorkerTable)> = OnceLock::new();
+
+/// Maps the counters. Called before the first fork, so that every worker adds
+/// to the same ones and any of them can report the totals.
+pub fn init(table: WorkerTable) -> Result<(), String> {
+    let shared = map_shared::<Shared>(1).map_err(|e| format!("cannot map the metrics: {e}"))?;
+    shared[0].started.store(unix_now(), Ordering::Relaxed);
+    let _ = METRICS.set((&shared[0], table));
+    Ok(())
+}
+
+pub fn add(counter: Counter, n: u64) {
+    if let Some((shared, _)) = METRICS.get() {
+        shared.counters[counter as usize].fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// Counts a request once its response is written, with the time it took and
+/// the time its CGI process ran, if any.
+pub fn request(entry: &Entry) {
+    let Some((shared, _)) = METRICS.get() else {
+        return;
+    };
+    let labels = format!(
+        "vhost=\"{}\",location=\"{}\",method=\"{}\",status=\"{}xx\"",
+        escape(&entry.vhost),
+        escape(&entry.location),
+        escape(&entry.method),
+        entry.status / 100
+    );
+    if let Some(series) = shared.series(&labels) {
+        series.latency.observe(entry.started.elapsed());
+    }
+    shared.rate.record(unix_now());
+    if let Some(cgi) = entry.upstream_time {
+        shared.cgi.observe(cgi);
+    }
+}
+
+/// Every metric in the Prometheus text format.
+pub fn render() -> String {
+    let Some((shared, table)) = METRICS.get() else {
+        return String::new();
+    };
+    let ready: Vec<&Series> = shared.series.iter().filter(|s| s.state.load(Ordering::Acquire) == READY).collect();
+    let mut out = String::new();
+
+    header(&mut out, "http_requests_total", "counter", "Requests served by virtual host, location, method and status class.");
+    for series in &ready {
+        out.push_str(&format!("http_requests_total{{{}}} {}\n", series.labels(), series.latency.count.load(Ordering::Relaxed)));
+    }
+    header(&mut out, "http_request_duration_seconds", "histogram", "Time from the first byte of a request to the end of its response.");
+    for series in &ready {
+        series.latency.render(&mut out, "http_request_duration_seconds", series.labels());
+    }
+
+    header(&mut out, "http_connections", "gauge", "Open client connections by state.");
+    for (state, open) in ConnState::NAMES.iter().zip(table.states()) {
+        out.push_str(&format!("http_connections{{state=\"{state}\"}} {open}\n"));
+    }
+
+    for ((name, help), value) in COUNTERS.iter().zip(&shared.counters) {
+        header(&mut out, name, "counter", help);
+        out.push_str(&format!("{name} {}\n", value.load(Ordering::Relaxed)));
+    }
+    header(&mut out, "cgi_duration_seconds", "histogram", "Time from starting a CGI process to the end of its output.");
+    shared.cgi.render(&mut out, "cgi_duration_seconds", "");
+    out
+}
+
+/// Requests of one virtual host, for the status page.
+pub struct VhostTotals {
+    pub vhost: String,
+    pub requests: u64,
+    /// By status class, `1xx` first
+    pub classes: [u64; 5],
+}
+
+/// What the status page shows, totalled over every worker.
+pub struct Summary {
+    pub uptime: Duration,
+    pub workers: usize,
+    pub accepted: u64,
+    pub handled: u64,
+    pub requests: u64,
+    /// Open connections by `ConnState::index`
+    pub states: [u64; STATES],
+    /// Requests per second over 1, 5 and 15 minutes
+    pub rates: [f64; 3],
+    pub vhosts: Vec<VhostTotals>,
+}
+
+pub fn summary() -> Option<Summary> {
+    let (shared, table) = METRICS.get()?;
+    let now = unix_now();
+    let started = shared.started.load(Ordering::Relaxed);
+    let (workers, _, requests) = table.totals();
+    let mut vhosts: Vec<VhostTotals> = Vec::new();
+    for series in shared.series.iter().filter(|s| s.state.load(Ordering::Acquire) == READY) {
+        let labels = series.labels();
+        let vhost = vhost_label(labels);
+        // The status class comes last
+        let class = labels.strip_suffix("xx\"").and_then(|l| l.chars().last()?.to_digit(10)).filter(|c| (1..=5).contains(c));
+        let count = series.latency.count.load(Ordering::Relaxed);
+        let totals = match vhosts.iter().position(|v| v.vhost == vhost) {
+            Some(i) => &mut vhosts[i],
+            None => {
+                vhosts.push(VhostTotals { vhost, requests: 0, classes: [0; 5] });
+                vhosts.last_mut().unwrap()
+            }
+        };
+        totals.requests += count;
+        if let Some(class) = class {
+            totals.classes[class as usize - 1] += count;
+        }
+    }
+    vhosts.sort_by(|a, b| a.vhost.cmp(&b.vhost));
+    Some(Summary {
+        uptime: Duration::from_secs(now.saturating_sub(started)),
+        workers,
+        accepted: shared.counters[Counter::Accepted as usize].load(Ordering::Relaxed),
+        handled: shared.counters[Counter::Handled as usize].load(Ordering::Relaxed),
+        requests,
+        states: table.states(),
+        rates: [60, 300, 900].map(|window| shared.rate.per_second(window, now, started)),
+        vhosts,
+    })
+}
+
+/// A client connection as last published by the event loop serving it.
+pub struct ConnInfo {
+    pub client: SocketAddr,
+    pub state: &'static str,
+    /// Of the request in progress, empty between requests
+    pub path: String,
+    pub opened: Instant,
+}
+
+/// The connections of each event loop thread of this process.
+static CONNECTIONS: Mutex<Vec<(ThreadId, Vec<ConnInfo>)>> = Mutex::new(Vec::new());
+
+/// Replaces the connections published by the calling event loop thread.
+pub fn publish_connections(conns: Vec<ConnInfo>) {
+    let id = thread::current().id();
+    let mut all = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+    match all.iter_mut().find(|(thread, _)| *thread == id) {
+        Some((_, published)) => *published = conns,
+        None => all.push((id, conns)),
+    }
+}
+
+/// Runs `f` on the connections published by this process's event loops.
+pub fn with_connections<T>(f: impl FnOnce(&mut dyn Iterator<Item = &ConnInfo>) -> T) -> T {
+    let all = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut all.iter().flat_map(|(_, conns)| conns))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() { String::new() } else { format!("{{{labels}}}") }
+}
+
+/// The vhost of a request series, whose labels start with it, unescaped.
+fn vhost_label(labels: &str) -> String {
+    let mut vhost = String::new();
+    let mut chars = labels.strip_prefix("vhost=\"").unwrap_or_default().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => vhost.push('\n'),
+                Some(c) => vhost.push(c),
+                None => break,
+            },
+            c => vhost.push(c),
+        }
+    }
+    vhost
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Same in every process, unlike the std hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_render() {
+        let histogram = &map_shared::<Histogram>(1).unwrap()[0];
+        histogram.observe(Duration::from_millis(2));
+        histogram.observe(Duration::from_millis(300));
+        // Above every bound, only in `+Inf`
+        histogram.observe(Duration::from_secs(20));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "t", "vhost=\"a\"");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), BUCKETS.len() + 3);
+        assert_eq!(lines[0], "t_bucket{vhost=\"a\",le=\"0.001\"} 0");
+        assert_eq!(lines[1], "t_bucket{vhost=\"a\",le=\"0.005\"} 1");
+        assert_eq!(lines[6], "t_bucket{vhost=\"a\",le=\"0.25\"} 1");
+        assert_eq!(lines[7], "t_bucket{vhost=\"a\",le=\"0.5\"} 2");
+        assert_eq!(lines[8], "t_bucket{vhost=\"a\",le=\"1\"} 2");
+        assert_eq!(lines[11], "t_bucket{vhost=\"a\",le=\"10\"} 2");
+        assert_eq!(lines[12], "t_bucket{vhost=\"a\",le=\"+Inf\"} 3");
+        assert_eq!(lines[13], "t_sum{vhost=\"a\"} 20.302");
+        assert_eq!(lines[14], "t_count{vhost=\"a\"} 3");
+
+        let empty = &map_shared::<Histogram>(1).unwrap()[0];
+        let mut out = String::new();
+        empty.render(&mut out, "t", "");
+        assert!(out.starts_with("t_bucket{le=\"0.001\"} 0\n"));
+        assert!(out.ends_with("t_bucket{le=\"+Inf\"} 0\nt_sum 0\nt_count 0\n"));
+    }
+
+    #[test]
+    fn test_request_rate() {
+        let rate = &map_shared::<RequestRate>(1).unwrap()[0];
+        rate.record(100);
+        rate.record(1000);
+        rate.record(1000);
+        rate.record(1001);
+        // The second at 100 is outside the window
+        assert_eq!(rate.per_second(60, 1001, 0), 3.0 / 60.0);
+        // Only as long as the server has been up
+        assert_eq!(rate.per_second(60, 1001, 999), 3.0 / 2.0);
+        assert_eq!(rate.per_second(60, 1001, 1001), 3.0);
+
+        // Reuses the slot of 1000, dropping its count
+        rate.record(1000 + RATE_WINDOW as u64);
+        assert_eq!(rate.per_second(RATE_WINDOW as u64, 1000 + RATE_WINDOW as u64, 0), 2.0 / RATE_WINDOW as f64);
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(braces(""), "");
+        assert_eq!(braces("a=\"b\""), "{a=\"b\"}");
+
+        let vhost = "say \"hi\"\\\nbye";
+        let labels = format!("vhost=\"{}\",location=\"/\"", escape(vhost));
+        assert_eq!(labels, "vhost=\"say \\\"hi\\\"\\\\\\nbye\",location=\"/\"");
+        assert_eq!(vhost_label(&labels), vhost);
+        assert_eq!(vhost_label("location=\"/\""), "");
+    }
+}
@@ -1,2 +1,6 @@
+pub mod auth;
+pub mod format;
 pub mod server;
-pub mod handler;
\ No newline at end of file
+pub mod handler;
+pub mod log;
+pub mod metrics;
@@ -0,0 +1,509 @@
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::application::auth::{address, basic};
+use crate::application::handler::cgi::{start_cgi, CgiProcess};
+use crate::application::handler::delete::handle_delete;
+use crate::application::handler::error_page_handler::error_response;
+use crate::application::handler::metrics::handle_metrics;
+use crate::application::handler::status::handle_status;
+use crate::application::handler::static_file::{serve_static, StaticResult};
+use crate::application::handler::upload::handle_upload;
+use crate::application::log::error::{self, Level};
+use crate::application::server::rewrite::{self, Rewrite};
+use crate::application::server::variables::Variables;
+use crate::config::{EffectiveLocation, Server};
+use crate::http::method::Method;
+use crate::http::request::Request;
+use crate::http::{Response, StatusCode};
+
+/// Internal redirects (try_files fallbacks, named locations, rewrites) allowed
+/// per request before giving up with a 500, so a misconfigured chain cannot
+/// loop forever.
+const MAX_INTERNAL_REDIRECTS: usize = 10;
+
+/// How long an `auth_request` subrequest may take before the request fails
+/// with a 500.
+const AUTH_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Dispatch {
+    Response(Response),
+    Cgi(CgiProcess),
+    /// The `auth_request` subrequest is served by CGI: the event loop reads
+    /// its response and hands it to `resume`
+    AuthRequest(CgiProcess, Box<Pending>),
+}
+
+/// A request waiting for the CGI answer to its `auth_request` subrequest.
+pub struct Pending {
+    req: Request,
+    remote_addr: SocketAddr,
+    uri: String,
+    deadline: Instant,
+}
+
+impl Pending {
+    pub fn request(&self) -> &Request {
+        &self.req
+    }
+
+    /// Whether the subrequest took longer than `AUTH_REQUEST_TIMEOUT`.
+    pub fn timed_out(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+/// The response to an `auth_request` subrequest, `None` if it did not come
+/// in time.
+struct Answer {
+    uri: String,
+    resp: Option<Response>,
+}
+
+enum Step {
+    Done(Dispatch),
+    InternalRedirect(String),
+    /// Asked `uri` with a subrequest served by CGI
+    Subrequest(CgiProcess, String),
+}
+
+/// Routes a parsed request through the virtual server it was addressed to.
+/// `remote_addr` is the client's address, exposed as `$remote_addr`. The
+/// variables are returned as routing left them, for the access log.
+pub fn dispatch(srv: &Server, req: &Request, remote_addr: SocketAddr) -> (Dispatch, Variables) {
+    let mut vars = Variables::new(srv, req, remote_addr);
+    (route(srv, req, &mut vars, false, None), vars)
+}
+
+/// Routes a request again once its `auth_request` subrequest answered,
+/// which the same `auth_request` then uses instead of asking again. `None`
+/// if the subrequest did not finish in time.
+pub fn resume(srv: &Server, pending: Pending, resp: Option<Response>) -> (Dispatch, Variables) {
+    let mut vars = Variables::new(srv, &pending.req, pending.remote_addr);
+    let answer = Answer { uri: pending.uri, resp };
+    (route(srv, &pending.req, &mut vars, false, Some(answer)), vars)
+}
+
+/// `dispatch`, skipping the access checks for a subrequest.
+fn route(srv: &Server, req: &Request, vars: &mut Variables, subrequest: bool, mut answer: Option<Answer>) -> Dispatch {
+    let mut redirected: Option<Request> = None;
+
+    // Server-level rewrites run once, before the first location lookup
+    match rewrite::run(&srv.directives.rewrites, vars, &srv.effective) {
+        Rewrite::Respond(resp) => return finish(resp, &srv.effective, vars),
+        Rewrite::Rematch(path) | Rewrite::Break(path) => redirected = Some(with_path(req, path)),
+        Rewrite::Unchanged => {}
+    }
+    let mut loc = find_location(srv, &redirected.as_ref().unwrap_or(req).path, vars);
+
+    for _ in 0..=MAX_INTERNAL_REDIRECTS {
+        let current = redirected.as_ref().unwrap_or(req);
+        let effective = loc.as_deref().unwrap_or(&srv.effective);
+
+        let current = match rewrite::run(&effective.rewrites, vars, effective) {
+            Rewrite::Respond(resp) => return finish(resp, effective, vars),
+            Rewrite::Rematch(path) => {
+                loc = find_location(srv, &path, vars);
+                redirected = Some(with_path(current, path));
+                continue;
+            }
+            Rewrite::Break(path) => {
+                redirected = Some(with_path(current, path));
+                redirected.as_ref().unwrap_or(req)
+            }
+            Rewrite::Unchanged => current,
+        };
+
+        match handle_in_location(srv, effective, current, vars, subrequest, &mut answer) {
+            Step::Done(Dispatch::Response(resp)) => return finish(resp, effective, vars),
+            Step::Done(d) => return d,
+            Step::Subrequest(cgi_proc, uri) => {
+                let pending = Pending { req: req.clone(), remote_addr: vars.remote_addr(), uri, deadline: Instant::now() + AUTH_REQUEST_TIMEOUT };
+                return Dispatch::AuthRequest(cgi_proc, Box::new(pending));
+            }
+            Step::InternalRedirect(target) => {
+                if target.starts_with('@') {
+                    // Named locations keep the original URI
+                    loc = srv.find_named_location(&target).map(Cow::Borrowed);
+                    if loc.is_none() {
+                        break;
+                    }
+                } else {
+                    let path = match (target.contains('?'), current.path.split_once('?')) {
+                        (false, Some((_, query))) => format!("{target}?{query}"),
+                        _ => target,
+                    };
+                    loc = find_location(srv, &path, vars);
+                    vars.set_path(&path);
+                    redirected = Some(with_path(current, path));
+                }
+            }
+        }
+    }
+
+    finish(error_response(StatusCode::InternalServerError, &srv.effective), &srv.effective, vars)
+}
+
+/// Applies the location's `add_header` directives. Like nginx, headers are only
+/// added to successful and redirect responses unless marked `always`.
+fn finish(mut resp: Response, loc: &EffectiveLocation, vars: &Variables) -> Dispatch {
+    let success = matches!(resp.status.as_u16(), 200..=206 | 301..=304 | 307 | 308);
+    for header in loc.add_headers.iter().filter(|h| success || h.always) {
+        resp.headers.insert(header.name.clone(), vars.expand(&header.value));
+    }
+    Dispatch::Response(resp)
+}
+
+/// `Server::find_location`, making the captures of a regex location `$1`..`$9`.
+fn find_location<'s>(srv: &'s Server, path: &str, vars: &mut Variables) -> Option<Cow<'s, EffectiveLocation>> {
+    let (loc, caps) = srv.find_location_captures(path)?;
+    if let Some(caps) = caps {
+        vars.set_captures(&caps);
+    }
+    Some(loc)
+}
+
+fn with_path(req: &Request, path: String) -> Request {
+    let mut next = req.clone();
+    next.path = path;
+    next
+}
+
+fn handle_in_location(
+    srv: &Server,
+    loc: &EffectiveLocation,
+    req: &Request,
+    vars: &mut Variables,
+    subrequest: bool,
+    answer: &mut Option<Answer>,
+) -> Step {
+    // 1. Check body limit
+    if req.body.len() as u64 > loc.client_max_body_size {
+        return respond(error_response(StatusCode::PayloadTooLarge, loc));
+    }
+
+    // 2. Check methods
+    if let Some(allowed) = &loc.methods
+        && !allowed.contains(&req.method.into())
+    {
+        return respond(error_response(StatusCode::MethodNotAllowed, loc));
+    }
+
+    // 3. Check the client address and credentials, and ask the auth_request
+    // location
+    let mut cgi_env = Vec::new();
+    if !subrequest {
+        if let Err(resp) = address::check(vars.remote_addr().ip(), loc) {
+            return respond(resp);
+        }
+        if let Some(auth) = &loc.auth_basic
+            && let Err(resp) = basic::check(auth, &req.headers, loc)
+        {
+            return respond(resp);
+        }
+        if let Some(uri) = &loc.auth_request {
+            let resp = match answer.take_if(|a| a.uri == *uri) {
+                Some(answer) => answer.resp,
+                None => {
+                    let sub = auth_subrequest(req, uri);
+                    match route(srv, &sub, &mut Variables::new(srv, &sub, vars.remote_addr()), true, None) {
+                        Dispatch::Response(resp) => Some(resp),
+                        Dispatch::Cgi(cgi_proc) => return Step::Subrequest(cgi_proc, uri.clone()),
+                        Dispatch::AuthRequest(..) => unreachable!("subrequests skip auth_request"),
+                    }
+                }
+            };
+            match auth_request(loc, uri, resp, vars) {
+                Ok(env) => cgi_env = env,
+                Err(resp) => return respond(resp),
+            }
+        }
+    }
+
+    // 4. Handle redirect
+    if let Some(redir) = &loc.redirect {
+        let mut resp = Response::new(StatusCode::MovedPermanently);
+        resp.headers.insert("Location".into(), vars.expand(redir));
+        return respond(resp);
+    }
+
+    // 5. Handle metrics and the status page
+    if loc.metrics || loc.status.is_some() {
+        if req.method != Method::Get {
+            return respond(error_response(StatusCode::MethodNotAllowed, loc));
+        }
+        return respond(match loc.status {
+            Some(status) => handle_status(status, req),
+            None => handle_metrics(),
+        });
+    }
+
+    // 6. Handle CGI
+    if let Some(cgi_config) = &loc.cgi {
+        return match start_cgi(loc, req, cgi_config, &cgi_env) {
+            Ok(cgi_proc) => Step::Done(Dispatch::Cgi(cgi_proc)),
+            Err(resp) => respond(resp),
+        };
+    }
+
+    // 7. Handle Static / Upload
+    let path_no_q = req.path.split('?').next().unwrap_or("");
+    if path_no_q == "/upload" {
+        return respond(handle_upload(loc, req));
+    }
+    if req.method == Method::Delete {
+        return respond(handle_delete(loc, req));
+    }
+    if req.method != Method::Get {
+        return respond(error_response(StatusCode::MethodNotAllowed, loc));
+    }
+
+    match serve_static(loc, &req.path) {
+        StaticResult::Response(resp) => respond(resp),
+        StaticResult::InternalRedirect(target) => Step::InternalRedirect(target),
+    }
+}
+
+/// The GET subrequest asking `uri` whether `req` may go on. It carries the
+/// request's headers, plus `X-Original-URI` and `X-Original-Method`, but not
+/// its body. Whatever location serves `uri` answers it, CGI included.
+fn auth_subrequest(req: &Request, uri: &str) -> Request {
+    let mut headers = req.headers.clone();
+    headers.retain(|name, _| !["Content-Length", "Content-Type", "Transfer-Encoding"].iter().any(|h| name.eq_ignore_ascii_case(h)));
+    headers.insert("X-Original-URI".into(), req.path.clone());
+    headers.insert("X-Original-Method".into(), req.method.as_str().into());
+    Request {
+        method: Method::Get,
+        path: uri.to_string(),
+        version: req.version.clone(),
+        headers,
+        body: Vec::new(),
+        content_length: None,
+        keep_alive: req.keep_alive,
+    }
+}
+
+/// What the response to the subrequest to `uri` means for the request. A
+/// 2xx allows it and runs `auth_request_set`, whose variables are returned
+/// to be passed to CGI; a 401, with the subrequest's challenge, or a 403 is
+/// the response to send. Anything else, or no response in time, is logged
+/// and becomes a 500.
+fn auth_request(loc: &EffectiveLocation, uri: &str, resp: Option<Response>, vars: &mut Variables) -> Result<Vec<(String, String)>, Response> {
+    let Some(resp) = resp else {
+        error::log(Level::Error, &format!("auth request to {uri} failed: CGI did not finish within {}s", AUTH_REQUEST_TIMEOUT.as_secs()));
+        return Err(error_response(StatusCode::InternalServerError, loc));
+    };
+    match resp.status.as_u16() {
+        200..=299 => {
+            vars.set_upstream(resp.headers);
+            let mut env = Vec::new();
+            for set in &loc.auth_request_set {
+                let value = vars.expand(&set.value);
+                env.push((set.name.to_ascii_uppercase(), value.clone()));
+                vars.set(&set.name, value);
+            }
+            Ok(env)
+        }
+        401 => {
+            let mut challenge = error_response(StatusCode::Unauthorized, loc);
+            if let Some((_, value)) = resp.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("WWW-Authenticate")) {
+                challenge.headers.insert("WWW-Authenticate".into(), value.clone());
+            }
+            Err(challenge)
+        }
+        403 => Err(error_response(StatusCode::Forbidden, loc)),
+        status => {
+            error::log(Level::Error, &format!("auth request to {uri} returned unexpected status {status}"));
+            Err(error_response(StatusCode::InternalServerError, loc))
+        }
+    }
+}
+
+fn respond(resp: Response) -> Step {
+    Step::Done(Dispatch::Response(resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::handler::cgi::parse_cgi_response;
+    use crate::config::parser::parse_config;
+    use std::path::Path;
+
+    fn server(config: &str) -> Server {
+        parse_config(config, Path::new("."), "test.conf").unwrap().servers.remove(0)
+    }
+
+    fn get(srv: &Server, path: &str) -> Response {
+        match dispatch(srv, &Request::get(path, &[]), "127.0.0.1:40000".parse().unwrap()).0 {
+            Dispatch::Response(resp) => resp,
+            Dispatch::Cgi(_) | Dispatch::AuthRequest(..) => panic!("{path} started CGI"),
+        }
+    }
+
+    fn body(resp: &Response) -> &str {
+        std::str::from_utf8(&resp.body).unwrap()
+    }
+
+    #[test]
+    fn test_internal_redirects() {
+        let srv = server(r#"
+            server {
+                listen 8080;
+                root /nonexistent;
+                location /api { try_files $uri /echo; }
+                location /app { try_files $uri @fallback; }
+                location /lost { try_files $uri @nowhere; }
+                location /loop { try_files $uri /loop/again; }
+                location /echo { return 200 "$uri $args"; }
+                location @fallback { return 200 "named $uri"; }
+            }
+        "#);
+        // The query string follows an internal redirect that has none of its own
+        assert_eq!(body(&get(&srv, "/api/x?a=1")), "/echo a=1");
+        // A named location keeps the original URI
+        assert_eq!(body(&get(&srv, "/app/page")), "named /app/page");
+        assert_eq!(get(&srv, "/lost").status.as_u16(), 500);
+        // A chain that never settles gives up after MAX_INTERNAL_REDIRECTS
+        assert_eq!(get(&srv, "/loop/x").status.as_u16(), 500);
+    }
+
+    #[test]
+    fn test_location_modifiers() {
+        let srv = server(r#"
+            server {
+                listen 8080;
+                location = /exact { return 200 exact; }
+                location /exact { return 200 prefix; }
+                location ^~ /static/ { return 200 "no regex"; }
+                location ~* \.(png|jpg)$ { add_header X-Ext $1; return 200 "image $1"; }
+                location ~ ^/users/(\w+)/(\d+)$ { return 301 /u/$1?n=$2; }
+            }
+        "#);
+        assert_eq!(body(&get(&srv, "/exact")), "exact");
+        assert_eq!(body(&get(&srv, "/exact/more")), "prefix");
+        assert_eq!(body(&get(&srv, "/static/a.png")), "no regex");
+        // Captures of the location regex reach return and add_header
+        let resp = get(&srv, "/img/a.JPG");
+        assert_eq!((body(&resp), resp.headers["X-Ext"].as_str()), ("image JPG", "JPG"));
+        assert_eq!(get(&srv, "/users/ann/42").headers["Location"], "/u/ann?n=42");
+    }
+
+    #[test]
+    fn test_nested_locations() {
+        let srv = server(r#"
+            server {
+                listen 8080;
+                add_header X-Server yes;
+                location /app {
+                    add_header X-App $uri;
+                    methods POST;
+                    location /app/admin { }
+                    location /app/open { methods GET; return 200 open; }
+                }
+            }
+        "#);
+        // The inner location inherits the outer methods and add_header, not the server's
+        assert_eq!(get(&srv, "/app/admin").status.as_u16(), 405);
+        let resp = get(&srv, "/app/open/x");
+        assert_eq!((body(&resp), resp.headers["X-App"].as_str()), ("open", "/app/open/x"));
+        assert!(!resp.headers.contains_key("X-Server"));
+    }
+
+    #[test]
+    fn test_rewrite_phases() {
+        let srv = server(r#"
+            server {
+                listen 8080;
+                root /nonexistent;
+                rewrite ^/legacy/(.*)$ /blog/$1 last;
+                location /blog {
+                    rewrite ^/blog/(\d+)$ /post?id=$1 last;
+                    rewrite ^/blog/here$ /blog/served break;
+                    return 200 "blog $uri";
+                }
+                location /post { return 200 "post $arg_id"; }
+                location /loop { rewrite ^/loop$ /loop; }
+            }
+        "#);
+        // Server rules run before the location lookup, location rules after it
+        assert_eq!(body(&get(&srv, "/legacy/7")), "post 7");
+        // `break` stops the rewrite phase and serves from the same location
+        assert_eq!(get(&srv, "/blog/here").status.as_u16(), 404);
+        assert_eq!(get(&srv, "/loop").status.as_u16(), 500);
+    }
+
+    #[test]
+    fn test_auth_request() {
+        let dir = std::env::temp_dir().join(format!("localhost-auth-request-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for sub in ["ok", "deny", "who", "odd", "cgi"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+            std::fs::write(dir.join(sub).join("a.txt"), sub).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("auth")).unwrap();
+        std::fs::write(dir.join("auth/slow.sh"), "sleep 5\n").unwrap();
+        let srv = server(&format!(r#"
+            server {{
+                listen 8080;
+                root {};
+                auth_request_set $user $upstream_http_x_user;
+                add_header X-User $user;
+                location = /auth/ok {{ add_header X-User ann; return 200; }}
+                location = /auth/deny {{ return 403; }}
+                location = /auth/who {{ add_header WWW-Authenticate "Basic realm=x" always; return 401; }}
+                location = /auth/odd {{ return 302 /elsewhere; }}
+                location /auth/ {{ cgi .sh /bin/sh; }}
+                location /ok {{ auth_request /auth/ok; }}
+                location /deny {{ auth_request /auth/deny; }}
+                location /who {{ auth_request /auth/who; }}
+                location /odd {{ auth_request /auth/odd; }}
+                location /cgi {{ auth_request /auth/slow.sh; }}
+            }}
+        "#, dir.display()));
+
+        // Subrequests answered without CGI are handled right away
+        let resp = get(&srv, "/ok/a.txt");
+        assert_eq!((resp.status.as_u16(), body(&resp), resp.headers["X-User"].as_str()), (200, "ok", "ann"));
+        assert_eq!(get(&srv, "/deny/a.txt").status.as_u16(), 403);
+        let resp = get(&srv, "/who/a.txt");
+        assert_eq!((resp.status.as_u16(), resp.headers["WWW-Authenticate"].as_str()), (401, "Basic realm=x"));
+        assert_eq!(get(&srv, "/odd/a.txt").status.as_u16(), 500);
+
+        // One served by CGI leaves the request pending until it answers
+        let req = Request::get("/cgi/a.txt", &[]);
+        let Dispatch::AuthRequest(cgi_proc, pending) = dispatch(&srv, &req, "127.0.0.1:40000".parse().unwrap()).0 else {
+            panic!("no auth subrequest");
+        };
+        assert_eq!(pending.request().path, "/cgi/a.txt");
+        assert!(!pending.timed_out());
+        unsafe {
+            libc::kill(cgi_proc.pid, libc::SIGKILL);
+            libc::waitpid(cgi_proc.pid, std::ptr::null_mut(), 0);
+            for fd in [cgi_proc.output, cgi_proc.errors] {
+                libc::close(fd);
+            }
+        }
+        assert!(cgi_proc.input.is_none());
+
+        let answers = [
+            (Some("Status: 200 OK\r\nX-User: bob\r\n\r\n"), 200, "bob"),
+            (Some("Status: 403 Forbidden\r\n\r\n"), 403, ""),
+            // It did not answer in time
+            (None, 500, ""),
+        ];
+        for (answer, status, user) in answers {
+            let pending = Pending { req: req.clone(), remote_addr: pending.remote_addr, uri: pending.uri.clone(), deadline: pending.deadline };
+            let resp = match resume(&srv, pending, answer.map(|a| parse_cgi_response(a.as_bytes()))).0 {
+                Dispatch::Response(resp) => resp,
+                _ => panic!("{answer:?} did not answer the request"),
+            };
+            assert_eq!(resp.status.as_u16(), status, "{answer:?}");
+            if status == 200 {
+                assert_eq!((body(&resp), resp.headers["X-User"].as_str()), ("cgi", user));
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,10 +1,14 @@
 use std::collections::HashMap;
 
-use crate::core::net::connection::Connection;
+use crate::application::metrics::{self, Counter};
+use crate::core::net::connection::{Connection, ConnState};
 
 pub struct ServerManager {
     pub conns: HashMap<i32, Connection>,
     pub pipe_map: HashMap<i32, i32>,
+    /// CGI processes that closed their output before exiting, reaped once
+    /// they have
+    pub exiting: Vec<i32>,
 }
 
 impl ServerManager {
@@ -12,10 +16,12 @@ impl ServerManager {
         Self {
             conns: HashMap::new(),
             pipe_map: HashMap::new(),
+            exiting: Vec::new(),
         }
     }
 
     pub fn insert(&mut self, fd_raw: i32, conn: Connection) {
+        metrics::add(Counter::Accepted, 1);
         self.conns.insert(fd_raw, conn);
     }
 
@@ -23,7 +29,37 @@ impl ServerManager {
         // If it's a connection, remove it and any associated pipes?
         // We don't know the pipes easily unless we scan or store them in Connection.
         // For now, assume caller handles unregister_pipe.
-        self.conns.remove(&fd);
+        if self.conns.remove(&fd).is_some() {
+            metrics::add(Counter::Handled, 1);
+        }
+    }
+
+    /// Connections waiting for a new request with nothing buffered, which a
+    /// stopping server can close right away.
+    pub fn idle(&self) -> Vec<i32> {
+        self.conns
+            .iter()
+            .filter(|(_, c)| match c.state {
+                ConnState::Reading => c.read_buf.is_empty(),
+                ConnState::Closing => true,
+                _ => false,
+            })
+            .map(|(&fd, _)| fd)
+            .collect()
+    }
+
+    /// Connections whose `auth_request` subrequest took too long.
+    pub fn late_subrequests(&self) -> Vec<i32> {
+        self.conns
+            .iter()
+            .filter(|(_, c)| matches!(&c.state, ConnState::Cgi { subrequest: Some(pending), .. } if pending.timed_out()))
+            .map(|(&fd, _)| fd)
+            .collect()
+    }
+
+    /// Collects the CGI processes in `exiting` that have exited.
+    pub fn reap(&mut self) {
+        self.exiting.retain(|&pid| unsafe { libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) } == 0);
     }
 
         pub fn sweep_timeouts(&mut self) -> Vec<i32> {
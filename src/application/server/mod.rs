@@ -1 +1,4 @@
-pub mod manager;
\ No newline at end of file
+pub mod manager;
+pub mod dispatch;
+pub mod rewrite;
+pub mod variables;
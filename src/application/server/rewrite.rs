@@ -0,0 +1,189 @@
+use crate::application::handler::error_page_handler::error_response;
+use crate::application::server::variables::Variables;
+use crate::config::{EffectiveLocation, RewriteFlag, RewriteOp};
+use crate::http::{Response, StatusCode};
+
+/// Result of running a block's `rewrite` / `return` rules against a request.
+pub enum Rewrite {
+    /// No rule changed the URI
+    Unchanged,
+    /// The URI was rewritten (`last`, or no flag) and the location must be
+    /// searched again. Holds the new path including the query.
+    Rematch(String),
+    /// The URI was rewritten with `break`: serve it from the current location
+    Break(String),
+    Respond(Response),
+}
+
+/// Runs `ops` in order. `$uri` and `$args` in `vars` track the rewritten path
+/// and `set` assigns into `vars`.
+pub fn run(ops: &[RewriteOp], vars: &mut Variables, loc: &EffectiveLocation) -> Rewrite {
+    let mut changed = false;
+
+    for op in ops {
+        match op {
+            RewriteOp::Set { name, value } => {
+                let value = vars.expand(value);
+                vars.set(name, value);
+            }
+            RewriteOp::Return { code, target } => {
+                let status = StatusCode::from_u16(*code).unwrap_or(StatusCode::InternalServerError);
+                let target = target.as_deref().map(|t| vars.expand(t));
+                return Rewrite::Respond(return_response(status, target, loc));
+            }
+            RewriteOp::Rewrite { regex, replacement, flag } => {
+                let uri = vars.uri().to_string();
+                let Some(caps) = regex.captures(&uri) else {
+                    continue;
+                };
+                let replaced = vars.expand_with(replacement, Some(&caps));
+                vars.set_captures(&caps);
+                // A `?` in the replacement sets new arguments; the original ones
+                // are appended unless the replacement ends with `?`
+                let path = match replaced.split_once('?') {
+                    Some((u, "")) => u.to_string(),
+                    Some((_, _)) if vars.args().is_empty() => replaced,
+                    Some((_, _)) => format!("{replaced}&{}", vars.args()),
+                    None => join(&replaced, vars.args()),
+                };
+                vars.set_path(&path);
+                changed = true;
+
+                let external = path.starts_with("http://") || path.starts_with("https://");
+                match flag {
+                    Some(RewriteFlag::Permanent) => return Rewrite::Respond(redirect(StatusCode::MovedPermanently, path)),
+                    Some(RewriteFlag::Redirect) => return Rewrite::Respond(redirect(StatusCode::Found, path)),
+                    _ if external => return Rewrite::Respond(redirect(StatusCode::Found, path)),
+                    Some(RewriteFlag::Last) => return Rewrite::Rematch(path),
+                    Some(RewriteFlag::Break) => return Rewrite::Break(path),
+                    None => {}
+                }
+            }
+        }
+    }
+
+    if changed { Rewrite::Rematch(join(vars.uri(), vars.args())) } else { Rewrite::Unchanged }
+}
+
+fn return_response(status: StatusCode, target: Option<String>, loc: &EffectiveLocation) -> Response {
+    match target {
+        Some(url) if status.is_redirect() => redirect(status, url),
+        Some(text) => {
+            let mut resp = Response::new(status);
+            resp.body = text.into_bytes();
+            resp.headers.insert("Content-Type".into(), "text/plain; charset=utf-8".into());
+            resp
+        }
+        None if status.as_u16() >= 400 => error_response(status, loc),
+        None => Response::new(status),
+    }
+}
+
+fn redirect(status: StatusCode, location: String) -> Response {
+    let mut resp = Response::new(status);
+    resp.headers.insert("Location".into(), location);
+    resp
+}
+
+fn join(uri: &str, args: &str) -> String {
+    if args.is_empty() { uri.to_string() } else { format!("{uri}?{args}") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::parse_config;
+    use crate::config::Server;
+    use crate::http::request::Request;
+    use regex::Regex;
+    use std::path::Path;
+
+    fn server() -> Server {
+        parse_config("server { listen 8080; server_name example.test; }", Path::new("."), "test.conf").unwrap().servers.remove(0)
+    }
+
+    fn rewrite(regex: &str, replacement: &str, flag: Option<RewriteFlag>) -> RewriteOp {
+        RewriteOp::Rewrite { regex: Regex::new(regex).unwrap(), replacement: replacement.into(), flag }
+    }
+
+    /// Runs `ops` against a GET for `path`, returning the outcome and the final `$uri?$args`.
+    fn apply(ops: &[RewriteOp], path: &str) -> (Rewrite, String) {
+        let srv = server();
+        let req = Request::get(path, &[]);
+        let mut vars = Variables::new(&srv, &req, "127.0.0.1:40000".parse().unwrap());
+        let outcome = run(ops, &mut vars, &srv.effective);
+        let uri = join(vars.uri(), vars.args());
+        (outcome, uri)
+    }
+
+    fn rematch(outcome: Rewrite) -> String {
+        match outcome {
+            Rewrite::Rematch(path) => path,
+            _ => panic!("expected a rematch"),
+        }
+    }
+
+    fn respond(outcome: Rewrite) -> Response {
+        match outcome {
+            Rewrite::Respond(resp) => resp,
+            _ => panic!("expected a response"),
+        }
+    }
+
+    #[test]
+    fn test_captures_and_arguments() {
+        let last = [rewrite(r"^/blog/(\d+)/(\w+)$", "/post?id=$1&slug=$2", Some(RewriteFlag::Last))];
+        assert_eq!(rematch(apply(&last, "/blog/42/hello").0), "/post?id=42&slug=hello");
+        // The original arguments follow the new ones
+        assert_eq!(rematch(apply(&last, "/blog/42/hello?x=1").0), "/post?id=42&slug=hello&x=1");
+        let plain = [rewrite("^/old/(.*)$", "/new/$1", Some(RewriteFlag::Last))];
+        assert_eq!(rematch(apply(&plain, "/old/a/b?x=1").0), "/new/a/b?x=1");
+        // A trailing `?` drops them
+        let drop = [rewrite("^/old/(.*)$", "/new/$1?", Some(RewriteFlag::Last))];
+        assert_eq!(rematch(apply(&drop, "/old/a?x=1").0), "/new/a");
+        // `$10` is the first capture followed by 0
+        let digits = [rewrite("^/(a)$", "/$10", Some(RewriteFlag::Last))];
+        assert_eq!(rematch(apply(&digits, "/a").0), "/a0");
+    }
+
+    #[test]
+    fn test_flags() {
+        let (outcome, _) = apply(&[rewrite("^/x$", "/y", Some(RewriteFlag::Break)), rewrite("^/y$", "/z", None)], "/x");
+        assert!(matches!(outcome, Rewrite::Break(path) if path == "/y"));
+
+        // Without a flag the following rules see the new URI
+        let chain = [rewrite("^/a$", "/b", None), rewrite("^/b$", "/c", None), rewrite("^/nope$", "/d", None)];
+        let (outcome, uri) = apply(&chain, "/a");
+        assert_eq!(rematch(outcome), "/c");
+        assert_eq!(uri, "/c");
+        assert!(matches!(apply(&chain, "/other").0, Rewrite::Unchanged));
+
+        let resp = respond(apply(&[rewrite("^/(.*)$", "/new/$1", Some(RewriteFlag::Permanent))], "/p?q=1").0);
+        assert_eq!((resp.status.as_u16(), resp.headers["Location"].as_str()), (301, "/new/p?q=1"));
+        let resp = respond(apply(&[rewrite("^/(.*)$", "/new/$1", Some(RewriteFlag::Redirect))], "/p").0);
+        assert_eq!(resp.status.as_u16(), 302);
+        // An absolute URL redirects even with `last`
+        let resp = respond(apply(&[rewrite("^/ext/(.*)$", "https://$host/$1", Some(RewriteFlag::Last))], "/ext/a").0);
+        assert_eq!((resp.status.as_u16(), resp.headers["Location"].as_str()), (302, "https://example.test/a"));
+    }
+
+    #[test]
+    fn test_return_and_set() {
+        let ops = [
+            RewriteOp::Set { name: "who".into(), value: "$arg_name".into() },
+            rewrite("^/hi$", "/hello", None),
+            RewriteOp::Return { code: 200, target: Some("$who at $uri".into()) },
+            RewriteOp::Return { code: 500, target: None },
+        ];
+        let resp = respond(apply(&ops, "/hi?name=ann").0);
+        assert_eq!((resp.status.as_u16(), resp.body.as_slice()), (200, b"ann at /hello".as_slice()));
+        assert_eq!(resp.headers["Content-Type"], "text/plain; charset=utf-8");
+
+        let resp = respond(apply(&[RewriteOp::Return { code: 307, target: Some("/to$uri".into()) }], "/x").0);
+        assert_eq!((resp.status.as_u16(), resp.headers["Location"].as_str()), (307, "/to/x"));
+        let resp = respond(apply(&[RewriteOp::Return { code: 204, target: None }], "/x").0);
+        assert!(resp.status.as_u16() == 204 && resp.body.is_empty());
+        let resp = respond(apply(&[RewriteOp::Return { code: 410, target: None }], "/x").0);
+        assert!(String::from_utf8_lossy(&resp.body).contains("410"));
+    }
+}
@@ -0,0 +1,267 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use regex::Captures;
+
+use crate::config::Server;
+use crate::http::headers::Headers;
+use crate::http::method::Method;
+use crate::http::request::Request;
+
+const BUILTIN_VARIABLES: &[&str] = &["host", "uri", "args", "remote_addr", "request_method"];
+const BUILTIN_PREFIXES: &[&str] = &["arg_", "http_", "cookie_", "upstream_http_"];
+
+/// The `$name` values visible to one request: built-ins read from the request
+/// and connection, plus whatever the rewrite phase assigned with `set` and
+/// `auth_request_set`. `$uri` and `$args` follow the request through rewrites.
+/// `$upstream_http_<header>` reads the response to the `auth_request`
+/// subrequest. The access log keeps the values a request ended up with.
+pub struct Variables {
+    method: Method,
+    headers: Headers,
+    remote_addr: SocketAddr,
+    host: String,
+    uri: String,
+    args: String,
+    custom: HashMap<String, String>,
+    upstream: Headers,
+    /// `$1`..`$9` of the last location or `rewrite` regex that matched
+    captures: Vec<String>,
+}
+
+impl Variables {
+    pub fn new(srv: &Server, req: &Request, remote_addr: SocketAddr) -> Self {
+        // `$host`: the Host header without its port, or the first server_name
+        let host = req
+            .header("Host")
+            .and_then(|h| h.split(':').next())
+            .filter(|h| !h.is_empty())
+            .or_else(|| srv.server_names.first().map(String::as_str))
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let mut vars = Self {
+            method: req.method,
+            headers: req.headers.clone(),
+            remote_addr,
+            host,
+            uri: String::new(),
+            args: String::new(),
+            custom: HashMap::new(),
+            upstream: Headers::new(),
+            captures: Vec::new(),
+        };
+        vars.set_path(&req.path);
+        vars
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    pub fn args(&self) -> &str {
+        &self.args
+    }
+
+    /// Updates `$uri` and `$args` after the request path was rewritten.
+    pub fn set_path(&mut self, path: &str) {
+        let (uri, args) = path.split_once('?').unwrap_or((path, ""));
+        self.uri = uri.to_string();
+        self.args = args.to_string();
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// The request's headers, as received.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Keeps the groups of a regex that matched the URI as `$1`..`$9`.
+    pub fn set_captures(&mut self, caps: &Captures) {
+        self.captures = caps.iter().skip(1).take(9).map(|m| m.map_or("", |m| m.as_str()).to_string()).collect();
+    }
+
+    /// Makes the headers of a subrequest's response `$upstream_http_*`.
+    pub fn set_upstream(&mut self, headers: Headers) {
+        self.upstream = headers;
+    }
+
+    pub fn set(&mut self, name: &str, value: String) {
+        self.custom.insert(name.to_string(), value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        let value = match name {
+            "host" => &self.host,
+            "uri" => &self.uri,
+            "args" => &self.args,
+            "remote_addr" => return Some(Cow::Owned(self.remote_addr.ip().to_string())),
+            "request_method" => return Some(Cow::Borrowed(self.method.as_str())),
+            _ => {
+                if let Some(d) = capture_index(name) {
+                    return self.captures.get(d - 1).map(|c| Cow::Borrowed(c.as_str()));
+                }
+                if let Some(arg) = name.strip_prefix("arg_") {
+                    return self
+                        .args
+                        .split('&')
+                        .find_map(|pair| pair.strip_prefix(arg)?.strip_prefix('='))
+                        .map(Cow::Borrowed);
+                }
+                if let Some(header) = name.strip_prefix("upstream_http_") {
+                    let header = header.replace('_', "-");
+                    return self
+                        .upstream
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case(&header))
+                        .map(|(_, v)| Cow::Borrowed(v.as_str()));
+                }
+                if let Some(header) = name.strip_prefix("http_") {
+                    return self.header(&header.replace('_', "-")).map(Cow::Borrowed);
+                }
+                if let Some(cookie) = name.strip_prefix("cookie_") {
+                    return self
+                        .header("Cookie")?
+                        .split(';')
+                        .find_map(|c| c.trim().strip_prefix(cookie)?.strip_prefix('='))
+                        .map(Cow::Borrowed);
+                }
+                return self.custom.get(name).map(|v| Cow::Borrowed(v.as_str()));
+            }
+        };
+        Some(Cow::Borrowed(value))
+    }
+
+    /// Interpolates `$name` references in `template`.
+    pub fn expand(&self, template: &str) -> String {
+        self.expand_with(template, None)
+    }
+
+    /// Interpolates `$name` references, and `$1`..`$9` from `caps` when given.
+    /// Built-in variables with no value (a missing header, cookie or argument)
+    /// expand to nothing; unknown names are left as written.
+    pub fn expand_with(&self, template: &str, caps: Option<&Captures>) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(pos) = rest.find('$') {
+            out.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+            let name_len = name_len(after);
+            let name = &after[..name_len];
+
+            if let Some(d) = name.chars().next().and_then(|c| c.to_digit(10)) {
+                match caps {
+                    Some(caps) => out.push_str(caps.get(d as usize).map(|m| m.as_str()).unwrap_or("")),
+                    None => out.push_str(&rest[pos..pos + 2]),
+                }
+                rest = &after[1..];
+                continue;
+            }
+            match self.get(name) {
+                Some(value) => out.push_str(&value),
+                None if is_builtin(name) => {}
+                None => {
+                    out.push('$');
+                    out.push_str(name);
+                }
+            }
+            rest = &after[name_len..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Whether `name` is a built-in variable, which `set` may not assign.
+pub fn is_builtin(name: &str) -> bool {
+    BUILTIN_VARIABLES.contains(&name) || BUILTIN_PREFIXES.iter().any(|p| name.starts_with(p)) || capture_index(name).is_some()
+}
+
+/// Length of the variable name at the start of `s`, the text after a `$`.
+/// Only a single digit is a capture reference: `$10` is `$1` then `0`.
+pub fn name_len(s: &str) -> usize {
+    match s.as_bytes().first() {
+        Some(b'0'..=b'9') => 1,
+        _ => s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(s.len()),
+    }
+}
+
+/// `1`..`9` for the names of regex captures.
+fn capture_index(name: &str) -> Option<usize> {
+    match name.as_bytes() {
+        &[d @ b'1'..=b'9'] => Some(usize::from(d - b'0')),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parser::parse_config;
+    use std::path::Path;
+
+    fn server() -> Server {
+        parse_config("server { listen 8080; server_name Example.Test; }", Path::new("."), "test.conf").unwrap().servers.remove(0)
+    }
+
+    fn expand(req: &Request, template: &str) -> String {
+        Variables::new(&server(), req, "[::1]:40000".parse().unwrap()).expand(template)
+    }
+
+    #[test]
+    fn test_builtins() {
+        let req = Request::get(
+            "/a/b?name=ann&x=1&named=no",
+            &[("Host", "WWW.Site.Test:8080"), ("User-Agent", "curl/8"), ("Cookie", "sid=42; theme=dark"), ("X-Custom-Id", "7")],
+        );
+        assert_eq!(expand(&req, "$host|$uri|$args|$remote_addr|$request_method"), "www.site.test|/a/b|name=ann&x=1&named=no|::1|GET");
+        assert_eq!(expand(&req, "$arg_name $arg_named $cookie_theme $http_user_agent $http_x_custom_id"), "ann no dark curl/8 7");
+        // Missing built-ins expand to nothing, unknown names stay as written
+        assert_eq!(expand(&req, "[$arg_missing$cookie_none$http_nope] $unknown $"), "[] $unknown $");
+        // `$host` falls back to the first server_name
+        assert_eq!(expand(&Request::get("/", &[]), "$host$uri$args"), "example.test/");
+    }
+
+    #[test]
+    fn test_set_and_captures() {
+        let req = Request::get("/x?y=1", &[]);
+        let srv = server();
+        let mut vars = Variables::new(&srv, &req, "127.0.0.1:40000".parse().unwrap());
+        vars.set("site", "main".into());
+        vars.set_path("/new/path?q=2");
+        assert_eq!(vars.expand("$site:$uri:$args:$arg_q:$arg_y"), "main:/new/path:q=2:2:");
+
+        let caps = regex::Regex::new(r"^/(\w+)/(\w+)$").unwrap().captures("/one/two").unwrap();
+        assert_eq!(vars.expand_with("$2-$1-$3-$site", Some(&caps)), "two-one--main");
+        // Without captures `$1` is kept, and only one digit is a reference
+        assert_eq!(vars.expand("$1"), "$1");
+        assert_eq!(vars.expand_with("$12", Some(&caps)), "one2");
+
+        assert!(is_builtin("arg_anything") && is_builtin("uri") && !is_builtin("site"));
+    }
+
+    #[test]
+    fn test_upstream_headers() {
+        let req = Request::get("/", &[("X-User", "client")]);
+        let srv = server();
+        let mut vars = Variables::new(&srv, &req, "127.0.0.1:40000".parse().unwrap());
+        // Empty until a subrequest answered
+        assert_eq!(vars.expand("[$upstream_http_x_user]"), "[]");
+        let mut headers = Headers::new();
+        headers.insert("x-user".into(), "ann".into());
+        headers.insert("X-Auth-Groups".into(), "a,b".into());
+        vars.set_upstream(headers);
+        assert_eq!(vars.expand("$upstream_http_x_user $upstream_http_X_AUTH_GROUPS $http_x_user"), "ann a,b client");
+        assert!(is_builtin("upstream_http_x_user"));
+    }
+}
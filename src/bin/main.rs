@@ -6,71 +6,259 @@ mod application;
 mod http;
 #[path = "../config/mod.rs"]
 mod config;
+#[path = "../cli.rs"]
+mod cli;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
 use std::os::fd::AsRawFd;
-use std::path::Path;
-use std::time::Duration;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use application::handler::{error_page_handler::error_response, static_file::serve_static, cgi::{start_cgi, parse_cgi_response}, upload::handle_upload};
+use application::handler::{error_page_handler::error_response, cgi::parse_cgi_response};
+use application::log::access::{AccessLogWriter, Entry, REQUEST_ID_HEADER};
+use application::log::error::{self, Context, Level};
+use application::metrics::{self, Counter};
+use application::server::dispatch::{dispatch, resume, Dispatch};
 use application::server::manager::ServerManager;
-use config::load_config;
+use application::server::variables::Variables;
+use cli::Action;
+use config::{load_config, Config, Server};
+use config::merge::MAX_WORKER_PROCESSES;
 use core::event::EventLoop;
+use core::event::mailbox::{self, Mailbox, MailboxSender};
+use core::signal;
+use core::workers::{WorkerSlot, WorkerTable, STATES};
 use core::net::connection::{Connection, ConnState};
+use core::net::fd::Fd;
+use core::net::inherit::{self, LISTENERS_ENV, UPGRADE_FROM_ENV};
 use core::net::socket::{accept_nonblocking, create_listening_socket};
+use core::pool::BlockingPool;
 use http::parser::{parse_request, ParseResult};
 use http::serializer::serialize_response;
 use http::{Response, StatusCode};
 
 fn main() -> Result<(), String> {
-    let cfg = load_config(std::path::Path::new("config.conf"))?;
-    let event_loop = EventLoop::new()?;
-    let mut mgr = ServerManager::new();
-    let mut listen_map: HashMap<i32, SocketAddr> = HashMap::new();
-    let mut listen_fds: Vec<core::net::fd::Fd> = Vec::new();
-    let mut seen = HashSet::new();
-
-    for srv in &cfg.servers {
-        for addr in &srv.listen {
-            for addr in &srv.listen {
-                for name in &srv.server_names {
-                    let key = (addr, name);
-                    if !seen.insert(key.clone()) {
-                       eprintln!("Config error: duplicate listen address {} with server_name '{}'", addr, name);
-                       std::process::exit(1);
-                    }
-                }
+    let opts = cli::parse_args(std::env::args().skip(1))
+        .unwrap_or_else(|e| fail(&format!("{e}\n\n{}", cli::USAGE)));
+    if opts.action == Action::Help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    // Where the binary was started from, to start it the same way on upgrade
+    let start_dir = std::env::current_dir().map_err(|e| format!("cannot read the current directory: {e}"))?;
+    if let Some(prefix) = &opts.prefix {
+        std::env::set_current_dir(prefix)
+            .unwrap_or_else(|e| fail(&format!("cannot use prefix {}: {e}", prefix.display())));
+    }
+
+    if let Action::Signal(sig) = opts.action {
+        // Only the pid file is needed: a running server can be stopped even
+        // if its config no longer validates
+        let cfg = config::loader::read_config(&opts.config).unwrap_or_else(|e| fail(&e));
+        cli::signal_running(&cfg.pid, sig).unwrap_or_else(|e| fail(&e));
+        return Ok(());
+    }
+
+    let testing = matches!(opts.action, Action::Test | Action::Dump);
+    let cfg = match load_config(&opts.config) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            // Printed as is: parse errors span several lines
+            eprintln!("{e}");
+            if testing {
+                eprintln!("{}: configuration test failed", opts.config.display());
             }
-            if !listen_map.values().any(|a| a == addr) {
-                eprintln!("Listening on {}", addr);
-                let fd = create_listening_socket(*addr)?;
-                let fd_raw = fd.0;
-                event_loop.poller().register_read(fd_raw)?;
-                listen_map.insert(fd_raw, *addr);
-                listen_fds.push(fd);
+            std::process::exit(1);
+        }
+    };
+    match opts.action {
+        Action::Test | Action::Dump => {
+            eprintln!("{}: configuration is valid", opts.config.display());
+            if opts.action == Action::Dump {
+                print!("{}", config::dump::dump(&cfg));
             }
+            return Ok(());
         }
+        Action::Run | Action::Help | Action::Signal(_) => {}
+    }
+    error::configure(cfg.error_log.clone());
+
+    let mut listeners: HashMap<i32, (SocketAddr, Fd)> = HashMap::new();
+    let mut inherited = inherit::take_inherited()?;
+    update_listeners(&cfg, None, &mut listeners, &mut inherited.listeners)?;
+    for (addr, _) in inherited.listeners.drain(..) {
+        error::log(Level::Notice, &format!("Closing inherited listener on {addr}: no server uses it"));
+    }
+    let cfg = Arc::new(cfg);
+
+    signal::install(&[libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2])?;
+    cli::write_pid_file(&cfg.pid)?;
+    if let Some(old) = inherited.upgrade_from {
+        // Serving now: the old process can drain
+        error::log(Level::Notice, &format!("Took over the listeners of process {old}"));
+        unsafe { libc::kill(old, libc::SIGTERM) };
     }
 
+    if cfg.worker_processes > 1 {
+        return run_master(&opts.config, &start_dir, cfg, listeners);
+    }
+    let table = WorkerTable::new(1)?;
+    metrics::init(table)?;
+    let status = table.slot(table.claim().ok_or("the worker table is full")?);
+    status.pid.store(std::process::id() as libc::pid_t, Ordering::Relaxed);
+    if !serve(cfg, &mut listeners, Role::Single { config: &opts.config, start_dir: &start_dir }, Some(status))? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Threads running handlers for the event loops when `worker_threads` is set.
+const BLOCKING_THREADS: usize = 4;
+
+/// What an event loop runs as: the whole server, one of a master's workers,
+/// which leaves reloads, upgrades and the pid file to the master, or an
+/// extra event loop thread, which gets its connections and instructions
+/// from the first one.
+#[derive(Clone, Copy)]
+enum Role<'a> {
+    Single { config: &'a Path, start_dir: &'a Path },
+    Worker { master: libc::pid_t },
+    Thread,
+}
+
+/// What event loop threads send each other through their mailboxes.
+enum Message {
+    /// A connection accepted by the first thread
+    Accepted { fd: Fd, local: SocketAddr, remote: SocketAddr },
+    /// A request handled on the blocking pool, for connection `id` on `fd`
+    Dispatched { fd: i32, id: u64, result: Dispatch, vars: Box<Variables> },
+    Reload(Arc<Config>),
+    /// Reopen the access log files after SIGUSR1
+    Reopen,
+    /// Drain, or on a second one, stop waiting
+    Stop,
+}
+
+/// An event loop thread's links to the others when `worker_threads` is set.
+struct Threads {
+    mailbox: Mailbox<Message>,
+    /// The mailbox of every event loop thread, this one's at `index`
+    peers: Vec<MailboxSender<Message>>,
+    index: usize,
+    pool: Arc<BlockingPool>,
+    /// The other event loop threads, which the first one waits for
+    handles: Vec<JoinHandle<Result<bool, String>>>,
+}
+
+/// Serves on `listeners` until the server is stopped, from one event loop or,
+/// with `worker_threads`, from several threads: the first accepts and hands
+/// connections out in turn, and every request is handled on the blocking
+/// pool. Open connections and requests are counted in `status`. Returns
+/// whether every connection finished before `shutdown_timeout`.
+fn serve(
+    cfg: Arc<Config>,
+    listeners: &mut HashMap<i32, (SocketAddr, Fd)>,
+    role: Role,
+    status: Option<&'static WorkerSlot>,
+) -> Result<bool, String> {
+    // Writes the access logs of every event loop thread, in order
+    let log_writer = Arc::new(signal::blocked(|| BlockingPool::new(1))?);
+    let Some(count) = cfg.worker_threads else {
+        return run_loop(cfg, listeners, role, status, None, AccessLogWriter::new(log_writer));
+    };
+    let mut peers = Vec::new();
+    let mut mailboxes = Vec::new();
+    for _ in 0..count {
+        let (sender, mailbox) = mailbox::mailbox()?;
+        peers.push(sender);
+        mailboxes.push(mailbox);
+    }
+    let mut mailboxes = mailboxes.into_iter();
+    let own = mailboxes.next().ok_or("worker_threads must be at least 1")?;
+
+    // Signals are left to this thread, which checks for them
+    let (pool, handles) = signal::blocked(|| -> Result<_, String> {
+        let pool = Arc::new(BlockingPool::new(BLOCKING_THREADS)?);
+        let mut handles = Vec::new();
+        for (i, mailbox) in mailboxes.enumerate() {
+            let index = i + 1;
+            let threads = Threads { mailbox, peers: peers.clone(), index, pool: Arc::clone(&pool), handles: Vec::new() };
+            let cfg = Arc::clone(&cfg);
+            let access_log = AccessLogWriter::new(Arc::clone(&log_writer));
+            let handle = thread::Builder::new()
+                .name(format!("event-{index}"))
+                .spawn(move || run_loop(cfg, &mut HashMap::new(), Role::Thread, status, Some(threads), access_log))
+                .map_err(|e| format!("cannot start an event loop thread: {e}"))?;
+            handles.push(handle);
+        }
+        Ok((pool, handles))
+    })?;
+    let threads = Threads { mailbox: own, peers, index: 0, pool, handles };
+    run_loop(cfg, listeners, role, status, Some(threads), AccessLogWriter::new(log_writer))
+}
+
+fn run_loop(
+    mut cfg: Arc<Config>,
+    listeners: &mut HashMap<i32, (SocketAddr, Fd)>,
+    role: Role,
+    status: Option<&'static WorkerSlot>,
+    mut threads: Option<Threads>,
+    mut access_log: AccessLogWriter,
+) -> Result<bool, String> {
+    let event_loop = EventLoop::new()?;
+    let mut mgr = ServerManager::new();
+    for &fd in listeners.keys() {
+        event_loop.poller().register_read(fd)?;
+    }
+    if let Some(threads) = &threads {
+        event_loop.poller().register_read(threads.mailbox.fd())?;
+    }
+    let single = matches!(role, Role::Single { .. });
+    // Set on SIGTERM/SIGINT: listeners are closed and connections drain until then
+    let mut shutdown_deadline: Option<Instant> = None;
+    let mut clean = true;
+    // Counted since, and open connections as of, the last update of `status`
+    let mut requests: u64 = 0;
+    let mut reported_connections: u64 = 0;
+    let mut reported_states = [0u64; STATES];
+    // Round-robin position when handing out connections
+    let mut next_thread = 0;
+    // Connections are published for the status page once a second
+    let mut last_published = Instant::now();
+
     loop {
-        event_loop.tick(64, Some(1000), |ev| {
+        let draining = shutdown_deadline.is_some();
+        event_loop.tick(64, Some(if draining { 100 } else { 1000 }), |ev| {
             // Accept new connections on any listener
-            if let Some(&local_addr) = listen_map.get(&ev.fd) {
+            if let Some(&(local_addr, _)) = listeners.get(&ev.fd) {
                 if ev.readable {
                     loop {
                         match accept_nonblocking(ev.fd) {
-                            Ok(Some(fd)) => {
+                            Ok(Some((fd, remote_addr))) => {
+                                if let Some(threads) = &threads {
+                                    let target = next_thread % threads.peers.len();
+                                    next_thread += 1;
+                                    if target != threads.index {
+                                        threads.peers[target].send(Message::Accepted { fd, local: local_addr, remote: remote_addr });
+                                        continue;
+                                    }
+                                }
                                 let fd_raw = fd.as_raw_fd();
-                                let srv = cfg.find_server(local_addr, None);
-                                let timeout = Duration::from_secs(srv.keep_alive_timeout.unwrap_or(75));
-                                mgr.insert(fd_raw, Connection::new(fd, local_addr, timeout));
-                                let _ = event_loop.poller().register_read(fd_raw);
+                                let timeout = keep_alive_timeout(&cfg, local_addr);
+                                mgr.insert(fd_raw, Connection::new(fd, local_addr, remote_addr, timeout, Arc::clone(&cfg)));
+                                check(event_loop.poller().register_read(fd_raw), Level::Error, "register_read", || mgr.conns[&fd_raw].context());
                             }
                             Ok(None) => break,
                             Err(e) => {
-                                eprintln!("accept error: {e}");
+                                metrics::add(Counter::AcceptErrors, 1);
+                                error::log(Level::Error, &format!("accept on {local_addr} failed: {e}"));
                                 break;
                             }
                         }
@@ -98,8 +286,7 @@ fn main() -> Result<(), String> {
 
                         // If state is already Closing, handle it immediately
                         if matches!(conn.state, ConnState::Closing) {
-                            let _ = event_loop.poller().deregister(conn_fd);
-                            unsafe { libc::close(conn_fd) };
+                            check(event_loop.poller().deregister(conn_fd), Level::Debug, "deregister", || conn.context());
                             should_close = true;
                         } else {
                             match &mut conn.state {
@@ -110,6 +297,8 @@ fn main() -> Result<(), String> {
                                         let n = unsafe { libc::read(conn_fd, buf.as_mut_ptr() as *mut _, buf.len()) };
                                         if n > 0 {
                                             let n = n as usize;
+                                            metrics::add(Counter::BytesReceived, n as u64);
+                                            conn.read_started.get_or_insert_with(Instant::now);
                                             conn.read_buf.extend_from_slice(&buf[..n]);
                                             
                                             // We don't know the exact limit yet until we parse the Host header,
@@ -119,134 +308,56 @@ fn main() -> Result<(), String> {
                                                 ParseResult::Error(err) => {
                                                     let status = if err == "body too large" { StatusCode::PayloadTooLarge } else { StatusCode::BadRequest };
                                                     // Use default server for this port for error response
-                                                    let srv = cfg.find_server(conn.local_addr, None);
-                                                    let root = srv.root.as_deref().unwrap_or(Path::new("www"));
-                                                    let resp = error_response(status, srv, root);
-                                                    let mut bytes = serialize_response(&resp, false, conn.timeout);
-                                                    conn.write_buf.append(&mut bytes);
-                                                    conn.state = ConnState::Writing;
-                                                    let _ = event_loop.poller().register_write(conn_fd);
+                                                    let srv = conn.config.find_server(conn.local_addr, None);
+                                                    let resp = error_response(status, &srv.effective);
+                                                    let started = conn.read_started.take().unwrap_or_else(Instant::now);
+                                                    conn.log = Some(Entry::new(srv, &srv.effective, conn.remote_addr, None, started));
+                                                    conn.keep_alive = false;
+                                                    send_response(conn, conn_fd, resp, &event_loop);
                                                     break;
                                                 }
-                                                ParseResult::Complete(req, used) => {
+                                                ParseResult::Complete(mut req, used) => {
                                                     conn.read_buf.drain(0..used);
-                                                    conn.keep_alive = req.keep_alive;
-                                                    
-                                                    // Host header may come in any casing; try common variants
-                                                    let host_header = req.headers
-                                                        .get("Host")
-                                                        .or_else(|| req.headers.get("host"))
-                                                        .map(|s| s.as_str());
-                                                    let srv = cfg.find_server(conn.local_addr, host_header);
-                                                    let loc = srv.find_location(&req.path);
-                                                    
-                                                    // 1. Check body limit
-                                                    let limit = loc.and_then(|l| l.body_limit).or(srv.client_max_body_size).unwrap_or(20 * 1024 * 1024);
-                                                    if req.body.len() as u64 > limit {
-                                                        let root = loc.and_then(|l| l.root.as_deref()).or(srv.root.as_deref()).unwrap_or(Path::new("www"));
-                                                        let resp = error_response(StatusCode::PayloadTooLarge, srv, root);
-                                                        let mut bytes = serialize_response(&resp, conn.keep_alive, conn.timeout);
-                                                        conn.write_buf.append(&mut bytes);
-                                                        conn.state = ConnState::Writing;
-                                                        let _ = event_loop.poller().register_write(conn_fd);
-                                                        break;
+                                                    let started = conn.read_started.take().unwrap_or_else(Instant::now);
+                                                    if !conn.read_buf.is_empty() {
+                                                        // The next request is already arriving
+                                                        conn.read_started = Some(Instant::now());
                                                     }
+                                                    requests += 1;
+                                                    conn.keep_alive = req.keep_alive && !draining;
 
-                                                    // 2. Check methods
-                                                    if let Some(l) = loc {
-                                                        if let Some(allowed) = &l.methods {
-                                                            if !allowed.contains(&req.method.into()) {
-                                                                let root = l.root.as_deref().or(srv.root.as_deref()).unwrap_or(Path::new("www"));
-                                                                let resp = error_response(StatusCode::MethodNotAllowed, srv, root);
-                                                                let mut bytes = serialize_response(&resp, conn.keep_alive, conn.timeout);
-                                                                conn.write_buf.append(&mut bytes);
-                                                                conn.state = ConnState::Writing;
-                                                                let _ = event_loop.poller().register_write(conn_fd);
-                                                                break;
-                                                            }
+                                                    // Requests that start after a reload use the new config; a
+                                                    // connection whose address was removed answers with
+                                                    // `Connection: close` and is not reused
+                                                    if !Arc::ptr_eq(&conn.config, &cfg) {
+                                                        if cfg.servers.iter().any(|s| s.listen.contains(&conn.local_addr)) {
+                                                            conn.config = Arc::clone(&cfg);
+                                                            conn.timeout = keep_alive_timeout(&cfg, conn.local_addr);
+                                                        } else {
+                                                            conn.keep_alive = false;
                                                         }
                                                     }
+                                                    let config = Arc::clone(&conn.config);
 
-                                                    // 3. Handle redirect
-                                                    if let Some(l) = loc {
-                                                        if let Some(redir) = &l.redirect {
-                                                            let mut resp = Response::new(StatusCode::MovedPermanently);
-                                                            resp.headers.insert("Location".into(), redir.clone());
-                                                            let mut bytes = serialize_response(&resp, conn.keep_alive, conn.timeout);
-                                                            conn.write_buf.append(&mut bytes);
-                                                            conn.state = ConnState::Writing;
-                                                            let _ = event_loop.poller().register_write(conn_fd);
-                                                            break;
-                                                        }
-                                                    }
+                                                    // Host header may come in any casing; try common variants
+                                                    let host_header = req.headers
+                                                        .get("Host")
+                                                        .or_else(|| req.headers.get("host"))
+                                                        .cloned();
 
-                                                    let loc_root = loc.and_then(|l| l.root.as_deref());
-                                                    let root = loc_root.or(srv.root.as_deref()).unwrap_or(Path::new("www"));
-                                                    let path_no_q = req.path.split('?').next().unwrap_or("");
-                                                    
-                                                    // 4. Handle CGI
-                                                    if let Some(cgi_config) = loc.and_then(|l| l.cgi.as_ref()) {
-                                                        match start_cgi(srv, root, &req, cgi_config) {
-                                                            Ok(cgi_proc) => {
-                                                                let _ = event_loop.poller().register_read(cgi_proc.output);
-                                                                mgr.pipe_map.insert(cgi_proc.output, conn_fd);
-                                                                if let Some(input) = cgi_proc.input {
-                                                                    let _ = event_loop.poller().register_write(input);
-                                                                    mgr.pipe_map.insert(input, conn_fd);
-                                                                }
-                                                                conn.state = ConnState::Cgi {
-                                                                    pid: cgi_proc.pid,
-                                                                    input: cgi_proc.input,
-                                                                    output: cgi_proc.output,
-                                                                    data: Vec::new(),
-                                                                };
-                                                                break;
-                                                            },
-                                                            Err(resp) => {
-                                                                let mut bytes = serialize_response(&resp, conn.keep_alive, conn.timeout);
-                                                                conn.write_buf.append(&mut bytes);
-                                                                conn.state = ConnState::Writing;
-                                                                let _ = event_loop.poller().register_write(conn_fd);
-                                                                break;
-                                                            }
-                                                        }
-                                                    } else {
-                                                        // 5. Handle Static / Upload
-                                                        let resp = if path_no_q == "/upload" {
-                                                            handle_upload(srv, root, &req)  
-                                                        } else if req.method == http::method::Method::Delete {
-                                                            let location_prefix = loc.map(|l| l.path.as_str()).unwrap_or("");
-                                                            application::handler::delete::handle_delete(srv, root, &req, location_prefix)
-                                                        } else {
-                                                            let mut indices = srv.index.clone();
-                                                            if let Some(l) = loc {
-                                                                if let Some(df) = &l.default_file {
-                                                                    indices.insert(0, df.clone());
-                                                                }
-                                                            }
-                                                            if indices.is_empty() {
-                                                                indices.push("index.html".into());
-                                                            }
-                                                            let autoindex = loc.and_then(|l| l.autoindex).unwrap_or(false);
-                                                            let location_prefix = loc.map(|l| l.path.as_str()).unwrap_or("");
-                                                            // Strip prefix only if location root differs from server root
-                                                            let strip_prefix = match (loc_root, srv.root.as_deref()) {
-                                                                (Some(lr), Some(sr)) => lr != sr,
-                                                                (Some(_), None) => true,
-                                                                _ => false,
-                                                            };
-                                                            if req.method == http::method::Method::Get {
-                                                                serve_static(srv, root, &req.path, location_prefix, strip_prefix, &indices, autoindex)
-                                                            } else {
-                                                                error_response(StatusCode::MethodNotAllowed, srv, root)
-                                                            }
-                                                        };
-                                                        let mut bytes = serialize_response(&resp, conn.keep_alive, conn.timeout);
-                                                        conn.write_buf.append(&mut bytes);
-                                                        conn.state = ConnState::Writing;
-                                                        let _ = event_loop.poller().register_write(conn_fd);
-                                                        break;
-                                                    }
+                                                    let srv = config.find_server(conn.local_addr, host_header.as_deref());
+                                                    let loc = srv.find_location(&req.path);
+                                                    let loc = loc.as_deref().unwrap_or(&srv.effective);
+                                                    let entry = Entry::new(srv, loc, conn.remote_addr, Some(&req), started);
+                                                    // Handlers and CGI see the ID the logs use
+                                                    req.headers.retain(|name, _| !name.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+                                                    req.headers.insert(REQUEST_ID_HEADER.into(), entry.request_id.clone());
+                                                    conn.log = Some(entry);
+
+                                                    let remote = conn.remote_addr;
+                                                    let handle = move |srv: &Server| dispatch(srv, &req, remote);
+                                                    handle_request(conn, conn_fd, host_header, handle, threads.as_ref(), &mut mgr.pipe_map, &event_loop);
+                                                    break;
                                                 }
                                             }
                                         } else if n == 0 {
@@ -264,10 +375,11 @@ fn main() -> Result<(), String> {
                                     }
                                 }
                             },
-                            ConnState::Cgi { pid, input, output, data } => {
+                            ConnState::Cgi { pid, input, output, errors, data, subrequest, .. } => {
                                 let pid_cp = *pid;
                                 let output_cp = *output;
                                 let input_cp = *input;
+                                let errors_cp = *errors;
 
                                 if ev.fd == output_cp && ev.readable {
                                     let mut buf = [0u8; 4096];
@@ -278,32 +390,65 @@ fn main() -> Result<(), String> {
                                         } else if n == 0 {
                                             // EOF
                                             let resp = parse_cgi_response(data);
+                                            let wrote_nothing = data.is_empty();
+                                            let subrequest = subrequest.take();
+                                            if let Some(entry) = &mut conn.log {
+                                                entry.upstream_finished();
+                                            }
                                             
                                             // Cleanup
                                             unsafe { libc::close(output_cp); }
-                                            let _ = event_loop.poller().deregister(output_cp);
+                                            check(event_loop.poller().deregister(output_cp), Level::Debug, "deregister CGI output", || conn.context());
                                             mgr.pipe_map.remove(&output_cp);
                                             if let Some(in_fd) = input_cp {
                                                 unsafe { libc::close(in_fd); }
-                                                let _ = event_loop.poller().deregister(in_fd);
+                                                check(event_loop.poller().deregister(in_fd), Level::Debug, "deregister CGI input", || conn.context());
                                                 mgr.pipe_map.remove(&in_fd);
                                             }
-                                            unsafe { libc::waitpid(pid_cp, std::ptr::null_mut(), libc::WNOHANG); }
+                                            if let Some(err_fd) = errors_cp {
+                                                log_cgi_stderr(conn, err_fd, true);
+                                                unsafe { libc::close(err_fd); }
+                                                check(event_loop.poller().deregister(err_fd), Level::Debug, "deregister CGI stderr", || conn.context());
+                                                mgr.pipe_map.remove(&err_fd);
+                                            }
+                                            // An exit status is only known if it has exited already;
+                                            // otherwise it is reaped later
+                                            let mut status = 0;
+                                            let exited = unsafe { libc::waitpid(pid_cp, &mut status, libc::WNOHANG) } == pid_cp;
+                                            if !exited {
+                                                mgr.exiting.push(pid_cp);
+                                            }
+                                            if wrote_nothing || (exited && !(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0)) {
+                                                metrics::add(Counter::CgiFailures, 1);
+                                            }
 
-                                            let mut bytes = serialize_response(&resp, conn.keep_alive, conn.timeout);
-                                            conn.write_buf.append(&mut bytes);
-                                            conn.state = ConnState::Writing;
-                                            let _ = event_loop.poller().register_write(conn_fd);
+                                            match subrequest {
+                                                Some(pending) => {
+                                                    let host = pending.request().header("Host").map(str::to_string);
+                                                    let handle = move |srv: &Server| resume(srv, *pending, Some(resp));
+                                                    handle_request(conn, conn_fd, host, handle, threads.as_ref(), &mut mgr.pipe_map, &event_loop);
+                                                }
+                                                None => send_response(conn, conn_fd, resp, &event_loop),
+                                            }
                                             break;
                                         } else {
                                             break;
                                         }
                                     }
+                                } else if errors_cp == Some(ev.fd) {
+                                    if log_cgi_stderr(conn, ev.fd, false) {
+                                        unsafe { libc::close(ev.fd); }
+                                        check(event_loop.poller().deregister(ev.fd), Level::Debug, "deregister CGI stderr", || conn.context());
+                                        mgr.pipe_map.remove(&ev.fd);
+                                        if let ConnState::Cgi { errors, .. } = &mut conn.state {
+                                            *errors = None;
+                                        }
+                                    }
                                 } else if let Some(in_fd) = input_cp {
                                     if ev.fd == in_fd && ev.writable {
                                         // Assume request body handling needed here, but for now closing
                                         unsafe { libc::close(in_fd); }
-                                        let _ = event_loop.poller().deregister(in_fd);
+                                        check(event_loop.poller().deregister(in_fd), Level::Debug, "deregister CGI input", || conn.context());
                                         mgr.pipe_map.remove(&in_fd);
                                         if let ConnState::Cgi { input, .. } = &mut conn.state {
                                             *input = None;
@@ -322,9 +467,14 @@ fn main() -> Result<(), String> {
                                     };
                                     if n > 0 {
                                         let n = n as usize;
+                                        metrics::add(Counter::BytesSent, n as u64);
                                         conn.write_buf.drain(0..n);
                                         if conn.write_buf.is_empty() {
-                                            let _ = event_loop.poller().disable_write(conn_fd);
+                                            check(event_loop.poller().disable_write(conn_fd), Level::Error, "disable_write", || conn.context());
+                                            if let Some(entry) = conn.log.take() {
+                                                metrics::request(&entry);
+                                                access_log.log(&entry);
+                                            }
                                             if conn.keep_alive {
                                                 conn.state = ConnState::Reading;
                                             } else {
@@ -340,12 +490,11 @@ fn main() -> Result<(), String> {
                                     }
                                 }
                             },
+                            // Resumed when the pool's result arrives
+                            ConnState::Dispatching => {}
                             ConnState::Closing => {
-                                 let _ = event_loop.poller().deregister(conn_fd);
-                                 // Explicitly close the socket to free the port immediately
-                                 // The Fd's Drop will also try to close it, but closing an already-closed fd is safe
-                                 unsafe { libc::close(conn_fd) };
-                                 should_close = true;
+                                check(event_loop.poller().deregister(conn_fd), Level::Debug, "deregister", || conn.context());
+                                should_close = true;
                             }
                         }
                         } // end of else/match
@@ -359,10 +508,745 @@ fn main() -> Result<(), String> {
         })?;
 
         for fd in mgr.sweep_timeouts() {
-            let _ = event_loop.poller().deregister(fd);
-            // Explicitly close the socket before removing from manager
-            unsafe { libc::close(fd) };
-            mgr.remove(fd);
+            metrics::add(Counter::Timeouts, 1);
+            close_connection(&mut mgr, &event_loop, fd);
+        }
+        for fd in mgr.late_subrequests() {
+            abandon_subrequest(&mut mgr, &event_loop, threads.as_ref(), fd);
+        }
+        mgr.reap();
+        access_log.tick();
+        if last_published.elapsed() >= Duration::from_secs(1) {
+            metrics::publish_connections(mgr.conns.values().map(Connection::info).collect());
+            last_published = Instant::now();
+        }
+
+        // Added as differences: event loop threads share the counters
+        if let Some(status) = status {
+            let open = mgr.conns.len() as u64;
+            status.connections.fetch_add(open.wrapping_sub(reported_connections), Ordering::Relaxed);
+            status.requests.fetch_add(std::mem::take(&mut requests), Ordering::Relaxed);
+            reported_connections = open;
+            let mut states = [0u64; STATES];
+            for conn in mgr.conns.values() {
+                states[conn.state.index()] += 1;
+            }
+            for ((counter, open), reported) in status.states.iter().zip(states).zip(&mut reported_states) {
+                counter.fetch_add(open.wrapping_sub(*reported), Ordering::Relaxed);
+                *reported = open;
+            }
+        }
+
+        let mut stop = false;
+        let messages = threads.as_ref().map(|t| t.mailbox.drain()).unwrap_or_default();
+        for message in messages {
+            match message {
+                Message::Accepted { fd, local, remote } => {
+                    let fd_raw = fd.as_raw_fd();
+                    let timeout = keep_alive_timeout(&cfg, local);
+                    mgr.insert(fd_raw, Connection::new(fd, local, remote, timeout, Arc::clone(&cfg)));
+                    check(event_loop.poller().register_read(fd_raw), Level::Error, "register_read", || mgr.conns[&fd_raw].context());
+                }
+                Message::Dispatched { fd, id, result, vars } => match mgr.conns.get_mut(&fd) {
+                    Some(conn) if conn.id == id && matches!(conn.state, ConnState::Dispatching) => {
+                        start_response(conn, fd, result, *vars, &mut mgr.pipe_map, &event_loop);
+                    }
+                    // The connection was closed while the pool handled it
+                    _ => abandon(result),
+                },
+                Message::Reload(new_cfg) => cfg = new_cfg,
+                Message::Reopen => access_log.reopen(),
+                Message::Stop => stop = true,
+            }
+        }
+
+        // The terminal sends SIGINT to the whole process group: workers leave
+        // it to the master, which forwards SIGTERM, so they drain only once.
+        // `|` rather than `||` so that both flags are cleared
+        if !matches!(role, Role::Thread) {
+            stop |= signal::take(libc::SIGTERM) | (signal::take(libc::SIGINT) && single);
+        }
+        if let Role::Worker { master, .. } = role
+            && shutdown_deadline.is_none()
+            && unsafe { libc::getppid() } != master
+        {
+            error::log(Level::Warn, &format!("Master process {master} exited, shutting down"));
+            stop = true;
+        }
+        if stop {
+            if let Some(threads) = &threads
+                && threads.index == 0
+            {
+                for peer in &threads.peers[1..] {
+                    peer.send(Message::Stop);
+                }
+            }
+            if shutdown_deadline.is_none() {
+                for (fd, _) in listeners.drain() {
+                    check(event_loop.poller().deregister(fd), Level::Debug, "deregister listener", Context::default);
+                }
+                shutdown_deadline = Some(Instant::now() + cfg.shutdown_timeout);
+                if single {
+                    let open = status.map_or(mgr.conns.len() as u64, |s| s.connections.load(Ordering::Relaxed));
+                    error::log(Level::Notice, &format!("Shutting down, waiting up to {:?} for {open} connections", cfg.shutdown_timeout));
+                }
+            } else {
+                // A second signal stops waiting
+                shutdown_deadline = Some(Instant::now());
+            }
         }
+        if let Some(deadline) = shutdown_deadline {
+            for fd in mgr.idle() {
+                close_connection(&mut mgr, &event_loop, fd);
+            }
+            let expired = Instant::now() >= deadline;
+            if expired && !mgr.conns.is_empty() {
+                let remaining: Vec<i32> = mgr.conns.keys().copied().collect();
+                for &fd in &remaining {
+                    close_connection(&mut mgr, &event_loop, fd);
+                }
+                error::log(Level::Warn, &format!("Shutdown timeout expired, closed {} connections in progress", remaining.len()));
+                clean = false;
+            }
+            let children_running = reap_children();
+            // The other event loop threads drain on their own deadlines
+            let others_running = threads.as_ref().is_some_and(|t| t.handles.iter().any(|h| !h.is_finished()));
+            if mgr.conns.is_empty() && (!children_running || expired) && !others_running {
+                for handle in threads.iter_mut().flat_map(|t| t.handles.drain(..)) {
+                    match handle.join() {
+                        Ok(Ok(drained)) => clean &= drained,
+                        Ok(Err(e)) => {
+                            error::log(Level::Error, &e);
+                            clean = false;
+                        }
+                        Err(_) => clean = false,
+                    }
+                }
+                access_log.close();
+                if single {
+                    cli::remove_pid_file(&cfg.pid);
+                    if clean {
+                        error::log(Level::Notice, "Shutdown complete");
+                    }
+                }
+                return Ok(clean);
+            }
+            // Reload, reopen and upgrade wait until the next start
+            continue;
+        }
+        // A master forwards SIGUSR1 to its workers after reopening its own
+        // error log
+        if !matches!(role, Role::Thread) && signal::take(libc::SIGUSR1) {
+            error::reopen();
+            access_log.reopen();
+            if let Some(threads) = &threads {
+                for peer in &threads.peers[1..] {
+                    peer.send(Message::Reopen);
+                }
+            }
+            error::log(Level::Notice, "Reopened the log files");
+        }
+        let Role::Single { config, start_dir } = role else {
+            // Reloads and upgrades are the master's
+            continue;
+        };
+        if signal::take(libc::SIGHUP) {
+            match reload(config, &cfg, Some(&event_loop), listeners) {
+                Ok(new_cfg) => {
+                    cfg = Arc::new(new_cfg);
+                    if let Some(threads) = &threads {
+                        for peer in &threads.peers[1..] {
+                            peer.send(Message::Reload(Arc::clone(&cfg)));
+                        }
+                    }
+                    metrics::add(Counter::Reloads, 1);
+                    error::log(Level::Notice, "Configuration reloaded");
+                }
+                Err(e) => {
+                    metrics::add(Counter::ReloadFailures, 1);
+                    error::log(Level::Error, &format!("{e}\nReload failed, keeping the current configuration"));
+                }
+            }
+        }
+        if signal::take(libc::SIGUSR2) {
+            match upgrade(start_dir, listeners) {
+                Ok(program) => error::log(Level::Notice, &format!("Started {}, waiting for it to take over", program.display())),
+                Err(e) => error::log(Level::Error, &format!("{e}\nUpgrade failed, keeping the current process")),
+            }
+        }
+    }
+}
+
+/// Handles a request with `handle`, given the server it is for: on the
+/// blocking pool if there is one, or right away.
+fn handle_request(
+    conn: &mut Connection,
+    conn_fd: i32,
+    host: Option<String>,
+    handle: impl FnOnce(&Server) -> (Dispatch, Variables) + Send + 'static,
+    threads: Option<&Threads>,
+    pipe_map: &mut HashMap<i32, i32>,
+    event_loop: &EventLoop,
+) {
+    let config = Arc::clone(&conn.config);
+    let ctx = conn.context();
+    match threads {
+        Some(threads) => {
+            // Handlers read and write files: keep them off this thread
+            let reply = threads.peers[threads.index].clone();
+            let (local, id) = (conn.local_addr, conn.id);
+            threads.pool.run(move || {
+                let srv = config.find_server(local, host.as_deref());
+                let (result, vars) = error::scoped(ctx, || handle(srv));
+                reply.send(Message::Dispatched { fd: conn_fd, id, result, vars: Box::new(vars) });
+            });
+            conn.state = ConnState::Dispatching;
+        }
+        None => {
+            let srv = config.find_server(conn.local_addr, host.as_deref());
+            let (result, vars) = error::scoped(ctx, || handle(srv));
+            start_response(conn, conn_fd, result, vars, pipe_map, event_loop);
+        }
+    }
+}
+
+/// Sends a request's result to the client, or starts reading from its CGI
+/// process, or from that of its `auth_request` subrequest. `vars` are the
+/// request's variables as routing left them, for the access log.
+fn start_response(conn: &mut Connection, conn_fd: i32, result: Dispatch, vars: Variables, pipe_map: &mut HashMap<i32, i32>, event_loop: &EventLoop) {
+    if let Some(entry) = &mut conn.log {
+        entry.routed(vars);
+    }
+    let (cgi_proc, subrequest) = match result {
+        Dispatch::Response(resp) => return send_response(conn, conn_fd, resp, event_loop),
+        Dispatch::Cgi(cgi_proc) => (cgi_proc, None),
+        Dispatch::AuthRequest(cgi_proc, pending) => (cgi_proc, Some(pending)),
+    };
+    check(event_loop.poller().register_read(cgi_proc.output), Level::Error, "register_read CGI output", || conn.context());
+    pipe_map.insert(cgi_proc.output, conn_fd);
+    check(event_loop.poller().register_read(cgi_proc.errors), Level::Error, "register_read CGI stderr", || conn.context());
+    pipe_map.insert(cgi_proc.errors, conn_fd);
+    if let Some(input) = cgi_proc.input {
+        check(event_loop.poller().register_write(input), Level::Error, "register_write CGI input", || conn.context());
+        pipe_map.insert(input, conn_fd);
+    }
+    // `$upstream_response_time` is the request's own CGI
+    if subrequest.is_none()
+        && let Some(entry) = &mut conn.log
+    {
+        entry.upstream_started();
+    }
+    conn.state = ConnState::Cgi {
+        pid: cgi_proc.pid,
+        input: cgi_proc.input,
+        output: cgi_proc.output,
+        errors: Some(cgi_proc.errors),
+        data: Vec::new(),
+        stderr: Vec::new(),
+        subrequest,
+    };
+}
+
+/// Queues a response for writing, tagged with the request's ID, and records
+/// it for the access log.
+fn send_response(conn: &mut Connection, conn_fd: i32, mut resp: Response, event_loop: &EventLoop) {
+    if let Some(entry) = &conn.log {
+        resp.headers.insert(REQUEST_ID_HEADER.into(), entry.request_id.clone());
+    }
+    let mut bytes = serialize_response(&resp, conn.keep_alive, conn.timeout);
+    if let Some(entry) = &mut conn.log {
+        entry.responded(resp.status.as_u16(), resp.body.len(), bytes.len());
+    }
+    conn.write_buf.append(&mut bytes);
+    conn.state = ConnState::Writing;
+    check(event_loop.poller().register_write(conn_fd), Level::Error, "register_write", || conn.context());
+}
+
+/// Longest stderr line kept waiting for its newline
+const CGI_STDERR_LINE: usize = 4096;
+
+/// Reads what a CGI process wrote to its stderr and logs it a line at a time
+/// under the request's ID. At the end of its output, or once the line gets
+/// too long, a partial line is logged too. Returns whether stderr is closed.
+fn log_cgi_stderr(conn: &mut Connection, fd: i32, finished: bool) -> bool {
+    let ConnState::Cgi { stderr, .. } = &mut conn.state else {
+        return true;
+    };
+    let mut buf = [0u8; 4096];
+    let closed = loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n > 0 {
+            stderr.extend_from_slice(&buf[..n as usize]);
+        } else {
+            let err = io::Error::last_os_error();
+            break n == 0 || (err.raw_os_error() != Some(libc::EAGAIN) && err.raw_os_error() != Some(libc::EWOULDBLOCK));
+        }
+    };
+    let mut lines = std::mem::take(stderr);
+    let complete = lines.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    if !finished && !closed && lines.len() - complete < CGI_STDERR_LINE {
+        *stderr = lines.split_off(complete);
+    }
+    if !lines.is_empty() {
+        let ctx = conn.context();
+        for line in String::from_utf8_lossy(&lines).lines().filter(|l| !l.trim().is_empty()) {
+            error::log_with(Level::Error, &format!("CGI stderr: {line}"), &ctx);
+        }
+    }
+    closed
+}
+
+/// Drops a result nobody waits for anymore, killing its CGI process.
+fn abandon(result: Dispatch) {
+    if let Dispatch::Cgi(cgi_proc) | Dispatch::AuthRequest(cgi_proc, _) = result {
+        unsafe {
+            libc::kill(cgi_proc.pid, libc::SIGKILL);
+            libc::waitpid(cgi_proc.pid, std::ptr::null_mut(), 0);
+            for fd in cgi_proc.input.into_iter().chain([cgi_proc.output, cgi_proc.errors]) {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+/// Loads and validates the config again and moves the listeners over to it.
+/// On error nothing changes.
+fn reload(path: &Path, current: &Config, event_loop: Option<&EventLoop>, listeners: &mut HashMap<i32, (SocketAddr, Fd)>) -> Result<Config, String> {
+    let cfg = load_config(path)?;
+    update_listeners(&cfg, event_loop, listeners, &mut Vec::new())?;
+    if cfg.pid != current.pid {
+        cli::write_pid_file(&cfg.pid)?;
+        cli::remove_pid_file(&current.pid);
+    }
+    error::configure(cfg.error_log.clone());
+    Ok(cfg)
+}
+
+/// Opens a listener for every address in `cfg` that has none and closes the
+/// ones no server uses anymore, registering them with `event_loop` if one is
+/// given. Addresses found in `inherited` are taken from it instead of being
+/// opened. If an address cannot be opened, the existing listeners are left
+/// untouched.
+fn update_listeners(
+    cfg: &Config,
+    event_loop: Option<&EventLoop>,
+    listeners: &mut HashMap<i32, (SocketAddr, Fd)>,
+    inherited: &mut Vec<(SocketAddr, Fd)>,
+) -> Result<(), String> {
+    let wanted: Vec<SocketAddr> = cfg.servers.iter().flat_map(|s| s.listen.iter().copied()).collect();
+    let mut opened: Vec<(SocketAddr, Fd)> = Vec::new();
+    for &addr in &wanted {
+        if listeners.values().any(|(a, _)| *a == addr) || opened.iter().any(|(a, _)| *a == addr) {
+            continue;
+        }
+        if let Some(pos) = inherited.iter().position(|(a, _)| *a == addr) {
+            opened.push(inherited.swap_remove(pos));
+            continue;
+        }
+        // Dropping `opened` on error closes what was opened so far
+        let fd = create_listening_socket(addr).map_err(|e| format!("cannot listen on {addr}: {e}"))?;
+        opened.push((addr, fd));
+    }
+
+    let removed: Vec<i32> = listeners
+        .iter()
+        .filter(|(_, (addr, _))| !wanted.contains(addr))
+        .map(|(&fd, _)| fd)
+        .collect();
+    for fd in removed {
+        if let Some(event_loop) = event_loop {
+            check(event_loop.poller().deregister(fd), Level::Debug, "deregister listener", Context::default);
+        }
+        if let Some((addr, _)) = listeners.remove(&fd) {
+            error::log(Level::Notice, &format!("Stopped listening on {addr}"));
+        }
+    }
+    for (addr, fd) in opened {
+        if let Some(event_loop) = event_loop {
+            event_loop.poller().register_read(fd.0)?;
+        }
+        error::log(Level::Notice, &format!("Listening on {addr}"));
+        listeners.insert(fd.0, (addr, fd));
+    }
+    Ok(())
+}
+
+/// A worker forked by the master.
+struct Worker {
+    pid: libc::pid_t,
+    /// Its slot in the worker table, if one was free
+    slot: Option<usize>,
+    started: Instant,
+    /// Set when it was told to drain after a reload
+    retiring: bool,
+}
+
+/// Runs the master process: keeps `worker_processes` workers running on the
+/// shared listeners, replaces any that die and turns signals into actions
+/// on them. A reload starts new workers with the new config and drains the
+/// old ones.
+fn run_master(config: &Path, start_dir: &Path, mut cfg: Arc<Config>, mut listeners: HashMap<i32, (SocketAddr, Fd)>) -> Result<(), String> {
+    // Wakes the wait below when a worker exits
+    signal::install(&[libc::SIGCHLD])?;
+    // Room for one generation of workers draining while the next one runs
+    let table = WorkerTable::new(4 * MAX_WORKER_PROCESSES)?;
+    metrics::init(table)?;
+    let mut workers: Vec<Worker> = Vec::new();
+    // Crashing workers are restarted at most once a second
+    let mut respawn_after = Instant::now();
+    let mut stopping = false;
+    let mut failed = false;
+    error::log(Level::Notice, &format!("Master process {} starting {} workers", std::process::id(), cfg.worker_processes));
+
+    loop {
+        if !stopping && Instant::now() >= respawn_after {
+            spawn_workers(&cfg, &mut listeners, table, &mut workers);
+        }
+        // Signals interrupt the wait
+        unsafe { libc::poll(std::ptr::null_mut(), 0, 1000) };
+
+        loop {
+            let mut status = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+            if pid <= 0 {
+                break;
+            }
+            let Some(pos) = workers.iter().position(|w| w.pid == pid) else {
+                continue;
+            };
+            let worker = workers.swap_remove(pos);
+            if let Some(slot) = worker.slot {
+                table.release(slot);
+            }
+            let clean = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+            if stopping || worker.retiring {
+                // A drain that hit shutdown_timeout exits with 1
+                failed |= stopping && !clean;
+            } else {
+                error::log(Level::Error, &format!("Worker {pid} {}, starting a new one", exit_reason(status)));
+                if worker.started.elapsed() < Duration::from_secs(1) {
+                    respawn_after = Instant::now() + Duration::from_secs(1);
+                }
+            }
+        }
+        signal::take(libc::SIGCHLD);
+
+        if signal::take(libc::SIGTERM) | signal::take(libc::SIGINT) {
+            if !stopping {
+                stopping = true;
+                // Workers keep their own copies until they have drained
+                listeners.clear();
+                let (_, connections, _) = table.totals();
+                error::log(Level::Notice, &format!("Shutting down {} workers with {connections} connections", workers.len()));
+            }
+            // A second signal is forwarded too, and stops their wait
+            for worker in &workers {
+                unsafe { libc::kill(worker.pid, libc::SIGTERM) };
+            }
+        }
+        if stopping {
+            if workers.is_empty() {
+                cli::remove_pid_file(&cfg.pid);
+                if failed {
+                    std::process::exit(1);
+                }
+                error::log(Level::Notice, "Shutdown complete");
+                return Ok(());
+            }
+            continue;
+        }
+        if signal::take(libc::SIGHUP) {
+            match reload(config, &cfg, None, &mut listeners) {
+                Ok(new_cfg) => {
+                    cfg = Arc::new(new_cfg);
+                    for worker in &mut workers {
+                        worker.retiring = true;
+                    }
+                    let old: Vec<libc::pid_t> = workers.iter().map(|w| w.pid).collect();
+                    spawn_workers(&cfg, &mut listeners, table, &mut workers);
+                    for pid in old {
+                        unsafe { libc::kill(pid, libc::SIGTERM) };
+                    }
+                    metrics::add(Counter::Reloads, 1);
+                    error::log(Level::Notice, "Configuration reloaded, replacing the workers");
+                }
+                Err(e) => {
+                    metrics::add(Counter::ReloadFailures, 1);
+                    error::log(Level::Error, &format!("{e}\nReload failed, keeping the current configuration"));
+                }
+            }
+        }
+        if signal::take(libc::SIGUSR1) {
+            error::reopen();
+            for worker in &workers {
+                unsafe { libc::kill(worker.pid, libc::SIGUSR1) };
+            }
+            error::log(Level::Notice, "Reopened the log files, signalling the workers");
+        }
+        if signal::take(libc::SIGUSR2) {
+            match upgrade(start_dir, &listeners) {
+                Ok(program) => error::log(Level::Notice, &format!("Started {}, waiting for it to take over", program.display())),
+                Err(e) => error::log(Level::Error, &format!("{e}\nUpgrade failed, keeping the current process")),
+            }
+        }
+    }
+}
+
+/// Forks workers until the current generation has `worker_processes`.
+fn spawn_workers(cfg: &Arc<Config>, listeners: &mut HashMap<i32, (SocketAddr, Fd)>, table: WorkerTable, workers: &mut Vec<Worker>) {
+    let running = workers.iter().filter(|w| !w.retiring).count();
+    for _ in running..cfg.worker_processes {
+        let slot = table.claim();
+        let master = std::process::id() as libc::pid_t;
+        match unsafe { libc::fork() } {
+            -1 => {
+                error::log(Level::Crit, &format!("cannot start a worker: fork: {}", io::Error::last_os_error()));
+                if let Some(slot) = slot {
+                    table.release(slot);
+                }
+                return;
+            }
+            0 => {
+                let status = slot.map(|s| table.slot(s));
+                if let Some(status) = status {
+                    status.pid.store(unsafe { libc::getpid() }, Ordering::Relaxed);
+                }
+                let code = match serve(Arc::clone(cfg), listeners, Role::Worker { master }, status) {
+                    Ok(true) => 0,
+                    Ok(false) => 1,
+                    Err(e) => {
+                        error::log(Level::Error, &e);
+                        1
+                    }
+                };
+                std::process::exit(code);
+            }
+            pid => {
+                if let Some(slot) = slot {
+                    table.slot(slot).pid.store(pid, Ordering::Relaxed);
+                }
+                workers.push(Worker { pid, slot, started: Instant::now(), retiring: false });
+            }
+        }
+    }
+}
+
+fn exit_reason(status: libc::c_int) -> String {
+    if libc::WIFSIGNALED(status) {
+        format!("was killed by signal {}", libc::WTERMSIG(status))
+    } else {
+        format!("exited with status {}", libc::WEXITSTATUS(status))
+    }
+}
+
+/// Starts the binary again with the same arguments, handing it the listeners.
+/// An intermediate process starts it so that it is not a child of this one,
+/// whose drain waits for its children. The new process sends SIGTERM once it
+/// serves; if it fails before that, this one keeps running.
+fn upgrade(start_dir: &Path, listeners: &HashMap<i32, (SocketAddr, Fd)>) -> Result<PathBuf, String> {
+    let mut args = std::env::args_os();
+    let program = PathBuf::from(args.next().ok_or("cannot find the path of the running binary")?);
+    // A bare name is looked up in PATH, anything else is relative to where we started
+    let program = if program.components().count() > 1 { start_dir.join(program) } else { program };
+    let fds: Vec<i32> = listeners.keys().copied().collect();
+    let list: Vec<String> = fds.iter().map(|fd| fd.to_string()).collect();
+
+    let mut cmd = Command::new(&program);
+    cmd.args(args)
+        .current_dir(start_dir)
+        .env(LISTENERS_ENV, list.join(","))
+        .env(UPGRADE_FROM_ENV, std::process::id().to_string());
+    unsafe {
+        cmd.pre_exec(move || {
+            for &fd in &fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => Err(format!("fork: {}", io::Error::last_os_error())),
+        0 => {
+            let code = match cmd.spawn() {
+                Ok(_) => 0,
+                Err(e) => {
+                    eprintln!("cannot start {}: {e}", program.display());
+                    1
+                }
+            };
+            unsafe { libc::_exit(code) }
+        }
+        pid => {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+                Ok(program)
+            } else {
+                // The intermediate process has logged why
+                Err(format!("{} did not start", program.display()))
+            }
+        }
+    }
+}
+
+/// Closes a connection. One waiting on CGI also has its child killed and
+/// its pipes closed.
+fn close_connection(mgr: &mut ServerManager, event_loop: &EventLoop, fd: i32) {
+    let cgi = match mgr.conns.get(&fd).map(|c| &c.state) {
+        Some(ConnState::Cgi { pid, input, output, errors, .. }) => Some((*pid, *input, *output, *errors)),
+        _ => None,
+    };
+    if let Some((pid, input, output, errors)) = cgi {
+        let ctx = context(mgr, fd);
+        kill_cgi(pid, input.into_iter().chain([output]).chain(errors), &mut mgr.pipe_map, event_loop, &ctx);
+    }
+    check(event_loop.poller().deregister(fd), Level::Debug, "deregister", || context(mgr, fd));
+    // Dropping the connection closes the socket
+    mgr.remove(fd);
+}
+
+/// Kills the CGI process of an `auth_request` subrequest that took too long
+/// and goes on with its request, which then fails with a 500.
+fn abandon_subrequest(mgr: &mut ServerManager, event_loop: &EventLoop, threads: Option<&Threads>, fd: i32) {
+    let Some(conn) = mgr.conns.get_mut(&fd) else {
+        return;
+    };
+    let ConnState::Cgi { pid, input, output, errors, subrequest, .. } = &mut conn.state else {
+        return;
+    };
+    let pending = subrequest.take();
+    let pipes: Vec<i32> = input.iter().chain([&*output]).chain(errors.iter()).copied().collect();
+    kill_cgi(*pid, pipes, &mut mgr.pipe_map, event_loop, &conn.context());
+    metrics::add(Counter::CgiFailures, 1);
+    if let Some(pending) = pending {
+        let host = pending.request().header("Host").map(str::to_string);
+        let handle = move |srv: &Server| resume(srv, *pending, None);
+        handle_request(conn, fd, host, handle, threads, &mut mgr.pipe_map, event_loop);
+    }
+}
+
+/// Kills a CGI process, waiting for it so that it does not linger as a
+/// zombie, and closes its pipes.
+fn kill_cgi(pid: i32, pipes: impl IntoIterator<Item = i32>, pipe_map: &mut HashMap<i32, i32>, event_loop: &EventLoop, ctx: &Context) {
+    unsafe {
+        libc::kill(pid, libc::SIGKILL);
+        libc::waitpid(pid, std::ptr::null_mut(), 0);
+    }
+    for pipe in pipes {
+        check(event_loop.poller().deregister(pipe), Level::Debug, "deregister CGI pipe", || ctx.clone());
+        unsafe { libc::close(pipe) };
+        pipe_map.remove(&pipe);
+    }
+}
+
+fn context(mgr: &ServerManager, fd: i32) -> Context {
+    mgr.conns.get(&fd).map(Connection::context).unwrap_or_default()
+}
+
+/// Logs a failed change to what the event loop watches. A failed
+/// registration leaves the connection waiting until it times out; a failed
+/// removal is harmless once the fd is closed.
+fn check(result: Result<(), String>, level: Level, action: &str, ctx: impl FnOnce() -> Context) {
+    if let Err(e) = result {
+        error::log_with(level, &format!("{action} failed: {e}"), &ctx());
+    }
+}
+
+/// Collects exited children and returns whether any are still running.
+fn reap_children() -> bool {
+    loop {
+        match unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) } {
+            pid if pid > 0 => continue,
+            0 => return true,
+            // ECHILD: no children left
+            _ => return false,
+        }
+    }
+}
+
+fn keep_alive_timeout(cfg: &Config, addr: SocketAddr) -> Duration {
+    cfg.find_server(addr, None).keep_alive_timeout.unwrap_or(Duration::from_secs(75))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::parser::parse_config;
+
+    fn config(listen: &[&str]) -> Config {
+        let servers: String = listen.iter().map(|addr| format!("server {{ listen {addr}; }}\n")).collect();
+        parse_config(&servers, Path::new("."), "test.conf").unwrap()
+    }
+
+    #[test]
+    fn test_update_listeners() {
+        let mut listeners = HashMap::new();
+        update_listeners(&config(&["0.0.0.0:0"]), None, &mut listeners, &mut Vec::new()).unwrap();
+        assert_eq!(listeners.len(), 1);
+        let first = *listeners.keys().next().unwrap();
+
+        // An address still in use keeps its socket; a new one is opened
+        update_listeners(&config(&["0.0.0.0:0", "[::]:0"]), None, &mut listeners, &mut Vec::new()).unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners.contains_key(&first));
+
+        // An address that cannot be opened leaves every listener as it was
+        let err = update_listeners(&config(&["[::]:0", "192.0.2.1:9"]), None, &mut listeners, &mut Vec::new()).unwrap_err();
+        assert!(err.contains("cannot listen on 192.0.2.1:9"), "{err}");
+        assert_eq!(listeners.len(), 2);
+
+        // Unused ones are closed, and inherited sockets are adopted instead of opened
+        let inherited_fd = create_listening_socket("0.0.0.0:0".parse().unwrap()).unwrap();
+        let raw = inherited_fd.0;
+        let mut inherited = vec![("0.0.0.0:1".parse().unwrap(), inherited_fd)];
+        update_listeners(&config(&["[::]:0", "0.0.0.0:1"]), None, &mut listeners, &mut inherited).unwrap();
+        assert!(inherited.is_empty());
+        assert!(!listeners.contains_key(&first) && listeners.contains_key(&raw));
+        assert_eq!(listeners.len(), 2);
+    }
+
+    #[test]
+    fn test_close_connection_closes_once() {
+        // A high number, which no other test's descriptor takes meanwhile
+        const FD: i32 = 900;
+        let mut pair = [0; 2];
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, pair.as_mut_ptr()) }, 0);
+        assert_eq!(unsafe { libc::dup2(pair[0], FD) }, FD);
+        unsafe { libc::close(pair[0]) };
+        let event_loop = EventLoop::new().unwrap();
+        let mut mgr = ServerManager::new();
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        mgr.insert(FD, Connection::new(Fd(FD), addr, addr, Duration::from_secs(5), Arc::new(config(&["0.0.0.0:0"]))));
+        event_loop.poller().register_read(FD).unwrap();
+
+        close_connection(&mut mgr, &event_loop, FD);
+        assert_eq!(unsafe { libc::fcntl(FD, libc::F_GETFD) }, -1);
+        assert!(mgr.conns.is_empty());
+
+        // Once another descriptor reuses the number, nothing closes it again
+        assert_eq!(unsafe { libc::dup2(pair[1], FD) }, FD);
+        close_connection(&mut mgr, &event_loop, FD);
+        assert_ne!(unsafe { libc::fcntl(FD, libc::F_GETFD) }, -1);
+        unsafe {
+            libc::close(FD);
+            libc::close(pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_failed_reload_changes_nothing() {
+        let current = config(&["0.0.0.0:0"]);
+        let mut listeners = HashMap::new();
+        update_listeners(&current, None, &mut listeners, &mut Vec::new()).unwrap();
+        let before: Vec<i32> = listeners.keys().copied().collect();
+        assert!(reload(Path::new("/nonexistent/localhost.conf"), &current, None, &mut listeners).is_err());
+        assert_eq!(listeners.keys().copied().collect::<Vec<_>>(), before);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,102 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libc::c_int;
+
+pub const USAGE: &str = "\
+Usage: localhost [-c file] [-p prefix] [-t | -T | -s signal]
+
+Options:
+  -c file    use this config file (default: config.conf)
+  -p prefix  run in this directory; relative paths, including -c, resolve against it
+  -t         test the config and exit
+  -T         test the config and print the resolved configuration
+  -s signal  send a signal to the running server: stop, reload, reopen or upgrade
+  -h         show this help";
+
+pub struct Options {
+    pub config: PathBuf,
+    pub prefix: Option<PathBuf>,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Run,
+    /// `-t`
+    Test,
+    /// `-T`
+    Dump,
+    /// `-s name`, holding the signal number
+    Signal(c_int),
+    Help,
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut opts = Options { config: PathBuf::from("config.conf"), prefix: None, action: Action::Run };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => opts.config = PathBuf::from(value(&mut args, "-c")?),
+            "-p" => opts.prefix = Some(PathBuf::from(value(&mut args, "-p")?)),
+            "-t" => set_action(&mut opts, Action::Test)?,
+            "-T" => set_action(&mut opts, Action::Dump)?,
+            "-s" => {
+                let name = value(&mut args, "-s")?;
+                let sig = match name.as_str() {
+                    "stop" => libc::SIGTERM,
+                    "reload" => libc::SIGHUP,
+                    "reopen" => libc::SIGUSR1,
+                    "upgrade" => libc::SIGUSR2,
+                    _ => return Err(format!("unknown signal {name:?}, expected stop, reload, reopen or upgrade")),
+                };
+                set_action(&mut opts, Action::Signal(sig))?;
+            }
+            "-h" | "--help" => opts.action = Action::Help,
+            _ => return Err(format!("unknown option {arg:?}")),
+        }
+    }
+    Ok(opts)
+}
+
+fn set_action(opts: &mut Options, action: Action) -> Result<(), String> {
+    if opts.action != Action::Run && opts.action != action {
+        return Err("-t, -T and -s cannot be combined".into());
+    }
+    opts.action = action;
+    Ok(())
+}
+
+fn value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("option {option} requires a value"))
+}
+
+pub fn write_pid_file(path: &Path) -> Result<(), String> {
+    fs::write(path, format!("{}\n", std::process::id()))
+        .map_err(|e| format!("cannot write pid file {}: {e}", path.display()))
+}
+
+/// Removes the pid file unless another process has taken it over, as the
+/// new binary does on upgrade.
+pub fn remove_pid_file(path: &Path) {
+    let ours = fs::read_to_string(path).is_ok_and(|c| c.trim() == std::process::id().to_string());
+    if ours {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Sends `sig` to the process named in the pid file.
+pub fn signal_running(pid_file: &Path, sig: c_int) -> Result<(), String> {
+    let content = fs::read_to_string(pid_file)
+        .map_err(|e| format!("cannot read pid file {}: {e} (is the server running?)", pid_file.display()))?;
+    // Zero and negative pids would signal whole process groups
+    let pid: libc::pid_t = match content.trim().parse() {
+        Ok(pid) if pid > 0 => pid,
+        _ => return Err(format!("invalid pid {:?} in {}", content.trim(), pid_file.display())),
+    };
+    if unsafe { libc::kill(pid, sig) } < 0 {
+        let err = io::Error::last_os_error();
+        return Err(format!("cannot signal process {pid} from {}: {err}", pid_file.display()));
+    }
+    Ok(())
+}
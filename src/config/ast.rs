@@ -1,9 +1,44 @@
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use regex::{Captures, Regex};
+
+use crate::application::log::error::Level;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub servers: Vec<Server>,
+    pub http: HttpBlock,
+    /// File the running server writes its process id to, used by `-s`
+    pub pid: PathBuf,
+    /// How long a stopping server waits for requests in progress
+    pub shutdown_timeout: Duration,
+    /// Worker processes forked by a master; with 1 the server runs in a
+    /// single process
+    pub worker_processes: usize,
+    /// Event loop threads per process, with handlers run on a blocking
+    /// pool; without it a process serves from a single thread
+    pub worker_threads: Option<usize>,
+    /// Formats declared with `log_format`; the predefined ones are not listed
+    pub log_formats: Vec<LogFormat>,
+    /// Address lists declared with `geo`
+    pub geos: Vec<Geo>,
+    /// Where messages not tied to a server go
+    pub error_log: ErrorLog,
+}
+
+/// The top-level `http { ... }` block. Its directives are defaults for every
+/// server and its locations are added to every server that does not declare
+/// the same location itself.
+#[derive(Debug, Clone, Default)]
+pub struct HttpBlock {
+    pub keep_alive_timeout: Option<Duration>,
+    pub error_log: Option<ErrorLog>,
+    pub directives: Directives,
+    pub locations: Vec<Location>,
 }
 
 impl Config {
@@ -40,42 +75,451 @@ impl Config {
 pub struct Server {
     pub listen: Vec<SocketAddr>,
     pub server_names: Vec<String>,
-    pub root: Option<PathBuf>,
-    pub index: Vec<String>,
-    pub errors: Vec<ErrorPage>,
-    pub client_max_body_size: Option<u64>,
-    pub keep_alive_timeout: Option<u64>,
+    pub keep_alive_timeout: Option<Duration>,
+    /// Where messages about this server's requests go, the top-level
+    /// `error_log` if unset
+    pub error_log: Option<ErrorLog>,
+    pub directives: Directives,
     pub locations: Vec<Location>,
+    /// Settings for requests that match no location, filled in by `merge::resolve`
+    pub effective: EffectiveLocation,
 }
 
 impl Server {
-    pub fn find_location(&self, path: &str) -> Option<&Location> {
-        let mut best_match: Option<&Location> = None;
-        for loc in &self.locations {
-            if path.starts_with(&loc.path) {
-                if let Some(best) = best_match {
-                    if loc.path.len() > best.path.len() {
-                        best_match = Some(loc);
-                    }
-                } else {
-                    best_match = Some(loc);
-                }
+    /// Picks the location for a request path with nginx's precedence: an exact
+    /// `=` match wins outright, then the longest prefix if it is `^~`, then the
+    /// first matching regex in config order, and finally the longest prefix.
+    /// The search then continues among the nested locations of the winner.
+    /// Regex captures are substituted into the returned location's values.
+    pub fn find_location(&self, path: &str) -> Option<Cow<'_, EffectiveLocation>> {
+        self.find_location_captures(path).map(|(loc, _)| loc)
+    }
+
+    /// `find_location`, with the captures of the regex location that matched.
+    pub fn find_location_captures<'p>(&self, path: &'p str) -> Option<(Cow<'_, EffectiveLocation>, Option<Captures<'p>>)> {
+        let path = path.split('?').next().unwrap_or("");
+        let (mut loc, mut caps) = match_location(&self.locations, path)?;
+        while let Some((inner, inner_caps)) = match_location(&loc.locations, path) {
+            loc = inner;
+            if inner_caps.is_some() {
+                caps = inner_caps;
             }
         }
-        best_match
+        let effective = match &caps {
+            Some(caps) if caps.len() > 1 => Cow::Owned(loc.effective.with_captures(caps)),
+            _ => Cow::Borrowed(&loc.effective),
+        };
+        Some((effective, caps))
+    }
+
+    /// Looks up a `location @name` block, the target of internal redirects.
+    pub fn find_named_location(&self, name: &str) -> Option<&EffectiveLocation> {
+        self.locations
+            .iter()
+            .find(|l| l.is_named() && l.path == name)
+            .map(|l| &l.effective)
     }
 }
 
+fn match_location<'a, 'p>(locations: &'a [Location], path: &'p str) -> Option<(&'a Location, Option<Captures<'p>>)> {
+    let mut best_prefix: Option<&Location> = None;
+    for loc in locations {
+        if loc.is_named() {
+            continue;
+        }
+        match loc.modifier {
+            LocationModifier::Exact if loc.path == path => return Some((loc, None)),
+            LocationModifier::Prefix | LocationModifier::PreferPrefix
+                if loc.matches_prefix(path) && best_prefix.is_none_or(|best| loc.path.len() > best.path.len()) =>
+            {
+                best_prefix = Some(loc);
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(best) = best_prefix.filter(|b| b.modifier == LocationModifier::PreferPrefix) {
+        return Some((best, None));
+    }
+
+    for loc in locations {
+        if let Some(caps) = loc.regex.as_ref().and_then(|re| re.captures(path)) {
+            return Some((loc, Some(caps)));
+        }
+    }
+
+    best_prefix.map(|loc| (loc, None))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LocationModifier {
+    /// `location /prefix`
+    Prefix,
+    /// `location = /path`
+    Exact,
+    /// `location ^~ /prefix`: when it is the longest prefix, regexes are skipped
+    PreferPrefix,
+    /// `location ~ regex`
+    Regex,
+    /// `location ~* regex`
+    RegexCaseless,
+}
+
 #[derive(Debug, Clone)]
 pub struct Location {
     pub path: String,
+    pub modifier: LocationModifier,
+    /// Compiled pattern for `~` and `~*` locations
+    pub regex: Option<Regex>,
+    pub directives: Directives,
+    /// Locations nested inside this one
+    pub locations: Vec<Location>,
+    /// Filled in by `merge::resolve` once the whole config is parsed
+    pub effective: EffectiveLocation,
+}
+
+impl Location {
+    pub fn is_named(&self) -> bool {
+        self.path.starts_with('@')
+    }
+
+    pub fn is_regex(&self) -> bool {
+        self.regex.is_some()
+    }
+
+    /// Prefix match on segment boundaries: `/uploads` matches `/uploads` and
+    /// `/uploads/a` but not `/uploadsXYZ`.
+    fn matches_prefix(&self, path: &str) -> bool {
+        match path.strip_prefix(self.path.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.path.ends_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// Directives accepted in both server and location blocks, as written in the
+/// config. Unset values are inherited from the enclosing block at load time,
+/// except `redirect`, `try_files` and the rewrite rules, which only apply where
+/// they are written.
+#[derive(Debug, Clone, Default)]
+pub struct Directives {
     pub root: Option<PathBuf>,
+    pub index: Option<Vec<String>>,
+    pub errors: Vec<ErrorPage>,
+    pub client_max_body_size: Option<u64>,
     pub methods: Option<Vec<HttpMethod>>,
     pub redirect: Option<String>,
     pub autoindex: Option<bool>,
+    pub autoindex_format: Option<AutoindexFormat>,
+    pub autoindex_exact_size: Option<bool>,
+    pub autoindex_localtime: Option<bool>,
+    pub autoindex_hidden: Option<bool>,
+    pub autoindex_template: Option<PathBuf>,
     pub default_file: Option<String>,
+    pub try_files: Option<Vec<String>>,
+    pub cgi: Option<Cgi>,
+    /// `rewrite`, `return` and `set` directives in config order
+    pub rewrites: Vec<RewriteOp>,
+    /// Inherited as a whole, only when the block declares no `add_header`
+    pub add_headers: Option<Vec<AddHeader>>,
+    /// Inherited as a whole like `add_headers`; `access_log off` is empty
+    pub access_logs: Option<Vec<AccessLog>>,
+    /// `metrics;`, only in a location and not inherited by nested ones
+    pub metrics: bool,
+    /// `status [connections];`, like `metrics`
+    pub status: Option<StatusPage>,
+    /// Realm of `auth_basic`; `auth_basic off` is `Some(None)`
+    pub auth_basic: Option<Option<String>>,
+    pub auth_basic_user_file: Option<PathBuf>,
+    /// URI of `auth_request`; `auth_request off` is `Some(None)`
+    pub auth_request: Option<Option<String>>,
+    /// Inherited as a whole like `add_headers`
+    pub auth_request_set: Option<Vec<AuthRequestSet>>,
+    /// `allow` and `deny` rules in config order, inherited as a whole like
+    /// `add_headers`
+    pub access_rules: Option<Vec<AccessRule>>,
+}
+
+/// Fully resolved settings of a location (or of a server, for requests that
+/// match no location). This is what request handlers work with.
+#[derive(Debug, Clone, Default)]
+pub struct EffectiveLocation {
+    /// Pattern of the location block, empty for the server-level settings
+    pub location: String,
+    pub root: PathBuf,
+    /// Prefix removed from the request path before it is joined to `root`,
+    /// set when a prefix location declares a root other than the server's
+    pub strip_prefix: Option<String>,
+    /// Index files to try, `default_file` first
+    pub index: Vec<String>,
+    pub errors: Vec<ErrorPage>,
+    pub client_max_body_size: u64,
+    /// `None` allows every method
+    pub methods: Option<Vec<HttpMethod>>,
+    pub redirect: Option<String>,
+    /// `None` when autoindex is off
+    pub autoindex: Option<AutoindexOptions>,
+    pub try_files: Option<Vec<String>>,
     pub cgi: Option<Cgi>,
-    pub body_limit: Option<u64>,
+    /// Rewrite rules of this location; empty for the server-level settings,
+    /// whose rules run once before the location lookup
+    pub rewrites: Vec<RewriteOp>,
+    pub add_headers: Vec<AddHeader>,
+    /// Files every request served here is logged to, none when off
+    pub access_logs: Vec<AccessLog>,
+    /// Serves the server's metrics instead of files
+    pub metrics: bool,
+    /// Serves the status page instead of files
+    pub status: Option<StatusPage>,
+    /// `None` when anyone may access it
+    pub auth_basic: Option<AuthBasic>,
+    /// URI asked whether a request may go on
+    pub auth_request: Option<String>,
+    pub auth_request_set: Vec<AuthRequestSet>,
+    /// Checked in order against the client address; empty allows everyone
+    pub access_rules: Vec<AccessRule>,
+}
+
+impl EffectiveLocation {
+    fn with_captures(&self, caps: &Captures) -> EffectiveLocation {
+        let mut loc = self.clone();
+        loc.root = PathBuf::from(expand_captures(&loc.root.to_string_lossy(), caps));
+        loc.redirect = loc.redirect.map(|r| expand_captures(&r, caps));
+        if let Some(cgi) = &mut loc.cgi {
+            cgi.interpreter = PathBuf::from(expand_captures(&cgi.interpreter.to_string_lossy(), caps));
+        }
+        for op in &mut loc.rewrites {
+            match op {
+                RewriteOp::Return { target: Some(value), .. } | RewriteOp::Set { value, .. } => {
+                    *value = expand_captures(value, caps);
+                }
+                _ => {}
+            }
+        }
+        for header in &mut loc.add_headers {
+            header.value = expand_captures(&header.value, caps);
+        }
+        for set in &mut loc.auth_request_set {
+            set.value = expand_captures(&set.value, caps);
+        }
+        loc
+    }
+}
+
+/// Replaces `$0`..`$9` with the corresponding regex capture (empty if unmatched).
+fn expand_captures(template: &str, caps: &Captures) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$'
+            && let Some(d) = chars.peek().and_then(|d| d.to_digit(10))
+        {
+            chars.next();
+            out.push_str(caps.get(d as usize).map(|m| m.as_str()).unwrap_or(""));
+            continue;
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// A step of the rewrite phase. Rules run in the order they are written; the
+/// first `return`, or `rewrite` with a flag, ends the phase.
+#[derive(Debug, Clone)]
+pub enum RewriteOp {
+    /// `rewrite regex replacement [flag];`
+    Rewrite { regex: Regex, replacement: String, flag: Option<RewriteFlag> },
+    /// `return code [url|text];`, a redirect URL for 3xx codes, a body otherwise
+    Return { code: u16, target: Option<String> },
+    /// `set $name value;`, name stored without the `$`
+    Set { name: String, value: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewriteFlag {
+    /// Stop rewriting and search the location again with the new URI
+    Last,
+    /// Stop rewriting and serve the new URI from the current location
+    Break,
+    /// 302 to the new URI
+    Redirect,
+    /// 301 to the new URI
+    Permanent,
+}
+
+/// `add_header name value [always];`. The value may reference variables.
+#[derive(Debug, Clone)]
+pub struct AddHeader {
+    pub name: String,
+    pub value: String,
+    /// Also send the header with error responses
+    pub always: bool,
+}
+
+/// `auth_basic realm;` with its `auth_basic_user_file`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthBasic {
+    pub realm: String,
+    /// htpasswd file; `None` without `auth_basic_user_file`, which
+    /// validation reports
+    pub user_file: Option<PathBuf>,
+}
+
+/// `auth_request_set $name value;`: sets a variable from the response to
+/// the `auth_request` subrequest, once it allowed the request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthRequestSet {
+    pub name: String,
+    pub value: String,
+}
+
+/// `allow source;` or `deny source;`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessRule {
+    pub allow: bool,
+    pub source: AccessSource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessSource {
+    All,
+    Network(Cidr),
+    /// A `geo` list, its networks shared with every rule naming it
+    Geo { name: String, networks: Arc<[Cidr]> },
+}
+
+impl AccessRule {
+    pub fn matches(&self, addr: IpAddr) -> bool {
+        match &self.source {
+            AccessSource::All => true,
+            AccessSource::Network(net) => net.contains(addr),
+            AccessSource::Geo { networks, .. } => networks.iter().any(|net| net.contains(addr)),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network; a single address has the full prefix length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    /// Network address, with the bits past `prefix` cleared
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// Parses `address` or `address/prefix`. Host bits are cleared, so
+    /// `10.1.2.3/8` is `10.0.0.0/8`.
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) if !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()) => p.parse().ok()?,
+            Some(_) => return None,
+            None => max,
+        };
+        if prefix > max {
+            return None;
+        }
+        let addr = match addr {
+            IpAddr::V4(a) => IpAddr::V4((u32::from(a) & mask32(prefix)).into()),
+            IpAddr::V6(a) => IpAddr::V6((u128::from(a) & mask128(prefix)).into()),
+        };
+        Some(Cidr { addr, prefix })
+    }
+
+    /// IPv4 clients reaching an IPv6 socket (`::ffff:a.b.c.d`) match IPv4
+    /// networks.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => u32::from(a) & mask32(self.prefix) == u32::from(net),
+            (IpAddr::V6(net), IpAddr::V6(a)) => u128::from(a) & mask128(self.prefix) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn mask32(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask128(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+/// `geo name file;`: a named list of networks for `allow` and `deny`, read
+/// from the file when the config is loaded.
+#[derive(Debug, Clone)]
+pub struct Geo {
+    pub name: String,
+    pub path: PathBuf,
+    pub networks: Arc<[Cidr]>,
+}
+
+/// `status [connections];`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusPage {
+    /// Also lists the open connections
+    pub connections: bool,
+}
+
+/// `error_log path|stderr [level];`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorLog {
+    /// `None` for stderr
+    pub path: Option<PathBuf>,
+    /// Least severe level written
+    pub level: Level,
+}
+
+/// `log_format name 'template';`
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    pub name: String,
+    /// The format strings joined, with `$name` references expanded per request
+    pub template: String,
+}
+
+/// `access_log path [format];`, the format resolved when the config is parsed.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    pub path: PathBuf,
+    pub format: String,
+    pub template: LogTemplate,
+}
+
+#[derive(Debug, Clone)]
+pub enum LogTemplate {
+    /// A `log_format` string
+    Text(String),
+    /// The predefined `json` format: one object per request with fixed fields
+    Json,
+}
+
+/// Listing options resolved from the `autoindex_*` directives.
+#[derive(Debug, Clone)]
+pub struct AutoindexOptions {
+    pub format: AutoindexFormat,
+    pub exact_size: bool,
+    pub localtime: bool,
+    pub show_hidden: bool,
+    pub template: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoindexFormat {
+    Html,
+    Json,
+    Xml,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -101,8 +545,73 @@ pub struct ErrorPage {
     pub path: String,
 }
 
+impl ErrorPage {
+    /// The file served for this page: an absolute path if it exists, otherwise
+    /// the path relative to `root`.
+    pub fn file(&self, root: &Path) -> PathBuf {
+        let path = Path::new(&self.path);
+        if path.is_absolute() && path.exists() {
+            path.to_path_buf()
+        } else {
+            root.join(self.path.trim_start_matches('/'))
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Cgi {
     pub extension: String,
     pub interpreter: PathBuf,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        Cidr::parse(s).unwrap_or_else(|| panic!("{s}"))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("10.1.2.3").to_string(), "10.1.2.3/32");
+        assert_eq!(cidr("2001:db8::1").to_string(), "2001:db8::1/128");
+        assert_eq!(cidr("2001:db8:ffff::1/33").to_string(), "2001:db8:8000::/33");
+        assert_eq!(cidr("255.255.255.255/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("ffff::/0").to_string(), "::/0");
+        for bad in ["", "10.0.0", "10.0.0.0/", "10.0.0.0/33", "::/129", "10.0.0.0/+8", "10.0.0.0/8/8", "office"] {
+            assert_eq!(Cidr::parse(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let all4 = cidr("0.0.0.0/0");
+        assert!(all4.contains(ip("0.0.0.0")) && all4.contains(ip("255.255.255.255")));
+        assert!(!all4.contains(ip("::1")));
+        let all6 = cidr("::/0");
+        assert!(all6.contains(ip("::1")) && all6.contains(ip("2001:db8::1")));
+        // Mapped addresses are the IPv4 client behind them
+        assert!(!all6.contains(ip("::ffff:10.0.0.1")));
+        assert!(!all6.contains(ip("10.0.0.1")));
+
+        let host = cidr("10.1.2.3/32");
+        assert!(host.contains(ip("10.1.2.3")) && !host.contains(ip("10.1.2.4")));
+        let host6 = cidr("2001:db8::1/128");
+        assert!(host6.contains(ip("2001:db8::1")) && !host6.contains(ip("2001:db8::2")));
+
+        let net = cidr("192.168.1.0/24");
+        assert!(net.contains(ip("192.168.1.255")) && !net.contains(ip("192.168.2.0")));
+        assert!(net.contains(ip("::ffff:192.168.1.7")));
+        // Only `::ffff:` addresses are mapped, not the deprecated compatible ones
+        assert!(!net.contains(ip("::192.168.1.7")));
+        // An IPv4 client does not match the IPv6 network of its mapped form
+        assert!(!cidr("::ffff:0:0/96").contains(ip("192.168.1.7")));
+        assert!(!cidr("2001:db8::/32").contains(ip("32.1.13.184")));
+    }
+}
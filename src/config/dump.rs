@@ -0,0 +1,210 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use super::ast::*;
+
+/// Renders the resolved configuration in config syntax, one block per server
+/// with every inherited value written out, for `-T`.
+pub fn dump(cfg: &Config) -> String {
+    let mut out = String::new();
+    line(&mut out, 0, &format!("pid {};", quote(&cfg.pid.to_string_lossy())));
+    line(&mut out, 0, &format!("shutdown_timeout {};", duration(cfg.shutdown_timeout)));
+    line(&mut out, 0, &format!("worker_processes {};", cfg.worker_processes));
+    line(&mut out, 0, &error_log(&cfg.error_log));
+    if let Some(threads) = cfg.worker_threads {
+        line(&mut out, 0, &format!("worker_threads {threads};"));
+    }
+    for format in &cfg.log_formats {
+        line(&mut out, 0, &format!("log_format {} {};", quote(&format.name), quote(&format.template)));
+    }
+    for geo in &cfg.geos {
+        line(&mut out, 0, &format!("geo {} {};", quote(&geo.name), quote(&geo.path.to_string_lossy())));
+    }
+    for srv in &cfg.servers {
+        out.push('\n');
+        line(&mut out, 0, "server {");
+        for addr in &srv.listen {
+            line(&mut out, 1, &format!("listen {addr};"));
+        }
+        if !srv.server_names.is_empty() {
+            let names: Vec<String> = srv.server_names.iter().map(|n| quote(n)).collect();
+            line(&mut out, 1, &format!("server_name {};", names.join(" ")));
+        }
+        if let Some(timeout) = srv.keep_alive_timeout {
+            line(&mut out, 1, &format!("keep_alive_timeout {};", duration(timeout)));
+        }
+        if let Some(log) = &srv.error_log {
+            line(&mut out, 1, &error_log(log));
+        }
+        settings(&mut out, 1, &srv.effective, &srv.directives.rewrites);
+        for loc in &srv.locations {
+            location(&mut out, 1, loc);
+        }
+        line(&mut out, 0, "}");
+    }
+    out
+}
+
+fn location(out: &mut String, depth: usize, loc: &Location) {
+    let modifier = match loc.modifier {
+        LocationModifier::Prefix => "",
+        LocationModifier::Exact => "= ",
+        LocationModifier::PreferPrefix => "^~ ",
+        LocationModifier::Regex => "~ ",
+        LocationModifier::RegexCaseless => "~* ",
+    };
+    out.push('\n');
+    line(out, depth, &format!("location {modifier}{} {{", quote(&loc.path)));
+    settings(out, depth + 1, &loc.effective, &loc.effective.rewrites);
+    if loc.effective.metrics {
+        line(out, depth + 1, "metrics;");
+    }
+    if let Some(status) = loc.effective.status {
+        line(out, depth + 1, if status.connections { "status connections;" } else { "status;" });
+    }
+    for child in &loc.locations {
+        location(out, depth + 1, child);
+    }
+    line(out, depth, "}");
+}
+
+fn settings(out: &mut String, depth: usize, e: &EffectiveLocation, rewrites: &[RewriteOp]) {
+    line(out, depth, &format!("root {};", quote(&e.root.to_string_lossy())));
+    let index: Vec<String> = e.index.iter().map(|i| quote(i)).collect();
+    line(out, depth, &format!("index {};", index.join(" ")));
+    for page in &e.errors {
+        line(out, depth, &format!("error_page {} {};", page.code, quote(&page.path)));
+    }
+    line(out, depth, &format!("client_max_body_size {};", size(e.client_max_body_size)));
+    if let Some(methods) = &e.methods {
+        let names: Vec<&str> = methods
+            .iter()
+            .map(|m| match m {
+                HttpMethod::Get => "GET",
+                HttpMethod::Post => "POST",
+                HttpMethod::Delete => "DELETE",
+            })
+            .collect();
+        line(out, depth, &format!("methods {};", names.join(" ")));
+    }
+    if let Some(redirect) = &e.redirect {
+        line(out, depth, &format!("redirect {};", quote(redirect)));
+    }
+    match &e.autoindex {
+        Some(opts) => {
+            let format = match opts.format {
+                AutoindexFormat::Html => "html",
+                AutoindexFormat::Json => "json",
+                AutoindexFormat::Xml => "xml",
+            };
+            line(out, depth, "autoindex on;");
+            line(out, depth, &format!("autoindex_format {format};"));
+            line(out, depth, &format!("autoindex_exact_size {};", on_off(opts.exact_size)));
+            line(out, depth, &format!("autoindex_localtime {};", on_off(opts.localtime)));
+            line(out, depth, &format!("autoindex_hidden {};", on_off(opts.show_hidden)));
+            if let Some(template) = &opts.template {
+                line(out, depth, &format!("autoindex_template {};", quote(&template.to_string_lossy())));
+            }
+        }
+        None => line(out, depth, "autoindex off;"),
+    }
+    if let Some(files) = &e.try_files {
+        let files: Vec<String> = files.iter().map(|f| quote(f)).collect();
+        line(out, depth, &format!("try_files {};", files.join(" ")));
+    }
+    if let Some(cgi) = &e.cgi {
+        line(out, depth, &format!("cgi {} {};", quote(&cgi.extension), quote(&cgi.interpreter.to_string_lossy())));
+    }
+    for op in rewrites {
+        let text = match op {
+            RewriteOp::Rewrite { regex, replacement, flag } => {
+                let flag = match flag {
+                    Some(RewriteFlag::Last) => " last",
+                    Some(RewriteFlag::Break) => " break",
+                    Some(RewriteFlag::Redirect) => " redirect",
+                    Some(RewriteFlag::Permanent) => " permanent",
+                    None => "",
+                };
+                format!("rewrite {} {}{flag};", quote(regex.as_str()), quote(replacement))
+            }
+            RewriteOp::Return { code, target: Some(target) } => format!("return {code} {};", quote(target)),
+            RewriteOp::Return { code, target: None } => format!("return {code};"),
+            RewriteOp::Set { name, value } => format!("set ${name} {};", quote(value)),
+        };
+        line(out, depth, &text);
+    }
+    for header in &e.add_headers {
+        let always = if header.always { " always" } else { "" };
+        line(out, depth, &format!("add_header {} {}{always};", quote(&header.name), quote(&header.value)));
+    }
+    if e.access_logs.is_empty() {
+        line(out, depth, "access_log off;");
+    }
+    for log in &e.access_logs {
+        line(out, depth, &format!("access_log {} {};", quote(&log.path.to_string_lossy()), quote(&log.format)));
+    }
+    match &e.auth_basic {
+        Some(auth) => {
+            line(out, depth, &format!("auth_basic {};", quote(&auth.realm)));
+            if let Some(file) = &auth.user_file {
+                line(out, depth, &format!("auth_basic_user_file {};", quote(&file.to_string_lossy())));
+            }
+        }
+        None => line(out, depth, "auth_basic off;"),
+    }
+    match &e.auth_request {
+        Some(uri) => line(out, depth, &format!("auth_request {};", quote(uri))),
+        None => line(out, depth, "auth_request off;"),
+    }
+    for set in &e.auth_request_set {
+        line(out, depth, &format!("auth_request_set ${} {};", set.name, quote(&set.value)));
+    }
+    for rule in &e.access_rules {
+        let directive = if rule.allow { "allow" } else { "deny" };
+        let source = match &rule.source {
+            AccessSource::All => "all".to_string(),
+            AccessSource::Network(net) => net.to_string(),
+            AccessSource::Geo { name, .. } => quote(name),
+        };
+        line(out, depth, &format!("{directive} {source};"));
+    }
+}
+
+fn error_log(log: &ErrorLog) -> String {
+    let path = log.path.as_ref().map_or_else(|| "stderr".to_string(), |p| quote(&p.to_string_lossy()));
+    format!("error_log {path} {};", log.level.as_str())
+}
+
+fn line(out: &mut String, depth: usize, text: &str) {
+    let _ = writeln!(out, "{}{text}", "    ".repeat(depth));
+}
+
+/// Quotes a value that would not read back as a single word, with single
+/// quotes if it contains double ones.
+fn quote(value: &str) -> String {
+    if value.contains('"') {
+        format!("'{value}'")
+    } else if value.is_empty() || value.contains(|c: char| c.is_ascii_whitespace() || matches!(c, ';' | '{' | '}' | '#' | '\'')) {
+        format!("\"{value}\"")
+    } else {
+        value.to_string()
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value { "on" } else { "off" }
+}
+
+fn size(bytes: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1 << 30, "g"), (1 << 20, "m"), (1 << 10, "k")];
+    for (unit, suffix) in UNITS {
+        if bytes >= unit && bytes.is_multiple_of(unit) {
+            return format!("{}{suffix}", bytes / unit);
+        }
+    }
+    bytes.to_string()
+}
+
+fn duration(d: Duration) -> String {
+    if d.subsec_millis() == 0 { format!("{}s", d.as_secs()) } else { format!("{}ms", d.as_millis()) }
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Expands an `include` pattern relative to `base_dir` into the matching
+/// files, sorted by path. `*` and `?` work in any path component and never
+/// match a leading `.`. A pattern without wildcards must name an existing
+/// file; a wildcard pattern may match nothing.
+pub fn expand_glob(pattern: &str, base_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let full = base_dir.join(pattern);
+    if !has_wildcard(pattern) {
+        if !full.is_file() {
+            return Err(format!("include: {} not found", full.display()));
+        }
+        return Ok(vec![full]);
+    }
+
+    let mut candidates = vec![PathBuf::new()];
+    for comp in full.components() {
+        let part = comp.as_os_str().to_string_lossy();
+        if !matches!(comp, Component::Normal(_)) || !has_wildcard(&part) {
+            for c in &mut candidates {
+                c.push(comp);
+            }
+            continue;
+        }
+        let mut next = Vec::new();
+        for dir in &candidates {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir.as_path() };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !name.starts_with('.') && wildcard_match(&part, &name) {
+                    next.push(dir.join(name));
+                }
+            }
+        }
+        candidates = next;
+    }
+
+    let mut files: Vec<PathBuf> = candidates.into_iter().filter(|p| p.is_file()).collect();
+    files.sort();
+    Ok(files)
+}
+
+fn has_wildcard(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Matches `name` against a pattern where `*` is any run of characters and
+/// `?` a single character.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    // Position after the last `*` and the name index it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        match p.get(pi) {
+            Some('*') => {
+                backtrack = Some((pi + 1, ni));
+                pi += 1;
+            }
+            Some(&c) if c == '?' || c == n[ni] => {
+                pi += 1;
+                ni += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    pi = bp;
+                    ni = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
@@ -1,11 +1,24 @@
 use std::fs;
 use std::path::Path;
 
-use super::parser;
+use super::{parser, validate};
 use super::Config;
 
+/// Reads, parses and validates a config file.
 pub fn load_config(path: &Path) -> Result<Config, String> {
-    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let cfg = read_config(path)?;
+    let problems = validate::validate(&cfg);
+    if !problems.is_empty() {
+        let rendered: Vec<String> = problems.iter().map(|p| format!("{}: {p}", path.display())).collect();
+        return Err(rendered.join("\n"));
+    }
+    Ok(cfg)
+}
+
+/// Reads and parses a config file without checking it against the
+/// filesystem, enough to find the pid file of a running server.
+pub fn read_config(path: &Path) -> Result<Config, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
     let base_dir = path.parent().unwrap_or_else(|| Path::new(".")).to_path_buf();
-    parser::parse_config(&content, &base_dir)
-}
\ No newline at end of file
+    parser::parse_config(&content, &base_dir, &path.display().to_string())
+}
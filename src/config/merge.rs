@@ -0,0 +1,162 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::ast::*;
+use crate::application::log::error::Level;
+
+/// Body size limit when no `client_max_body_size` is configured anywhere.
+pub const DEFAULT_BODY_LIMIT: u64 = 20 * 1024 * 1024;
+
+/// Pid file when no `pid` directive is configured.
+pub const DEFAULT_PID_FILE: &str = "localhost.pid";
+
+/// Drain time on SIGTERM/SIGINT when no `shutdown_timeout` is configured.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Error log when no `error_log` is configured: stderr, from `notice` up.
+pub const DEFAULT_ERROR_LOG: ErrorLog = ErrorLog { path: None, level: Level::Notice };
+
+/// Upper bound for `worker_processes`.
+pub const MAX_WORKER_PROCESSES: usize = 256;
+
+/// Upper bound for `worker_threads`.
+pub const MAX_WORKER_THREADS: usize = 256;
+
+/// Formats every config can use in `access_log` without declaring them.
+pub const PREDEFINED_LOG_FORMATS: &[(&str, &str)] = &[
+    (
+        "combined",
+        "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent \"$http_referer\" \"$http_user_agent\"",
+    ),
+    ("common", "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent"),
+];
+
+/// Predefined format that writes each request as a JSON object.
+pub const JSON_LOG_FORMAT: &str = "json";
+
+/// Format of an `access_log` that names none.
+pub const DEFAULT_LOG_FORMAT: &str = "combined";
+
+/// Propagates directives http -> server -> location -> nested location and
+/// computes the `EffectiveLocation` of every block. Runs once, right after parsing.
+pub fn resolve(cfg: &mut Config) {
+    for srv in &mut cfg.servers {
+        apply_http_defaults(srv, &cfg.http);
+        srv.effective = effective(&srv.directives, None);
+        srv.effective.rewrites.clear();
+        let server_root = srv.directives.root.clone();
+        for loc in &mut srv.locations {
+            resolve_location(loc, &srv.directives, &srv.effective, server_root.as_deref());
+        }
+    }
+}
+
+/// Shared locations go after the server's own, unless it declares the same one.
+fn apply_http_defaults(srv: &mut Server, http: &HttpBlock) {
+    inherit(&mut srv.directives, &http.directives);
+    if srv.keep_alive_timeout.is_none() {
+        srv.keep_alive_timeout = http.keep_alive_timeout;
+    }
+    if srv.error_log.is_none() {
+        srv.error_log = http.error_log.clone();
+    }
+    for loc in &http.locations {
+        if !srv.locations.iter().any(|l| l.modifier == loc.modifier && l.path == loc.path) {
+            srv.locations.push(loc.clone());
+        }
+    }
+}
+
+fn resolve_location(loc: &mut Location, parent: &Directives, parent_eff: &EffectiveLocation, server_root: Option<&Path>) {
+    let own_root = loc.directives.root.is_some();
+    inherit(&mut loc.directives, parent);
+
+    // A prefix location with its own root maps its path onto that root;
+    // nested locations without a root keep stripping the same prefix.
+    let strip_prefix = if !own_root {
+        parent_eff.strip_prefix.clone()
+    } else if !loc.is_regex() && !loc.is_named() && loc.directives.root.as_deref() != server_root {
+        Some(loc.path.clone())
+    } else {
+        None
+    };
+    loc.effective = effective(&loc.directives, strip_prefix);
+    loc.effective.location = loc.path.clone();
+
+    for child in &mut loc.locations {
+        resolve_location(child, &loc.directives, &loc.effective, server_root);
+    }
+}
+
+/// Fills every unset directive from the enclosing block. Error pages merge per
+/// status code. `redirect`, `try_files` and rewrite rules are not inherited.
+fn inherit(d: &mut Directives, parent: &Directives) {
+    fn fill<T: Clone>(slot: &mut Option<T>, from: &Option<T>) {
+        if slot.is_none() {
+            *slot = from.clone();
+        }
+    }
+    fill(&mut d.root, &parent.root);
+    fill(&mut d.index, &parent.index);
+    fill(&mut d.client_max_body_size, &parent.client_max_body_size);
+    fill(&mut d.methods, &parent.methods);
+    fill(&mut d.autoindex, &parent.autoindex);
+    fill(&mut d.autoindex_format, &parent.autoindex_format);
+    fill(&mut d.autoindex_exact_size, &parent.autoindex_exact_size);
+    fill(&mut d.autoindex_localtime, &parent.autoindex_localtime);
+    fill(&mut d.autoindex_hidden, &parent.autoindex_hidden);
+    fill(&mut d.autoindex_template, &parent.autoindex_template);
+    fill(&mut d.default_file, &parent.default_file);
+    fill(&mut d.cgi, &parent.cgi);
+    fill(&mut d.add_headers, &parent.add_headers);
+    fill(&mut d.access_logs, &parent.access_logs);
+    fill(&mut d.auth_basic, &parent.auth_basic);
+    fill(&mut d.auth_basic_user_file, &parent.auth_basic_user_file);
+    fill(&mut d.auth_request, &parent.auth_request);
+    fill(&mut d.auth_request_set, &parent.auth_request_set);
+    fill(&mut d.access_rules, &parent.access_rules);
+    for page in &parent.errors {
+        if !d.errors.iter().any(|e| e.code == page.code) {
+            d.errors.push(page.clone());
+        }
+    }
+}
+
+fn effective(d: &Directives, strip_prefix: Option<String>) -> EffectiveLocation {
+    let mut index = d.index.clone().unwrap_or_default();
+    if let Some(df) = &d.default_file {
+        index.insert(0, df.clone());
+    }
+    if index.is_empty() {
+        index.push("index.html".into());
+    }
+
+    EffectiveLocation {
+        location: String::new(),
+        root: d.root.clone().unwrap_or_else(|| PathBuf::from("www")),
+        strip_prefix,
+        index,
+        errors: d.errors.clone(),
+        client_max_body_size: d.client_max_body_size.unwrap_or(DEFAULT_BODY_LIMIT),
+        methods: d.methods.clone(),
+        redirect: d.redirect.clone(),
+        autoindex: (d.autoindex == Some(true)).then(|| AutoindexOptions {
+            format: d.autoindex_format.unwrap_or(AutoindexFormat::Html),
+            exact_size: d.autoindex_exact_size.unwrap_or(false),
+            localtime: d.autoindex_localtime.unwrap_or(false),
+            show_hidden: d.autoindex_hidden.unwrap_or(false),
+            template: d.autoindex_template.clone(),
+        }),
+        try_files: d.try_files.clone(),
+        cgi: d.cgi.clone(),
+        rewrites: d.rewrites.clone(),
+        add_headers: d.add_headers.clone().unwrap_or_default(),
+        access_logs: d.access_logs.clone().unwrap_or_default(),
+        metrics: d.metrics,
+        status: d.status,
+        auth_basic: d.auth_basic.clone().flatten().map(|realm| AuthBasic { realm, user_file: d.auth_basic_user_file.clone() }),
+        auth_request: d.auth_request.clone().flatten(),
+        auth_request_set: d.auth_request_set.clone().unwrap_or_default(),
+        access_rules: d.access_rules.clone().unwrap_or_default(),
+    }
+}
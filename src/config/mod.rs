@@ -1,6 +1,11 @@
 pub mod ast;
+pub mod dump;
+pub mod include;
 pub mod loader;
+pub mod merge;
 pub mod parser;
+pub mod units;
+pub mod validate;
 mod tests;
 
 pub use ast::*;
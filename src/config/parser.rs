@@ -1,7 +1,16 @@
+use std::fmt;
+use std::fs;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use regex::{Regex, RegexBuilder};
 
 use super::ast::*;
+use super::{include, merge, units};
+use crate::application::log::access;
+use crate::application::log::error::Level;
 
 #[derive(Debug, Clone)]
 enum Token {
@@ -13,275 +22,937 @@ enum Token {
     Semi,
 }
 
-pub fn parse_config(input: &str, base_dir: &Path) -> Result<Config, String> {
-    let tokens = tokenize(input)?;
-    let mut p = Parser { tokens, pos: 0, base_dir };
-    let cfg = p.parse_config()?;
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(s) | Token::StringLit(s) => write!(f, "{s:?}"),
+            Token::Number(n) => write!(f, "{n}"),
+            Token::LBrace => f.write_str("'{'"),
+            Token::RBrace => f.write_str("'}'"),
+            Token::Semi => f.write_str("';'"),
+        }
+    }
+}
+
+/// A token for error messages, `end of file` when there is none.
+fn describe(tok: Option<&Token>) -> String {
+    tok.map_or_else(|| "end of file".to_string(), Token::to_string)
+}
+
+/// Position of a token in one of the parsed sources, 1-based.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    source: usize,
+    line: usize,
+    col: usize,
+    len: usize,
+}
 
-    // Validation
-    if cfg.servers.is_empty() {
-        return Err("No servers defined".into());
+/// A config file (or the text passed to `parse_config`) and its display name.
+struct Source {
+    name: String,
+    text: String,
+}
+
+/// A parse error and, when known, where it happened.
+struct Diagnostic {
+    span: Option<Span>,
+    message: String,
+}
+
+impl Diagnostic {
+    /// `file:line:col: message` followed by the offending line and a caret.
+    fn render(&self, sources: &[Source]) -> String {
+        let Some(span) = self.span else {
+            return self.message.clone();
+        };
+        let src = &sources[span.source];
+        let line = src.text.lines().nth(span.line - 1).unwrap_or("");
+        // Keep tabs so the caret lines up with the source line
+        let indent: String = line.chars().take(span.col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        let number = span.line.to_string();
+        let pad = " ".repeat(number.len());
+        format!(
+            "{}:{}:{}: {}\n{pad} |\n{number} | {line}\n{pad} | {indent}{}",
+            src.name, span.line, span.col, self.message, "^".repeat(span.len.max(1))
+        )
     }
-    for (i, s) in cfg.servers.iter().enumerate() {
-        if s.listen.is_empty() {
-            return Err(format!("Server #{i} missing listen directive"));
-        }
+}
+
+/// Parses a config. `name` is used in error messages, which point at the
+/// offending line of the file (or included file) and list every error found.
+pub fn parse_config(input: &str, base_dir: &Path, name: &str) -> Result<Config, String> {
+    let mut ctx = Context { base_dir, sources: Vec::new(), errors: Vec::new() };
+    let (tokens, spans) = ctx.read_source(name.to_string(), input.to_string(), &mut Vec::new()).into_iter().unzip();
+    let mut p = Parser { tokens, spans, pos: 0, ctx, log_formats: Vec::new(), log_format_pos: Vec::new(), assigned: Vec::new(), geos: Vec::new() };
+    let mut cfg = p.parse_config();
+
+    let mut ctx = p.ctx;
+    if cfg.servers.is_empty() && ctx.errors.is_empty() {
+        ctx.error(None, "No servers defined".into());
+    }
+    if !ctx.errors.is_empty() {
+        // In file order; errors without a position go last
+        ctx.errors.sort_by_key(|e| e.span.map_or((usize::MAX, 0, 0), |s| (s.source, s.line, s.col)));
+        let rendered: Vec<String> = ctx.errors.iter().map(|e| e.render(&ctx.sources)).collect();
+        return Err(rendered.join("\n"));
     }
 
+    merge::resolve(&mut cfg);
     Ok(cfg)
 }
 
-fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+/// Everything read for one config: the main text and included files, plus
+/// the errors found so far.
+struct Context<'a> {
+    base_dir: &'a Path,
+    sources: Vec<Source>,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'a> Context<'a> {
+    fn error(&mut self, span: Option<Span>, message: String) {
+        self.errors.push(Diagnostic { span, message });
+    }
+
+    /// Tokenizes a source and splices the tokens of `include pattern;` files in
+    /// place of the directive, recursively. `stack` holds the files currently
+    /// being included.
+    fn read_source(&mut self, name: String, text: String, stack: &mut Vec<PathBuf>) -> Vec<(Token, Span)> {
+        let id = self.sources.len();
+        self.sources.push(Source { name, text });
+        let tokens = tokenize(&self.sources[id].text, id, &mut self.errors);
+
+        let mut out: Vec<(Token, Span)> = Vec::with_capacity(tokens.len());
+        let mut iter = tokens.into_iter();
+        while let Some((tok, span)) = iter.next() {
+            // `include` is only a directive where a statement starts
+            let at_statement = matches!(out.last(), None | Some((Token::LBrace | Token::RBrace | Token::Semi, _)));
+            match tok {
+                Token::Ident(s) if s == "include" && at_statement => {
+                    let pattern = match iter.next() {
+                        Some((Token::Ident(p) | Token::StringLit(p), _)) => p,
+                        other => {
+                            self.error(Some(span), format!("include expects a path, got {}", describe(other.as_ref().map(|t| &t.0))));
+                            continue;
+                        }
+                    };
+                    match iter.next() {
+                        Some((Token::Semi, _)) => {}
+                        other => {
+                            let at = other.as_ref().map_or(span, |t| t.1);
+                            self.error(Some(at), format!("Expected {}, got {}", Token::Semi, describe(other.as_ref().map(|t| &t.0))));
+                            continue;
+                        }
+                    }
+                    let files = match include::expand_glob(&pattern, self.base_dir) {
+                        Ok(files) => files,
+                        Err(e) => {
+                            self.error(Some(span), e);
+                            continue;
+                        }
+                    };
+                    for file in files {
+                        let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+                        if stack.contains(&canonical) {
+                            let chain: Vec<String> = stack.iter().chain([&canonical]).map(|p| p.display().to_string()).collect();
+                            self.error(Some(span), format!("include cycle: {}", chain.join(" -> ")));
+                            continue;
+                        }
+                        match fs::read_to_string(&file) {
+                            Ok(content) => {
+                                stack.push(canonical);
+                                out.extend(self.read_source(file.display().to_string(), content, stack));
+                                stack.pop();
+                            }
+                            Err(e) => self.error(Some(span), format!("include {}: {e}", file.display())),
+                        }
+                    }
+                }
+                tok => out.push((tok, span)),
+            }
+        }
+        out
+    }
+}
+
+/// Tracks the line and column while walking a source.
+struct Cursor<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    col: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+}
+
+fn tokenize(input: &str, source: usize, errors: &mut Vec<Diagnostic>) -> Vec<(Token, Span)> {
     let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
-    while let Some(&c) = chars.peek() {
+    let mut chars = Cursor { chars: input.chars().peekable(), line: 1, col: 1 };
+    while let Some(c) = chars.peek() {
+        let (line, col) = (chars.line, chars.col);
+        let span_to = |chars: &Cursor| Span {
+            source,
+            line,
+            col,
+            len: if chars.line == line { chars.col - col } else { 1 },
+        };
         match c {
-            '{' => { chars.next(); tokens.push(Token::LBrace); }
-            '}' => { chars.next(); tokens.push(Token::RBrace); }
-            ';' => { chars.next(); tokens.push(Token::Semi); }
+            '{' => { chars.next(); tokens.push((Token::LBrace, span_to(&chars))); }
+            '}' => { chars.next(); tokens.push((Token::RBrace, span_to(&chars))); }
+            ';' => { chars.next(); tokens.push((Token::Semi, span_to(&chars))); }
             '#' => { while let Some(ch) = chars.next() { if ch == '\n' { break; } } }
-            '"' => {
+            '"' | '\'' => {
                 chars.next();
                 let mut s = String::new();
                 let mut terminated = false;
                 while let Some(ch) = chars.next() {
-                    if ch == '"' {
+                    if ch == c {
                         terminated = true;
                         break;
                     }
                     s.push(ch);
                 }
+                let span = span_to(&chars);
                 if !terminated {
-                    return Err("Unterminated string literal".into());
+                    let opening = Span { len: 1, ..span };
+                    errors.push(Diagnostic { span: Some(opening), message: "Unterminated string literal".into() });
+                    break;
                 }
-                tokens.push(Token::StringLit(s));
+                let s = expand_env(&s).unwrap_or_else(|e| {
+                    errors.push(Diagnostic { span: Some(span), message: e });
+                    s
+                });
+                tokens.push((Token::StringLit(s), span));
             }
             c if c.is_ascii_whitespace() => { chars.next(); }
-            c if c.is_ascii_digit() => {
-                let mut s = String::new();
-                while let Some(&ch) = chars.peek() {
-                    if ch.is_ascii_whitespace() || ch == '{' || ch == '}' || ch == ';' { 
-                        break; 
-                    }
-                    s.push(ch);
-                    chars.next();
-              }
-              if s.chars().all(|ch| ch.is_ascii_digit()) {
-                let n = s.parse::<u64>().map_err(|e| e.to_string())?;
-                tokens.push(Token::Number(n));
-              } else {
-                tokens.push(Token::Ident(s));
-            }
-        }
             _ => {
                 let mut s = String::new();
-                while let Some(&ch) = chars.peek() {
+                while let Some(ch) = chars.peek() {
                     if ch.is_ascii_whitespace() || ch == '{' || ch == '}' || ch == ';' {
+                        // `${NAME}` may be followed by braces of its own
+                        if ch == '{' && s.ends_with('$') {
+                            while let Some(ch) = chars.next() {
+                                s.push(ch);
+                                if ch == '}' { break; }
+                            }
+                            continue;
+                        }
                         break;
                     }
                     s.push(ch);
                     chars.next();
                 }
-                tokens.push(Token::Ident(s));
+                let span = span_to(&chars);
+                let s = expand_env(&s).unwrap_or_else(|e| {
+                    errors.push(Diagnostic { span: Some(span), message: e });
+                    s
+                });
+                match s.parse::<u64>() {
+                    Ok(n) if s.chars().all(|ch| ch.is_ascii_digit()) => tokens.push((Token::Number(n), span)),
+                    Err(e) if !s.is_empty() && s.chars().all(|ch| ch.is_ascii_digit()) => {
+                        errors.push(Diagnostic { span: Some(span), message: format!("Invalid number {s}: {e}") });
+                    }
+                    _ => tokens.push((Token::Ident(s), span)),
+                }
             }
         }
     }
-    Ok(tokens)
+    tokens
+}
+
+/// Replaces `${NAME}` with the value of the environment variable `NAME`.
+/// Plain `$name` references are left for request time.
+fn expand_env(word: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(word.len());
+    let mut rest = word;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| format!("Unterminated ${{ in {word}"))?;
+        let name = &after[..end];
+        let value = std::env::var(name).map_err(|_| format!("Environment variable {name} is not set"))?;
+        out.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Names handled by `parse_directive`, offered as did-you-mean suggestions.
+const BLOCK_DIRECTIVES: &[&str] = &[
+    "root", "index", "error_page", "client_max_body_size", "body_limit", "methods", "redirect",
+    "autoindex", "autoindex_format", "autoindex_exact_size", "autoindex_localtime", "autoindex_hidden",
+    "autoindex_template", "default_file", "try_files", "rewrite", "return", "set", "add_header", "cgi",
+    "access_log", "auth_basic", "auth_basic_user_file", "auth_request", "auth_request_set", "allow", "deny",
+    "include",
+];
+const TOP_LEVEL_DIRECTIVES: &[&str] = &[
+    "server", "http", "include", "pid", "shutdown_timeout", "worker_processes", "worker_threads", "log_format",
+    "error_log", "geo",
+];
+const HTTP_DIRECTIVES: &[&str] = &["server", "location", "keep_alive_timeout", "log_format", "error_log", "geo"];
+const SERVER_DIRECTIVES: &[&str] = &["listen", "server_name", "location", "keep_alive_timeout", "error_log"];
+const LOCATION_DIRECTIVES: &[&str] = &["location", "metrics", "status"];
+
+/// `Unknown directive "rot" in server, did you mean "root"?`
+fn unknown_directive(name: &str, context: &str, own: &[&str], shared: &[&str]) -> String {
+    let closest = own
+        .iter()
+        .chain(shared)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .min_by_key(|(distance, _)| *distance)
+        .filter(|(distance, _)| *distance <= (name.len() / 3).max(1));
+    match closest {
+        Some((_, suggestion)) => format!("Unknown directive {name:?} in {context}, did you mean {suggestion:?}?"),
+        None => format!("Unknown directive {name:?} in {context}"),
+    }
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
 }
 
 struct Parser<'a> {
     tokens: Vec<Token>,
+    /// Position of each token in `tokens`
+    spans: Vec<Span>,
     pos: usize,
-    base_dir: &'a Path,
+    ctx: Context<'a>,
+    /// Declared so far: `access_log` can only use a format declared before it
+    log_formats: Vec<LogFormat>,
+    /// Token position of each of `log_formats`, for errors about its variables
+    log_format_pos: Vec<usize>,
+    /// Variables assigned by `set` and `auth_request_set` anywhere in the
+    /// config, which log formats may use
+    assigned: Vec<String>,
+    /// Declared so far, as for `log_formats`: `allow` and `deny` can only use
+    /// a geo declared before them
+    geos: Vec<Geo>,
 }
 
 impl<'a> Parser<'a> {
-    fn parse_config(&mut self) -> Result<Config, String> {
-        let mut servers = Vec::new();
+    fn parse_config(&mut self) -> Config {
+        let mut cfg = Config {
+            servers: Vec::new(),
+            http: HttpBlock::default(),
+            pid: self.ctx.base_dir.join(merge::DEFAULT_PID_FILE),
+            shutdown_timeout: merge::DEFAULT_SHUTDOWN_TIMEOUT,
+            worker_processes: 1,
+            worker_threads: None,
+            log_formats: Vec::new(),
+            geos: Vec::new(),
+            error_log: merge::DEFAULT_ERROR_LOG,
+        };
+        let mut http = None;
         while !self.is_end() {
-            match self.peek() {
-                Some(Token::Ident(s)) if s == "server" => {
-                    self.next();
-                    self.expect(Token::LBrace)?;
-                    servers.push(self.parse_server()?);
+            self.statement(|p| p.top_level_statement(&mut cfg, &mut http));
+        }
+        cfg.http = http.unwrap_or_default();
+        // Checked at the end: the `set` of a variable may come after its format
+        let unknown: Vec<(usize, String)> = self
+            .log_formats
+            .iter()
+            .zip(&self.log_format_pos)
+            .filter_map(|(format, &at)| {
+                let name = access::unknown_variable(&format.template, &self.assigned)?;
+                Some((at, format!("Unknown variable ${name} in log_format {}", format.name)))
+            })
+            .collect();
+        for (at, message) in unknown {
+            self.error_at(at, message);
+        }
+        cfg.log_formats = std::mem::take(&mut self.log_formats);
+        cfg.geos = std::mem::take(&mut self.geos);
+        cfg
+    }
+
+    fn top_level_statement(&mut self, cfg: &mut Config, http: &mut Option<HttpBlock>) -> Result<(), String> {
+        let keyword = self.pos;
+        match self.next() {
+            Some(Token::Ident(s)) if s == "server" => {
+                self.expect(Token::LBrace)?;
+                cfg.servers.push(self.parse_server(keyword));
+            }
+            Some(Token::Ident(s)) if s == "http" => {
+                self.expect(Token::LBrace)?;
+                let block = self.parse_http(keyword, &mut cfg.servers);
+                if http.is_some() {
+                    self.error_at(keyword, "Duplicate http block".into());
+                } else {
+                    *http = Some(block);
                 }
-                Some(tok) => return Err(format!("Unexpected token at top-level: {:?}", tok)),
-                None => break,
             }
+            Some(Token::Ident(s)) if s == "pid" => {
+                cfg.pid = self.parse_path()?;
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "shutdown_timeout" => {
+                cfg.shutdown_timeout = self.expect_duration("shutdown_timeout")?;
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "worker_processes" => {
+                cfg.worker_processes = self.parse_count("worker_processes", merge::MAX_WORKER_PROCESSES)?;
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "worker_threads" => {
+                cfg.worker_threads = Some(self.parse_count("worker_threads", merge::MAX_WORKER_THREADS)?);
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "log_format" => self.parse_log_format()?,
+            Some(Token::Ident(s)) if s == "error_log" => cfg.error_log = self.parse_error_log()?,
+            Some(Token::Ident(s)) if s == "geo" => self.parse_geo()?,
+            Some(Token::Ident(s)) => return Err(unknown_directive(&s, "top level", TOP_LEVEL_DIRECTIVES, &[])),
+            Some(tok) => return Err(format!("Unexpected {tok} at top level")),
+            None => {}
         }
-        Ok(Config { servers })
+        Ok(())
     }
 
-    fn parse_server(&mut self) -> Result<Server, String> {
-        let mut listen = Vec::new();
-        let mut server_names = Vec::new();
-        let mut root = None;
-        let mut index = Vec::new();
-        let mut errors = Vec::new();
-        let mut locations = Vec::new();
-        let mut client_max_body_size = None;
-        let mut keep_alive_timeout = None;
+    /// `worker_processes` and `worker_threads`: `N` or `auto`, one per CPU.
+    fn parse_count(&mut self, directive: &str, max: usize) -> Result<usize, String> {
+        match self.next() {
+            Some(Token::Ident(s)) if s == "auto" => Ok(std::thread::available_parallelism().map_or(1, |n| n.get()).min(max)),
+            Some(Token::Number(n)) if (1..=max as u64).contains(&n) => Ok(n as usize),
+            Some(Token::Number(n)) => Err(format!("{directive}: {n} is out of range, expected 1 to {max}")),
+            other => Err(format!("{directive}: expected a number or auto, got {}", describe(other.as_ref()))),
+        }
+    }
 
-        loop {
-            match self.peek() {
-                Some(Token::RBrace) => { self.next(); break; }
-                Some(Token::Ident(s)) if s == "listen" => {
-                    self.next();
-                    let addr = self.parse_listen_value()?;
-                    listen.push(addr);
-                    self.expect(Token::Semi)?;
-                }
-                Some(Token::Ident(s)) if s == "server_name" => {
-                    self.next();
-                    loop {
-                        match self.peek() {
-                            Some(Token::Semi) => break,
-                            Some(Token::Ident(name)) => { server_names.push(name.clone()); self.next(); }
-                            Some(Token::StringLit(name)) => { server_names.push(name.clone()); self.next(); }
-                            other => return Err(format!("Unexpected in server_name: {:?}", other)),
-                        }
-                    }
-                    self.expect(Token::Semi)?;
-                }
-                Some(Token::Ident(s)) if s == "root" => {
-                    self.next();
-                    let path = self.parse_path()?;
-                    root = Some(path);
-                    self.expect(Token::Semi)?;
-                }
-                Some(Token::Ident(s)) if s == "index" => {
-                    self.next();
-                    loop {
-                        match self.peek() {
-                            Some(Token::Semi) => break,
-                            Some(Token::Ident(v)) => { index.push(v.clone()); self.next(); }
-                            Some(Token::StringLit(v)) => { index.push(v.clone()); self.next(); }
-                            other => return Err(format!("Unexpected in index: {:?}", other)),
-                        }
-                    }
-                    self.expect(Token::Semi)?;
-                }
-                Some(Token::Ident(s)) if s == "error_page" => {
-                    self.next();
-                    let code = self.expect_number_u16()?;
-                    let path = self.expect_stringish()?;
-                    errors.push(ErrorPage { code, path });
-                    self.expect(Token::Semi)?;
-                }
-                Some(Token::Ident(s)) if s == "location" => {
-                    self.next();
-                    let path = self.expect_stringish()?;
-                    self.expect(Token::LBrace)?;
-                    let mut loc = self.parse_location(path)?;
-                    if loc.root.is_none() {
-                        loc.root = root.clone(); // inherit root
-                    }
-                    locations.push(loc);
-                }
-                Some(Token::Ident(s)) if s == "client_max_body_size" => {
+    /// `log_format name 'string' ...;`, after the keyword. The strings are
+    /// joined, so a long format can be split over several lines.
+    fn parse_log_format(&mut self) -> Result<(), String> {
+        let at = self.pos;
+        let name = self.expect_stringish()?;
+        let parts = self.parse_string_list("log_format")?;
+        self.expect(Token::Semi)?;
+        if parts.is_empty() {
+            return Err(format!("log_format {name} expects a format"));
+        }
+        let template = parts.concat();
+        if self.find_log_format(&name).is_some() {
+            return Err(format!("Duplicate log_format {name}"));
+        }
+        self.log_formats.push(LogFormat { name, template });
+        self.log_format_pos.push(at);
+        Ok(())
+    }
+
+    /// `error_log path|stderr [level];`, after the keyword.
+    fn parse_error_log(&mut self) -> Result<ErrorLog, String> {
+        let path = match self.peek() {
+            Some(Token::Ident(s)) if s == "stderr" => {
+                self.next();
+                None
+            }
+            _ => Some(self.parse_path()?),
+        };
+        let level = match self.peek() {
+            Some(Token::Semi) => merge::DEFAULT_ERROR_LOG.level,
+            _ => {
+                let name = self.expect_ident()?;
+                Level::parse(&name).ok_or_else(|| format!("error_log level expects debug|info|notice|warn|error|crit, got {name}"))?
+            }
+        };
+        self.expect(Token::Semi)?;
+        Ok(ErrorLog { path, level })
+    }
+
+    /// `geo name file;`, after the keyword. The file lists one address or
+    /// CIDR network per line; blank lines and `#` comments are skipped.
+    fn parse_geo(&mut self) -> Result<(), String> {
+        let name = self.expect_stringish()?;
+        let path = self.parse_path()?;
+        self.expect(Token::Semi)?;
+        if name == "all" || Cidr::parse(&name).is_some() {
+            return Err(format!("geo name {name} would be read as an address"));
+        }
+        if self.geos.iter().any(|g| g.name == name) {
+            return Err(format!("Duplicate geo {name}"));
+        }
+        let text = fs::read_to_string(&path).map_err(|e| format!("geo {name}: cannot read {}: {e}", path.display()))?;
+        let mut networks = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim().trim_end_matches(';').trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let net = Cidr::parse(line)
+                .ok_or_else(|| format!("geo {name}: {}:{}: invalid network {line}", path.display(), n + 1))?;
+            networks.push(net);
+        }
+        self.geos.push(Geo { name, path, networks: Arc::from(networks) });
+        Ok(())
+    }
+
+    /// `allow` and `deny`: `all`, an address, a CIDR network or a `geo` list.
+    fn parse_access_source(&mut self, directive: &str) -> Result<AccessSource, String> {
+        let word = self.expect_stringish()?;
+        if word == "all" {
+            return Ok(AccessSource::All);
+        }
+        if let Some(net) = Cidr::parse(&word) {
+            return Ok(AccessSource::Network(net));
+        }
+        match self.geos.iter().find(|g| g.name == word) {
+            Some(geo) => Ok(AccessSource::Geo { name: word, networks: geo.networks.clone() }),
+            None => Err(format!("{directive} expects all, an address, a network or a geo list, got {word}")),
+        }
+    }
+
+    fn find_log_format(&self, name: &str) -> Option<LogTemplate> {
+        if name == merge::JSON_LOG_FORMAT {
+            return Some(LogTemplate::Json);
+        }
+        self.log_formats
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.template.clone())
+            .or_else(|| merge::PREDEFINED_LOG_FORMATS.iter().find(|(n, _)| *n == name).map(|(_, t)| t.to_string()))
+            .map(LogTemplate::Text)
+    }
+
+    /// `http { ... }`: defaults and shared locations for every server. Server
+    /// blocks may be nested inside it; they are collected into `servers`.
+    fn parse_http(&mut self, keyword: usize, servers: &mut Vec<Server>) -> HttpBlock {
+        let mut http = HttpBlock::default();
+        while self.block_continues(keyword, "http") {
+            self.statement(|p| p.http_statement(&mut http, servers));
+        }
+        http
+    }
+
+    fn http_statement(&mut self, http: &mut HttpBlock, servers: &mut Vec<Server>) -> Result<(), String> {
+        let keyword = self.pos;
+        match self.peek() {
+            Some(Token::Ident(s)) if s == "server" => {
+                self.next();
+                self.expect(Token::LBrace)?;
+                servers.push(self.parse_server(keyword));
+            }
+            Some(Token::Ident(s)) if s == "location" => {
+                self.next();
+                http.locations.push(self.parse_location_block()?);
+            }
+            Some(Token::Ident(s)) if s == "keep_alive_timeout" => {
+                self.next();
+                http.keep_alive_timeout = Some(self.expect_duration("keep_alive_timeout")?);
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "log_format" => {
+                self.next();
+                self.parse_log_format()?;
+            }
+            Some(Token::Ident(s)) if s == "error_log" => {
+                self.next();
+                http.error_log = Some(self.parse_error_log()?);
+            }
+            Some(Token::Ident(s)) if s == "geo" => {
+                self.next();
+                self.parse_geo()?;
+            }
+            // These only apply where they are written, so a default makes no sense
+            Some(Token::Ident(s)) if matches!(s.as_str(), "rewrite" | "return" | "set" | "redirect" | "try_files") => {
+                let s = s.clone();
+                self.next();
+                return Err(format!("{s} is not allowed in http"));
+            }
+            Some(Token::Ident(s)) => {
+                let name = s.clone();
+                if !self.parse_directive(&name, &mut http.directives)? {
                     self.next();
-                    client_max_body_size = Some(self.expect_number_u64()?);
-                    self.expect(Token::Semi)?;
+                    return Err(unknown_directive(&name, "http", HTTP_DIRECTIVES, BLOCK_DIRECTIVES));
                 }
-                Some(Token::Ident(s)) if s == "keep_alive_timeout" => {
+            }
+            _ => return Err(format!("Expected a directive in http, got {}", describe(self.next().as_ref()))),
+        }
+        Ok(())
+    }
+
+    fn parse_server(&mut self, keyword: usize) -> Server {
+        let mut srv = Server {
+            listen: Vec::new(),
+            server_names: Vec::new(),
+            keep_alive_timeout: None,
+            error_log: None,
+            directives: Directives::default(),
+            locations: Vec::new(),
+            effective: EffectiveLocation::default(),
+        };
+        while self.block_continues(keyword, "server") {
+            self.statement(|p| p.server_statement(&mut srv));
+        }
+        if srv.listen.is_empty() {
+            self.error_at(keyword, "Server missing listen directive".into());
+        }
+        srv
+    }
+
+    fn server_statement(&mut self, srv: &mut Server) -> Result<(), String> {
+        match self.peek() {
+            Some(Token::Ident(s)) if s == "listen" => {
+                self.next();
+                let addr = self.parse_listen_value()?;
+                srv.listen.push(addr);
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "server_name" => {
+                self.next();
+                srv.server_names.extend(self.parse_string_list("server_name")?);
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "location" => {
+                self.next();
+                srv.locations.push(self.parse_location_block()?);
+            }
+            Some(Token::Ident(s)) if s == "keep_alive_timeout" => {
+                self.next();
+                srv.keep_alive_timeout = Some(self.expect_duration("keep_alive_timeout")?);
+                self.expect(Token::Semi)?;
+            }
+            Some(Token::Ident(s)) if s == "error_log" => {
+                self.next();
+                srv.error_log = Some(self.parse_error_log()?);
+            }
+            Some(Token::Ident(s)) => {
+                let name = s.clone();
+                if !self.parse_directive(&name, &mut srv.directives)? {
                     self.next();
-                    keep_alive_timeout = Some(self.expect_number_u64()?);
-                    self.expect(Token::Semi)?;
+                    return Err(unknown_directive(&name, "server", SERVER_DIRECTIVES, BLOCK_DIRECTIVES));
                 }
-                Some(tok) => return Err(format!("Unknown directive in server: {:?}", tok)),
-                None => return Err("Unexpected EOF in server block".into()),
             }
+            _ => return Err(format!("Expected a directive in server, got {}", describe(self.next().as_ref()))),
         }
+        Ok(())
+    }
 
-        Ok(Server {
-            listen,
-            server_names,
-            root,
-            index,
-            errors,
-            locations,
-            client_max_body_size,
-            keep_alive_timeout,
-        })
+    /// `location [modifier] pattern { ... }`, after the `location` keyword.
+    fn parse_location_block(&mut self) -> Result<Location, String> {
+        let keyword = self.pos.saturating_sub(1);
+        let (modifier, path, regex) = self.parse_location_pattern()?;
+        self.expect(Token::LBrace)?;
+        Ok(self.parse_location(keyword, modifier, path, regex))
     }
 
-    fn parse_location(&mut self, path: String) -> Result<Location, String> {
-        let mut root = None;
-        let mut methods = None;
-        let mut redirect = None;
-        let mut autoindex = None;
-        let mut default_file = None;
-        let mut cgi = None;
-        let mut body_limit = None;
+    fn parse_location_pattern(&mut self) -> Result<(LocationModifier, String, Option<Regex>), String> {
+        let first = self.expect_stringish()?;
+        let modifier = match first.as_str() {
+            "=" => LocationModifier::Exact,
+            "^~" => LocationModifier::PreferPrefix,
+            "~" => LocationModifier::Regex,
+            "~*" => LocationModifier::RegexCaseless,
+            _ => return Ok((LocationModifier::Prefix, first, None)),
+        };
+        let pattern = self.expect_stringish()?;
+        let regex = match modifier {
+            LocationModifier::Regex | LocationModifier::RegexCaseless => Some(
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(modifier == LocationModifier::RegexCaseless)
+                    .build()
+                    .map_err(|e| format!("Invalid location regex {pattern}: {e}"))?,
+            ),
+            _ => None,
+        };
+        Ok((modifier, pattern, regex))
+    }
 
-        loop {
-            match self.peek() {
-                Some(Token::RBrace) => { self.next(); break; }
-                Some(Token::Ident(s)) if s == "root" => {
-                    self.next();
-                    root = Some(self.parse_path()?);
-                    self.expect(Token::Semi)?;
+    fn parse_location(&mut self, keyword: usize, modifier: LocationModifier, path: String, regex: Option<Regex>) -> Location {
+        let mut loc = Location {
+            path,
+            modifier,
+            regex,
+            directives: Directives::default(),
+            locations: Vec::new(),
+            effective: EffectiveLocation::default(),
+        };
+        while self.block_continues(keyword, "location") {
+            self.statement(|p| p.location_statement(&mut loc));
+        }
+        loc
+    }
+
+    fn location_statement(&mut self, loc: &mut Location) -> Result<(), String> {
+        match self.peek() {
+            Some(Token::Ident(s)) if s == "location" => {
+                let keyword = self.pos;
+                self.next();
+                let nested = self.parse_location_block()?;
+                if nested.is_named() {
+                    // Reported in place: the block is already consumed
+                    self.error_at(keyword, format!("Named location {} cannot be nested", nested.path));
+                } else {
+                    loc.locations.push(nested);
                 }
-                Some(Token::Ident(s)) if s == "methods" => {
+            }
+            Some(Token::Ident(s)) if s == "metrics" => {
+                self.next();
+                self.expect(Token::Semi)?;
+                loc.directives.metrics = true;
+            }
+            Some(Token::Ident(s)) if s == "status" => {
+                self.next();
+                let connections = match self.peek() {
+                    Some(Token::Semi) => false,
+                    _ => match self.expect_ident()?.as_str() {
+                        "connections" => true,
+                        other => return Err(format!("status expects nothing or connections, got {other}")),
+                    },
+                };
+                self.expect(Token::Semi)?;
+                loc.directives.status = Some(StatusPage { connections });
+            }
+            Some(Token::Ident(s)) => {
+                let name = s.clone();
+                if !self.parse_directive(&name, &mut loc.directives)? {
                     self.next();
-                    let mut ms = Vec::new();
-                    loop {
-                        match self.peek() {
-                            Some(Token::Semi) => break,
-                            Some(Token::Ident(m)) => { ms.push(self.parse_method(m)?); self.next(); }
-                            other => return Err(format!("Unexpected in methods: {:?}", other)),
-                        }
+                    return Err(unknown_directive(&name, "location", LOCATION_DIRECTIVES, BLOCK_DIRECTIVES));
+                }
+            }
+            _ => return Err(format!("Expected a directive in location, got {}", describe(self.next().as_ref()))),
+        }
+        Ok(())
+    }
+
+    /// Parses a directive valid in both server and location blocks into `d`,
+    /// including its terminating `;`. Returns `Ok(false)` for any other name.
+    fn parse_directive(&mut self, name: &str, d: &mut Directives) -> Result<bool, String> {
+        match name {
+            "root" => {
+                self.next();
+                d.root = Some(self.parse_path()?);
+            }
+            "index" => {
+                self.next();
+                let names = self.parse_string_list("index")?;
+                d.index.get_or_insert_with(Vec::new).extend(names);
+            }
+            "error_page" => {
+                self.next();
+                let code = self.expect_number_u16()?;
+                let path = self.expect_stringish()?;
+                d.errors.push(ErrorPage { code, path });
+            }
+            "client_max_body_size" | "body_limit" => {
+                self.next();
+                d.client_max_body_size = Some(self.expect_size(name)?);
+            }
+            "methods" => {
+                self.next();
+                let mut ms = Vec::new();
+                loop {
+                    match self.peek() {
+                        Some(Token::Semi) => break,
+                        Some(Token::Ident(m)) => { ms.push(self.parse_method(m)?); self.next(); }
+                        other => return Err(format!("Unexpected {} in methods", describe(other))),
                     }
-                    methods = Some(ms);
-                    self.expect(Token::Semi)?;
                 }
-                Some(Token::Ident(s)) if s == "redirect" => {
-                    self.next();
-                    redirect = Some(self.expect_stringish()?);
-                    self.expect(Token::Semi)?;
+                d.methods = Some(ms);
+            }
+            "redirect" => {
+                self.next();
+                d.redirect = Some(self.expect_stringish()?);
+            }
+            "autoindex" => {
+                self.next();
+                d.autoindex = Some(self.expect_on_off("autoindex")?);
+            }
+            "autoindex_format" => {
+                self.next();
+                let v = self.expect_ident()?.to_lowercase();
+                d.autoindex_format = match v.as_str() {
+                    "html" => Some(AutoindexFormat::Html),
+                    "json" => Some(AutoindexFormat::Json),
+                    "xml" => Some(AutoindexFormat::Xml),
+                    _ => return Err("autoindex_format expects html|json|xml".into()),
+                };
+            }
+            "autoindex_exact_size" => {
+                self.next();
+                d.autoindex_exact_size = Some(self.expect_on_off("autoindex_exact_size")?);
+            }
+            "autoindex_localtime" => {
+                self.next();
+                d.autoindex_localtime = Some(self.expect_on_off("autoindex_localtime")?);
+            }
+            "autoindex_hidden" => {
+                self.next();
+                d.autoindex_hidden = Some(self.expect_on_off("autoindex_hidden")?);
+            }
+            "autoindex_template" => {
+                self.next();
+                d.autoindex_template = Some(self.parse_path()?);
+            }
+            "default_file" => {
+                self.next();
+                d.default_file = Some(self.expect_stringish()?);
+            }
+            "try_files" => {
+                self.next();
+                let entries = self.parse_string_list("try_files")?;
+                if entries.len() < 2 {
+                    return Err("try_files expects at least one file and a fallback".into());
                 }
-                Some(Token::Ident(s)) if s == "autoindex" => {
-                    self.next();
-                    let v = self.expect_ident()?.to_lowercase();
-                    autoindex = match v.as_str() {
-                        "on" => Some(true),
-                        "off" => Some(false),
-                        _ => return Err("autoindex expects on|off".into()),
-                    };
-                    self.expect(Token::Semi)?;
+                let fallback = entries.last().unwrap();
+                if let Some(code) = fallback.strip_prefix('=')
+                    && code.parse::<u16>().ok().and_then(crate::http::StatusCode::from_u16).is_none()
+                {
+                    return Err(format!("Unsupported try_files status: {fallback}"));
                 }
-                Some(Token::Ident(s)) if s == "default_file" => {
-                    self.next();
-                    default_file = Some(self.expect_stringish()?);
-                    self.expect(Token::Semi)?;
+                d.try_files = Some(entries);
+            }
+            "rewrite" => {
+                self.next();
+                let pattern = self.expect_stringish()?;
+                let regex = Regex::new(&pattern).map_err(|e| format!("Invalid rewrite regex {pattern}: {e}"))?;
+                let replacement = self.expect_stringish()?;
+                let flag = match self.peek() {
+                    Some(Token::Semi) => None,
+                    _ => Some(match self.expect_ident()?.as_str() {
+                        "last" => RewriteFlag::Last,
+                        "break" => RewriteFlag::Break,
+                        "redirect" => RewriteFlag::Redirect,
+                        "permanent" => RewriteFlag::Permanent,
+                        other => return Err(format!("rewrite flag expects last|break|redirect|permanent, got {other}")),
+                    }),
+                };
+                d.rewrites.push(RewriteOp::Rewrite { regex, replacement, flag });
+            }
+            "return" => {
+                self.next();
+                let (code, target) = match self.next() {
+                    Some(Token::Number(n)) => {
+                        let target = match self.peek() {
+                            Some(Token::Semi) => None,
+                            _ => Some(self.expect_stringish()?),
+                        };
+                        (n, target)
+                    }
+                    // `return url;` is a temporary redirect
+                    Some(Token::Ident(url)) | Some(Token::StringLit(url)) => (302, Some(url)),
+                    other => return Err(format!("Expected return code, got {}", describe(other.as_ref()))),
+                };
+                let status = u16::try_from(code).ok().and_then(crate::http::StatusCode::from_u16);
+                let Some(status) = status else {
+                    return Err(format!("Unsupported return status: {code}"));
+                };
+                if status.is_redirect() && target.is_none() {
+                    return Err(format!("return {code} expects a URL"));
                 }
-                Some(Token::Ident(s)) if s == "cgi" => {
+                d.rewrites.push(RewriteOp::Return { code: status.as_u16(), target });
+            }
+            "set" => {
+                self.next();
+                let name = self.expect_variable("set")?;
+                let value = self.expect_value()?;
+                self.assigned.push(name.clone());
+                d.rewrites.push(RewriteOp::Set { name, value });
+            }
+            "add_header" => {
+                self.next();
+                let name = self.expect_stringish()?;
+                let value = self.expect_value()?;
+                let always = match self.peek() {
+                    Some(Token::Semi) => false,
+                    _ => match self.expect_ident()?.as_str() {
+                        "always" => true,
+                        _ => return Err("add_header expects name value [always]".into()),
+                    },
+                };
+                d.add_headers.get_or_insert_with(Vec::new).push(AddHeader { name, value, always });
+            }
+            "access_log" => {
+                self.next();
+                if matches!(self.peek(), Some(Token::Ident(s)) if s == "off") {
                     self.next();
-                    let ext = self.expect_stringish()?;
-                    let interpreter = PathBuf::from(self.expect_stringish()?);
-                    cgi = Some(Cgi { extension: ext, interpreter });
-                    self.expect(Token::Semi)?;
+                    d.access_logs = Some(Vec::new());
+                } else {
+                    let path = self.parse_path()?;
+                    let format = match self.peek() {
+                        Some(Token::Semi) => merge::DEFAULT_LOG_FORMAT.to_string(),
+                        _ => self.expect_stringish()?,
+                    };
+                    let template = self.find_log_format(&format).ok_or_else(|| format!("Unknown log_format {format}"))?;
+                    d.access_logs.get_or_insert_with(Vec::new).push(AccessLog { path, format, template });
                 }
-                Some(Token::Ident(s)) if s == "body_limit" => {
-                    self.next();
-                    body_limit = Some(self.expect_number_u64()?);
-                    self.expect(Token::Semi)?;
+            }
+            "auth_basic" => {
+                self.next();
+                let realm = self.expect_stringish()?;
+                d.auth_basic = Some((realm != "off").then_some(realm));
+            }
+            "auth_basic_user_file" => {
+                self.next();
+                d.auth_basic_user_file = Some(self.parse_path()?);
+            }
+            "auth_request" => {
+                self.next();
+                let uri = self.expect_stringish()?;
+                if uri != "off" && !uri.starts_with('/') {
+                    return Err(format!("auth_request expects a URI or off, got {uri}"));
                 }
-                Some(tok) => return Err(format!("Unknown directive in location: {:?}", tok)),
-                None => return Err("Unexpected EOF in location block".into()),
+                d.auth_request = Some((uri != "off").then_some(uri));
             }
+            "auth_request_set" => {
+                self.next();
+                let name = self.expect_variable("auth_request_set")?;
+                let value = self.expect_value()?;
+                self.assigned.push(name.clone());
+                d.auth_request_set.get_or_insert_with(Vec::new).push(AuthRequestSet { name, value });
+            }
+            "allow" | "deny" => {
+                let allow = name == "allow";
+                self.next();
+                let source = self.parse_access_source(name)?;
+                d.access_rules.get_or_insert_with(Vec::new).push(AccessRule { allow, source });
+            }
+            "cgi" => {
+                self.next();
+                let ext = self.expect_stringish()?;
+                let interpreter = PathBuf::from(self.expect_stringish()?);
+                d.cgi = Some(Cgi { extension: ext, interpreter });
+            }
+            _ => return Ok(false),
         }
+        self.expect(Token::Semi)?;
+        Ok(true)
+    }
 
-        Ok(Location {
-            path,
-            root,
-            methods,
-            redirect,
-            autoindex,
-            default_file,
-            cgi,
-            body_limit,
-        })
+    /// Collects identifiers and strings up to (not including) the next `;`.
+    fn parse_string_list(&mut self, directive: &str) -> Result<Vec<String>, String> {
+        let mut values = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Semi) => break,
+                Some(Token::Ident(v)) | Some(Token::StringLit(v)) => { values.push(v.clone()); self.next(); }
+                other => return Err(format!("Unexpected {} in {directive}", describe(other))),
+            }
+        }
+        Ok(values)
     }
 
     fn parse_listen_value(&mut self) -> Result<SocketAddr, String> {
@@ -291,7 +962,7 @@ impl<'a> Parser<'a> {
                 let addr = format!("0.0.0.0:{}", n);
                 self.parse_socket_addr(&addr)
             }
-            other => Err(format!("Expected listen address, got {:?}", other)),
+            other => Err(format!("Expected listen address, got {}", describe(other.as_ref()))),
         }
     }
 
@@ -309,13 +980,27 @@ impl<'a> Parser<'a> {
         Err(format!("Invalid listen address: {s}"))
     }
 
+    /// The name of a `$variable` that `directive` assigns, which must not be a
+    /// built-in one.
+    fn expect_variable(&mut self, directive: &str) -> Result<String, String> {
+        let var = self.expect_stringish()?;
+        let name = match var.strip_prefix('$') {
+            Some(n) if !n.is_empty() && n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => n,
+            _ => return Err(format!("{directive} expects a $variable, got {var}")),
+        };
+        if crate::application::server::variables::is_builtin(name) {
+            return Err(format!("{directive} cannot assign the built-in variable {var}"));
+        }
+        Ok(name.to_string())
+    }
+
     fn parse_path(&mut self) -> Result<PathBuf, String> {
         let p = self.expect_stringish()?;
         let pb = PathBuf::from(&p);
         if pb.is_absolute() {
             Ok(pb)
         } else {
-            Ok(self.base_dir.join(pb))
+            Ok(self.ctx.base_dir.join(pb))
         }
     }
 
@@ -328,6 +1013,75 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Runs one statement parser. On failure the error is recorded with the
+    /// position of the offending token and parsing resumes after the statement.
+    fn statement(&mut self, parse: impl FnOnce(&mut Self) -> Result<(), String>) {
+        let start = self.pos;
+        if let Err(message) = parse(self) {
+            let at = if self.pos > start { self.pos - 1 } else { self.pos };
+            self.error_at(at, message);
+            self.recover(start);
+        }
+    }
+
+    /// Skips the rest of a statement that failed to parse: up to and including
+    /// its `;` or `{ ... }` block, stopping before a `}` that closes the
+    /// enclosing block.
+    fn recover(&mut self, start: usize) {
+        let mut depth = 0;
+        if self.pos > start {
+            match self.tokens[self.pos - 1] {
+                Token::Semi => return,
+                Token::RBrace if self.pos - 1 > start => {
+                    self.pos -= 1;
+                    return;
+                }
+                Token::LBrace => depth = 1,
+                _ => {}
+            }
+        }
+        while let Some(tok) = self.peek() {
+            match tok {
+                Token::Semi if depth == 0 => {
+                    self.next();
+                    return;
+                }
+                Token::LBrace => depth += 1,
+                Token::RBrace if depth == 0 => return,
+                Token::RBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.next();
+                        return;
+                    }
+                }
+                _ => {}
+            }
+            self.next();
+        }
+    }
+
+    /// Consumes the closing `}` of a block and returns false once the block
+    /// ends; a missing `}` is reported at the block's keyword.
+    fn block_continues(&mut self, keyword: usize, block: &str) -> bool {
+        match self.peek() {
+            Some(Token::RBrace) => {
+                self.next();
+                false
+            }
+            None => {
+                self.error_at(keyword, format!("Unexpected EOF in {block} block, missing }}"));
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn error_at(&mut self, pos: usize, message: String) {
+        let span = self.spans.get(pos).or(self.spans.last()).copied();
+        self.ctx.error(span, message);
+    }
+
     // token helpers
     fn peek(&self) -> Option<&Token> { self.tokens.get(self.pos) }
     fn next(&mut self) -> Option<Token> {
@@ -340,26 +1094,51 @@ impl<'a> Parser<'a> {
         if std::mem::discriminant(&got) == std::mem::discriminant(&want) {
             Ok(())
         } else {
-            Err(format!("Expected {:?}, got {:?}", want, got))
+            Err(format!("Expected {want}, got {got}"))
         }
     }
     fn expect_ident(&mut self) -> Result<String, String> {
         match self.next() {
             Some(Token::Ident(s)) => Ok(s),
-            other => Err(format!("Expected identifier, got {:?}", other)),
+            other => Err(format!("Expected identifier, got {}", describe(other.as_ref()))),
+        }
+    }
+    fn expect_on_off(&mut self, directive: &str) -> Result<bool, String> {
+        match self.expect_ident()?.to_lowercase().as_str() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(format!("{directive} expects on|off")),
         }
     }
     fn expect_stringish(&mut self) -> Result<String, String> {
         match self.next() {
             Some(Token::Ident(s)) => Ok(s),
             Some(Token::StringLit(s)) => Ok(s),
-            other => Err(format!("Expected string, got {:?}", other)),
+            other => Err(format!("Expected string, got {}", describe(other.as_ref()))),
         }
     }
+    /// A string, or a number kept as text (`set $limit 10;`).
+    fn expect_value(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(s)) | Some(Token::StringLit(s)) => Ok(s),
+            Some(Token::Number(n)) => Ok(n.to_string()),
+            other => Err(format!("Expected value, got {}", describe(other.as_ref()))),
+        }
+    }
+    /// A byte size such as `2000`, `512k` or `20M`.
+    fn expect_size(&mut self, directive: &str) -> Result<u64, String> {
+        let value = self.expect_value()?;
+        units::parse_size(&value).map_err(|e| format!("{directive}: {e}"))
+    }
+    /// A duration such as `75` (seconds), `500ms`, `30s` or `1h`.
+    fn expect_duration(&mut self, directive: &str) -> Result<Duration, String> {
+        let value = self.expect_value()?;
+        units::parse_duration(&value).map_err(|e| format!("{directive}: {e}"))
+    }
     fn expect_number_u64(&mut self) -> Result<u64, String> {
         match self.next() {
             Some(Token::Number(n)) => Ok(n),
-            other => Err(format!("Expected number, got {:?}", other)),
+            other => Err(format!("Expected number, got {}", describe(other.as_ref()))),
         }
     }
     fn expect_number_u16(&mut self) -> Result<u16, String> {
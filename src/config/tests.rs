@@ -1,7 +1,9 @@
 #[cfg(test)]
 mod tests {
     use super::super::parser::parse_config;
+    use super::super::ast::{AccessSource, AuthBasic, AuthRequestSet, AutoindexFormat, ErrorLog, LogTemplate, RewriteFlag, RewriteOp, StatusPage};
     use std::path::Path;
+    use std::time::Duration;
 
     #[test]
     fn test_simple_config() {
@@ -13,7 +15,7 @@ mod tests {
                 index index.html;
             }
         "#;
-        let config = parse_config(config_str, Path::new(".")).unwrap();
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
         assert_eq!(config.servers.len(), 1);
         let s = &config.servers[0];
         assert_eq!(s.listen[0].port(), 8080);
@@ -30,7 +32,7 @@ mod tests {
                 listen 9000;
             }
         "#;
-        let config = parse_config(config_str, Path::new(".")).unwrap();
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
         assert_eq!(config.servers.len(), 2);
     }
 
@@ -42,7 +44,7 @@ mod tests {
                 listen 8080; # Inline comment
             }
         "#;
-        let config = parse_config(config_str, Path::new(".")).unwrap();
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
         assert_eq!(config.servers.len(), 1);
     }
 
@@ -58,11 +60,725 @@ mod tests {
                 }
             }
         "#;
-        let config = parse_config(config_str, Path::new(".")).unwrap();
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
         let s = &config.servers[0];
         assert_eq!(s.locations.len(), 1);
         let loc = &s.locations[0];
         assert_eq!(loc.path, "/");
-        assert_eq!(loc.autoindex, Some(true));
+        assert_eq!(loc.directives.autoindex, Some(true));
+    }
+
+    #[test]
+    fn test_autoindex_options() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                location /files {
+                    autoindex on;
+                    autoindex_format json;
+                    autoindex_exact_size on;
+                    autoindex_localtime on;
+                }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let loc = &config.servers[0].locations[0];
+        assert_eq!(loc.directives.autoindex_format, Some(AutoindexFormat::Json));
+        assert_eq!(loc.directives.autoindex_exact_size, Some(true));
+        assert_eq!(loc.directives.autoindex_localtime, Some(true));
+        assert_eq!(loc.directives.autoindex_hidden, None);
+
+        let bad = r#"server { listen 8080; location / { autoindex_format yaml; } }"#;
+        assert!(parse_config(bad, Path::new("."), "test.conf").is_err());
+    }
+
+    #[test]
+    fn test_try_files_and_named_location() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                location / {
+                    try_files $uri $uri/ @app;
+                }
+                location @app {
+                    cgi .py /usr/bin/python3;
+                }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let s = &config.servers[0];
+        let root = s.find_location("/spa/route").unwrap();
+        assert_eq!(root.try_files.as_deref(), Some(&["$uri".to_string(), "$uri/".into(), "@app".into()][..]));
+        assert!(s.find_named_location("@app").unwrap().cgi.is_some());
+        // Named locations never match request paths
+        assert!(s.find_location("@app").is_none());
+
+        let bad = r#"server { listen 8080; location / { try_files $uri =999; } }"#;
+        assert!(parse_config(bad, Path::new("."), "test.conf").is_err());
+    }
+
+    #[test]
+    fn test_location_modifiers_precedence() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                location / { root /www; }
+                location /uploads { root /up; }
+                location = /exact { root /exact; }
+                location ^~ /static/ { root /static; }
+                location ~* \.(png|jpg)$ { root /img/$1; }
+                location ~ \.py$ { cgi .py /usr/bin/python3; }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let s = &config.servers[0];
+        let root_of = |p: &str| s.find_location(p).unwrap().root.clone();
+
+        assert_eq!(root_of("/exact"), Path::new("/exact"));
+        assert_eq!(root_of("/uploads/a.txt"), Path::new("/up"));
+        // Prefixes only match on segment boundaries
+        assert_eq!(root_of("/uploadsXYZ"), Path::new("/www"));
+        // ^~ stops the regex search, a plain prefix does not
+        assert_eq!(root_of("/static/logo.png"), Path::new("/static"));
+        assert_eq!(root_of("/uploads/logo.PNG"), Path::new("/img/PNG"));
+        assert!(s.find_location("/any/where/run.py?x=1").unwrap().cgi.is_some());
+
+        let bad = r#"server { listen 8080; location ~ "(" { } }"#;
+        assert!(parse_config(bad, Path::new("."), "test.conf").is_err());
+    }
+
+    #[test]
+    fn test_nested_location_inheritance() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                client_max_body_size 1000;
+                error_page 404 /errors/404.html;
+                location /files {
+                    root /srv/files;
+                    autoindex on;
+                    methods GET;
+                    error_page 403 /errors/403.html;
+                    location /files/private {
+                        methods GET DELETE;
+                        autoindex off;
+                    }
+                    location ~ \.txt$ {
+                        client_max_body_size 10;
+                    }
+                }
+                root /www;
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let s = &config.servers[0];
+        assert_eq!(s.effective.root, Path::new("/www"));
+
+        let files = s.find_location("/files/a").unwrap();
+        assert_eq!(files.client_max_body_size, 1000);
+        assert!(files.autoindex.is_some());
+        assert_eq!(files.strip_prefix.as_deref(), Some("/files"));
+        let codes: Vec<u16> = files.errors.iter().map(|e| e.code).collect();
+        assert_eq!(codes, vec![403, 404]);
+
+        let private = s.find_location("/files/private/x").unwrap();
+        assert_eq!(private.root, Path::new("/srv/files"));
+        assert_eq!(private.strip_prefix.as_deref(), Some("/files"));
+        assert_eq!(private.methods.as_ref().map(|m| m.len()), Some(2));
+        assert!(private.autoindex.is_none());
+
+        let txt = s.find_location("/files/notes.txt").unwrap();
+        assert_eq!(txt.client_max_body_size, 10);
+        assert_eq!(txt.root, Path::new("/srv/files"));
+
+        let bad = r#"server { listen 8080; location / { location @named { } } }"#;
+        assert!(parse_config(bad, Path::new("."), "test.conf").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_and_return() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                rewrite ^/old/(.*)$ /new/$1 permanent;
+                location /blog {
+                    rewrite ^/blog/(\d+)$ /post?id=$1 last;
+                    rewrite ^/blog/draft$ /blog;
+                    return 404;
+                }
+                location /moved {
+                    return https://example.com$uri;
+                }
+                location ~ ^/cap/(\w+)$ {
+                    return 200 "got $1";
+                }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let s = &config.servers[0];
+        assert_eq!(s.directives.rewrites.len(), 1);
+        // Server rules run before the lookup, they are not repeated per location
+        assert!(s.effective.rewrites.is_empty());
+
+        let blog = s.find_location("/blog/1").unwrap();
+        assert!(matches!(
+            &blog.rewrites[0],
+            RewriteOp::Rewrite { replacement, flag: Some(RewriteFlag::Last), .. } if replacement == "/post?id=$1"
+        ));
+        assert!(matches!(&blog.rewrites[1], RewriteOp::Rewrite { flag: None, .. }));
+        assert!(matches!(&blog.rewrites[2], RewriteOp::Return { code: 404, target: None }));
+
+        let moved = s.find_location("/moved").unwrap();
+        assert!(matches!(&moved.rewrites[0], RewriteOp::Return { code: 302, target: Some(t) } if t == "https://example.com$uri"));
+
+        let cap = s.find_location("/cap/abc").unwrap();
+        assert!(matches!(&cap.rewrites[0], RewriteOp::Return { code: 200, target: Some(t) } if t == "got abc"));
+
+        for bad in [
+            r#"server { listen 8080; rewrite ^/a /b forever; }"#,
+            r#"server { listen 8080; rewrite "(" /b; }"#,
+            r#"server { listen 8080; return 299; }"#,
+            r#"server { listen 8080; return 301; }"#,
+        ] {
+            assert!(parse_config(bad, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_variables_and_env() {
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("LOCALHOST_TEST_PORT", "8086") };
+        let config_str = r#"
+            server {
+                listen ${LOCALHOST_TEST_PORT};
+                client_max_body_size ${LOCALHOST_TEST_PORT};
+                set $site main;
+                add_header X-Site $site;
+                location /api {
+                    set $limit 10;
+                    add_header X-Api "$host$uri" always;
+                }
+                location /static { }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let s = &config.servers[0];
+        assert_eq!(s.listen[0].port(), 8086);
+        assert_eq!(s.effective.client_max_body_size, 8086);
+        assert!(matches!(&s.directives.rewrites[0], RewriteOp::Set { name, value } if name == "site" && value == "main"));
+
+        // add_header is inherited only by blocks that declare none
+        let api = s.find_location("/api").unwrap();
+        assert_eq!(api.add_headers.len(), 1);
+        assert_eq!(api.add_headers[0].value, "$host$uri");
+        assert!(api.add_headers[0].always);
+        assert!(matches!(&api.rewrites[0], RewriteOp::Set { value, .. } if value == "10"));
+        let stat = s.find_location("/static").unwrap();
+        assert_eq!(stat.add_headers[0].name, "X-Site");
+
+        for bad in [
+            r#"server { listen ${LOCALHOST_TEST_UNSET_VAR}; }"#,
+            r#"server { listen 8080; set $uri /x; }"#,
+            r#"server { listen 8080; set $http_host x; }"#,
+            r#"server { listen 8080; set name x; }"#,
+            r#"server { listen 8080; add_header X-A b sometimes; }"#,
+        ] {
+            assert!(parse_config(bad, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_http_block_defaults() {
+        let config_str = r#"
+            http {
+                client_max_body_size 4096;
+                keep_alive_timeout 30;
+                error_page 404 /errors/404.html;
+                location /shared { autoindex on; }
+                location /old { redirect "/"; }
+
+                server {
+                    listen 8080;
+                    location /old { redirect "/elsewhere"; }
+                }
+                server {
+                    listen 8081;
+                    client_max_body_size 10;
+                    keep_alive_timeout 5;
+                    error_page 404 /custom404.html;
+                }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        assert_eq!(config.servers.len(), 2);
+        let (a, b) = (&config.servers[0], &config.servers[1]);
+
+        assert_eq!(a.effective.client_max_body_size, 4096);
+        assert_eq!(a.keep_alive_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(a.effective.errors[0].path, "/errors/404.html");
+        assert!(a.find_location("/shared/x").unwrap().autoindex.is_some());
+        // The server's own location wins over the shared one
+        assert_eq!(a.find_location("/old").unwrap().redirect.as_deref(), Some("/elsewhere"));
+        assert_eq!(a.locations.len(), 2);
+
+        assert_eq!(b.effective.client_max_body_size, 10);
+        assert_eq!(b.keep_alive_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(b.effective.errors[0].path, "/custom404.html");
+        // Shared locations inherit from the server they are added to
+        assert_eq!(b.find_location("/shared").unwrap().client_max_body_size, 10);
+        assert_eq!(b.find_location("/old").unwrap().redirect.as_deref(), Some("/"));
+
+        for bad in [
+            r#"http { } http { } server { listen 8080; }"#,
+            r#"http { return 404; server { listen 8080; } }"#,
+            r#"http { listen 8080; }"#,
+        ] {
+            assert!(parse_config(bad, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_include_glob_and_cycles() {
+        let dir = std::env::temp_dir().join(format!("localhost-include-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        std::fs::write(dir.join("conf.d/b.conf"), "server { listen 8082; }").unwrap();
+        std::fs::write(dir.join("conf.d/a.conf"), "server { listen 8081; include common.inc; }").unwrap();
+        std::fs::write(dir.join("conf.d/.hidden.conf"), "server { listen 8089; }").unwrap();
+        std::fs::write(dir.join("conf.d/notes.txt"), "not config").unwrap();
+        std::fs::write(dir.join("common.inc"), "error_page 404 /404.html;").unwrap();
+
+        let config = parse_config("server { listen 8080; } include conf.d/*.conf;", &dir, "test.conf").unwrap();
+        let ports: Vec<u16> = config.servers.iter().map(|s| s.listen[0].port()).collect();
+        assert_eq!(ports, vec![8080, 8081, 8082]);
+        assert_eq!(config.servers[1].effective.errors[0].path, "/404.html");
+
+        // A wildcard matching nothing is fine, a missing file is not
+        assert!(parse_config("server { listen 8080; } include none/*.conf;", &dir, "test.conf").is_ok());
+        assert!(parse_config("server { listen 8080; } include missing.conf;", &dir, "test.conf").is_err());
+
+        std::fs::write(dir.join("loop1.conf"), "include loop2.conf;").unwrap();
+        std::fs::write(dir.join("loop2.conf"), "include loop1.conf;").unwrap();
+        let err = parse_config("include loop1.conf;", &dir, "test.conf").unwrap_err();
+        assert!(err.contains("include cycle"), "{err}");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_size_and_duration_units() {
+        let parse = |body: &str| parse_config(&format!("server {{ listen 8080; {body} }}"), Path::new("."), "test.conf");
+        let size = |v: &str| parse(&format!("client_max_body_size {v};")).map(|c| c.servers[0].effective.client_max_body_size);
+        let timeout = |v: &str| parse(&format!("keep_alive_timeout {v};")).map(|c| c.servers[0].keep_alive_timeout.unwrap());
+
+        assert_eq!(size("2000"), Ok(2000));
+        assert_eq!(size("512k"), Ok(512 * 1024));
+        assert_eq!(size("20M"), Ok(20 * 1024 * 1024));
+        assert_eq!(size("1g"), Ok(1024 * 1024 * 1024));
+        assert_eq!(timeout("75"), Ok(Duration::from_secs(75)));
+        assert_eq!(timeout("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(timeout("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(timeout("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(timeout("1m30s"), Ok(Duration::from_secs(90)));
+
+        let err = size("10X").unwrap_err();
+        assert!(err.contains("client_max_body_size:") && err.contains("expected k, m or g"), "{err}");
+        let err = timeout("5x").unwrap_err();
+        assert!(err.contains("keep_alive_timeout:") && err.contains("expected ms, s, m, h or d"), "{err}");
+        assert!(timeout("1m30").unwrap_err().contains("missing time unit"));
+        assert!(size("M").unwrap_err().contains("expected a number"));
+        assert!(size("99999999999G").unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn test_error_positions_and_suggestions() {
+        let config_str = "server {\n    listen 8080;\n    rot /www;\n    autoindex maybe;\n}\nserver {\n    server_name x;\n}\n";
+        let err = parse_config(config_str, Path::new("."), "site.conf").unwrap_err();
+        // Every error is reported, each with its position and the source line
+        assert!(err.contains("site.conf:3:5: Unknown directive \"rot\" in server, did you mean \"root\"?"), "{err}");
+        assert!(err.contains("3 |     rot /www;\n  |     ^^^"), "{err}");
+        assert!(err.contains("site.conf:4:15: autoindex expects on|off"), "{err}");
+        assert!(err.contains("site.conf:6:1: Server missing listen directive"), "{err}");
+
+        let err = parse_config("server { listen 8080; frobnicate 1; }", Path::new("."), "test.conf").unwrap_err();
+        assert!(err.contains("Unknown directive \"frobnicate\" in server\n"), "{err}");
+        let err = parse_config("server { listen 8080; root /www }", Path::new("."), "test.conf").unwrap_err();
+        assert!(err.contains("test.conf:1:33: Expected ';', got '}'"), "{err}");
+    }
+
+    #[test]
+    fn test_pid_and_dump_round_trip() {
+        let config_str = r#"
+            pid /run/localhost.pid;
+            shutdown_timeout 30s;
+            worker_processes 4;
+            worker_threads 2;
+            http {
+                client_max_body_size 2M;
+                add_header X-Served-By "local host";
+                server {
+                    listen 8080;
+                    server_name a.test b.test;
+                    keep_alive_timeout 1500ms;
+                    rewrite "^/old/(\d{2})$" /new/$1 last;
+                    location ~* \.(png|jpg)$ { methods GET; return 403; }
+                    location /files {
+                        root /srv/files;
+                        autoindex on;
+                        autoindex_format json;
+                        location /files/private { try_files $uri =404; }
+                    }
+                }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        assert_eq!(config.pid, Path::new("/run/localhost.pid"));
+        let default_pid = parse_config("server { listen 8080; }", Path::new("/etc/localhost"), "test.conf").unwrap();
+        assert_eq!(default_pid.pid, Path::new("/etc/localhost/localhost.pid"));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(default_pid.shutdown_timeout, Duration::from_secs(10));
+        assert_eq!(config.worker_processes, 4);
+        assert_eq!(default_pid.worker_processes, 1);
+        assert_eq!(config.worker_threads, Some(2));
+        assert_eq!(default_pid.worker_threads, None);
+        let auto = parse_config("worker_processes auto; server { listen 8080; }", Path::new("."), "test.conf").unwrap();
+        assert!(auto.worker_processes >= 1);
+        for bad in ["worker_processes 0;", "worker_processes 1000;", "worker_processes some;", "worker_threads 0;"] {
+            assert!(parse_config(bad, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+
+        // The dump parses back to the same resolved settings
+        let dumped = super::super::dump::dump(&config);
+        assert!(dumped.contains("client_max_body_size 2m;"), "{dumped}");
+        assert!(dumped.contains("add_header X-Served-By \"local host\";"), "{dumped}");
+        let again = parse_config(&dumped, Path::new(""), "dump.conf").unwrap_or_else(|e| panic!("{e}\n{dumped}"));
+        assert_eq!(super::super::dump::dump(&again), dumped);
+        let srv = &again.servers[0];
+        assert_eq!(srv.keep_alive_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(srv.directives.rewrites.len(), 1);
+        let private = srv.find_location("/files/private/x").unwrap();
+        assert_eq!(private.root, Path::new("/srv/files"));
+        assert_eq!(private.autoindex.as_ref().unwrap().format, AutoindexFormat::Json);
+        assert_eq!(private.client_max_body_size, 2 * 1024 * 1024);
+        assert!(matches!(srv.find_location("/a.PNG").unwrap().rewrites[..], [RewriteOp::Return { code: 403, .. }]));
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        // Each config, dumped, parses back to the same dump, which has the given lines
+        let cases: &[(&str, &[&str])] = &[
+            (
+                r#"log_format short '$remote_addr "$request"' " $status";
+                   server { listen 8080; access_log /var/log/a.log; location /api { access_log /var/log/api.log short; access_log /var/log/api.json json; } location /quiet { access_log off; } }"#,
+                // Formats with double quotes are dumped in single quotes
+                &["log_format short '$remote_addr \"$request\" $status';", "access_log /var/log/api.json json;", "access_log off;"],
+            ),
+            (
+                "error_log /var/log/error.log warn; http { error_log /var/log/http.log; server { listen 8080; } server { listen 8081; error_log stderr debug; } }",
+                &["error_log /var/log/error.log warn;", "error_log /var/log/http.log notice;", "error_log stderr debug;"],
+            ),
+            ("server { listen 8080; location = /metrics { metrics; methods GET; } }", &["metrics;"]),
+            (
+                "server { listen 8080; location = /status { status; } location = /status/full { status connections; } }",
+                &["status;", "status connections;"],
+            ),
+            (
+                r#"server { listen 8080; auth_basic "Members only"; auth_basic_user_file /etc/htpasswd; location /public { auth_basic off; } location /admin { auth_basic Admins; auth_basic_user_file /etc/admins; } }"#,
+                &["auth_basic \"Members only\";", "auth_basic_user_file /etc/admins;", "auth_basic off;"],
+            ),
+            (
+                "server { listen 8080; auth_request /auth; auth_request_set $user $upstream_http_x_user; location /auth { auth_request off; } }",
+                &["auth_request /auth;", "auth_request_set $user $upstream_http_x_user;", "auth_request off;"],
+            ),
+            (
+                "geo office /dev/null; server { listen 8080; allow 10.0.0.0/8; deny 10.1.2.3; allow office; deny all; }",
+                &["geo office /dev/null;", "allow 10.0.0.0/8;", "deny 10.1.2.3/32;", "allow office;", "deny all;"],
+            ),
+        ];
+        for (config_str, expected) in cases {
+            let config = parse_config(config_str, Path::new("."), "test.conf").unwrap_or_else(|e| panic!("{e}\n{config_str}"));
+            let dumped = super::super::dump::dump(&config);
+            for line in *expected {
+                assert!(dumped.contains(line), "{line}\n{dumped}");
+            }
+            let again = parse_config(&dumped, Path::new(""), "dump.conf").unwrap_or_else(|e| panic!("{e}\n{dumped}"));
+            assert_eq!(super::super::dump::dump(&again), dumped);
+        }
+    }
+
+    #[test]
+    fn test_access_log() {
+        let config_str = r#"
+            log_format short '$remote_addr "$request" '
+                             "$status $request_time";
+            http {
+                access_log /var/log/localhost/access.log;
+                server {
+                    listen 8080;
+                    location /quiet { access_log off; }
+                    location /api {
+                        access_log /var/log/localhost/api.log short;
+                        access_log /var/log/localhost/api-common.log common;
+                        access_log /var/log/localhost/api.json json;
+                    }
+                }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        assert_eq!(config.log_formats[0].template, "$remote_addr \"$request\" $status $request_time");
+        let srv = &config.servers[0];
+        assert_eq!(srv.effective.access_logs.len(), 1);
+        assert_eq!(srv.effective.access_logs[0].format, "combined");
+        assert!(srv.find_location("/quiet").unwrap().access_logs.is_empty());
+        let api = srv.find_location("/api/x").unwrap();
+        let formats: Vec<&str> = api.access_logs.iter().map(|l| l.format.as_str()).collect();
+        assert_eq!(formats, ["short", "common", "json"]);
+        assert!(matches!(api.access_logs[2].template, LogTemplate::Json));
+        assert_eq!(api.location, "/api");
+
+        // Any request variable, including one a later `set` assigns
+        let config_str = "log_format vars '$arg_q $cookie_sid $1 $user'; server { listen 8080; set $user x; }";
+        assert!(parse_config(config_str, Path::new("."), "test.conf").is_ok());
+
+        for bad in [
+            "server { listen 8080; access_log /tmp/a.log missing; }",
+            "log_format combined '$status'; server { listen 8080; }",
+            "log_format json '$status'; server { listen 8080; }",
+            "log_format x '$nope'; server { listen 8080; }",
+            "log_format x '$0'; server { listen 8080; }",
+            "server { listen 8080; access_log /tmp/a.log later; } log_format later '$status';",
+        ] {
+            assert!(parse_config(bad, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_error_log() {
+        use crate::application::log::error::Level;
+
+        let config_str = r#"
+            error_log /var/log/localhost/error.log warn;
+            http {
+                error_log /var/log/localhost/http.log;
+                server { listen 8080; }
+                server { listen 8081; error_log stderr debug; }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        assert_eq!(config.error_log.path.as_deref(), Some(Path::new("/var/log/localhost/error.log")));
+        assert_eq!(config.error_log.level, Level::Warn);
+        let http = config.servers[0].error_log.as_ref().unwrap();
+        assert_eq!(http.path.as_deref(), Some(Path::new("/var/log/localhost/http.log")));
+        assert_eq!(http.level, Level::Notice);
+        assert_eq!(config.servers[1].error_log, Some(ErrorLog { path: None, level: Level::Debug }));
+
+        let default = parse_config("server { listen 8080; }", Path::new("."), "test.conf").unwrap();
+        assert_eq!(default.error_log, ErrorLog { path: None, level: Level::Notice });
+        for bad in ["error_log /tmp/e.log loud; server { listen 8080; }", "error_log; server { listen 8080; }"] {
+            assert!(parse_config(bad, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_metrics_location() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                location = /metrics {
+                    metrics;
+                    methods GET;
+                }
+                location /app {
+                    location /app/inner { root /srv; }
+                }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let srv = &config.servers[0];
+        assert!(srv.find_location("/metrics").unwrap().metrics);
+        assert!(!srv.find_location("/app/inner").unwrap().metrics);
+        assert!(!srv.effective.metrics);
+        let err = parse_config("server { listen 8080; metrics; }", Path::new("."), "test.conf").unwrap_err();
+        assert!(err.contains("Unknown directive \"metrics\""), "{err}");
+    }
+
+    #[test]
+    fn test_status_location() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                location = /status { status; }
+                location = /status/full { status connections; }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let srv = &config.servers[0];
+        assert_eq!(srv.find_location("/status").unwrap().status, Some(StatusPage { connections: false }));
+        assert_eq!(srv.find_location("/status/full").unwrap().status, Some(StatusPage { connections: true }));
+        assert!(parse_config("server { listen 8080; location / { status all; } }", Path::new("."), "test.conf").is_err());
+    }
+
+    #[test]
+    fn test_auth_basic() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                auth_basic "Members only";
+                auth_basic_user_file conf/htpasswd;
+                location /files { }
+                location /public { auth_basic off; }
+                location /admin { auth_basic Admins; auth_basic_user_file /etc/admins; }
+            }
+            server { listen 8081; auth_basic Nobody; }
+        "#;
+        let config = parse_config(config_str, Path::new("/srv"), "test.conf").unwrap();
+        let srv = &config.servers[0];
+        let members = AuthBasic { realm: "Members only".into(), user_file: Some("/srv/conf/htpasswd".into()) };
+        assert_eq!(srv.effective.auth_basic, Some(members.clone()));
+        assert_eq!(srv.find_location("/files/a").unwrap().auth_basic, Some(members));
+        assert_eq!(srv.find_location("/public").unwrap().auth_basic, None);
+        let admins = AuthBasic { realm: "Admins".into(), user_file: Some("/etc/admins".into()) };
+        assert_eq!(srv.find_location("/admin").unwrap().auth_basic, Some(admins));
+
+        let problems = super::super::validate::validate(&config).join("\n");
+        assert!(problems.contains("auth_basic \"Nobody\" has no auth_basic_user_file (in server 0.0.0.0:8081)"), "{problems}");
+        assert!(problems.contains("auth_basic_user_file \"/srv/conf/htpasswd\" cannot be read"), "{problems}");
+    }
+
+    #[test]
+    fn test_auth_request() {
+        let config_str = r#"
+            server {
+                listen 8080;
+                auth_request /auth;
+                auth_request_set $user $upstream_http_x_user;
+                location /app { }
+                location /auth { auth_request off; }
+                location ~ ^/team/(\w+) { auth_request_set $team $1; }
+            }
+        "#;
+        let config = parse_config(config_str, Path::new("."), "test.conf").unwrap();
+        let srv = &config.servers[0];
+        let app = srv.find_location("/app").unwrap();
+        assert_eq!(app.auth_request.as_deref(), Some("/auth"));
+        assert_eq!(app.auth_request_set, vec![AuthRequestSet { name: "user".into(), value: "$upstream_http_x_user".into() }]);
+        assert_eq!(srv.find_location("/auth").unwrap().auth_request, None);
+        let team = srv.find_location("/team/red").unwrap();
+        assert_eq!(team.auth_request.as_deref(), Some("/auth"));
+        assert_eq!(team.auth_request_set, vec![AuthRequestSet { name: "team".into(), value: "red".into() }]);
+
+        for bad in ["auth_request auth;", "auth_request_set user x;", "auth_request_set $upstream_http_x_user x;"] {
+            let config_str = format!("server {{ listen 8080; {bad} }}");
+            assert!(parse_config(&config_str, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_access_rules() {
+        let dir = std::env::temp_dir().join(format!("localhost-geo-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("office"), "# office networks\n192.168.1.0/24\n\n2001:db8::/32;  # v6\n").unwrap();
+        std::fs::write(dir.join("broken"), "10.0.0.0/8\n10.0.0.0/33\n").unwrap();
+
+        let config_str = format!(r#"
+            geo office {}/office;
+            server {{
+                listen 8080;
+                allow 10.0.0.0/8;
+                allow office;
+                deny all;
+                location / {{ }}
+                location /open {{ allow all; }}
+                location /inner {{ }}
+                location /host {{ deny 10.1.2.3; allow ::1; }}
+            }}
+        "#, dir.display());
+        let config = parse_config(&config_str, Path::new("."), "test.conf").unwrap();
+        let srv = &config.servers[0];
+        let allowed = |path: &str, addr: &str| {
+            let loc = srv.find_location(path).unwrap();
+            let addr = addr.parse().unwrap();
+            loc.access_rules.iter().find(|r| r.matches(addr)).is_none_or(|r| r.allow)
+        };
+        assert!(allowed("/", "10.200.0.1"));
+        assert!(allowed("/inner", "192.168.1.77"));
+        assert!(allowed("/inner", "2001:db8::1"));
+        assert!(allowed("/inner", "::ffff:10.0.0.1"));
+        assert!(!allowed("/inner", "192.168.2.1"));
+        assert!(!allowed("/", "11.0.0.1"));
+        assert!(allowed("/open", "11.0.0.1"));
+        assert!(!allowed("/host", "10.1.2.3"));
+        assert!(allowed("/host", "10.1.2.4"));
+        assert!(allowed("/host", "::1"));
+        assert!(matches!(&srv.effective.access_rules[1].source, AccessSource::Geo { name, networks } if name == "office" && networks.len() == 2));
+
+        for bad in ["allow 10.0.0.0/33;", "deny 10.0.0;", "allow nowhere;", "deny 10.0.0.0/;"] {
+            let config_str = format!("server {{ listen 8080; {bad} }}");
+            assert!(parse_config(&config_str, Path::new("."), "test.conf").is_err(), "{bad}");
+        }
+        let err = parse_config(&format!("geo broken {}/broken; server {{ listen 8080; }}", dir.display()), Path::new("."), "test.conf").unwrap_err();
+        assert!(err.to_string().contains("broken:2: invalid network 10.0.0.0/33"), "{err}");
+        let err = parse_config("server { listen 8080; allow office; } geo office /dev/null;", Path::new("."), "test.conf").unwrap_err();
+        assert!(err.to_string().contains("got office"), "{err}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_semantic_validation() {
+        use super::super::validate::validate;
+        let dir = std::env::temp_dir().join(format!("localhost-validate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("www/errors")).unwrap();
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        std::fs::write(dir.join("www/errors/404.html"), "missing").unwrap();
+        std::fs::write(dir.join("interpreter"), "#!/bin/sh").unwrap();
+
+        let good = parse_config(r#"
+            http {
+                root www;
+                error_page 404 /errors/404.html;
+                server {
+                    listen 8080;
+                    server_name a;
+                    location /files { root files; }
+                    location /old { redirect "/new"; }
+                }
+                server { listen 8080; server_name b; }
+            }
+        "#, &dir, "test.conf").unwrap();
+        assert!(validate(&good).is_empty(), "{:?}", validate(&good));
+
+        let bad = r#"
+            server {
+                listen 8080;
+                root www;
+                error_page 404 /errors/missing.html;
+                error_page 200 /errors/404.html;
+                location /gone { root nowhere; }
+                location /cgi { cgi .py INTERPRETER; }
+                location /cgi { }
+                location /a { redirect "/b"; }
+                location /b { return 302 /a; }
+            }
+            server { listen 8080; root www; }
+        "#;
+        let interpreter = dir.join("interpreter").display().to_string();
+        let bad = parse_config(&bad.replace("INTERPRETER", &interpreter), &dir, "test.conf").unwrap();
+        let problems = validate(&bad).join("\n");
+        for expected in [
+            "conflicting default servers on 0.0.0.0:8080",
+            "errors/missing.html\" not found",
+            "error_page code 200 must be between 300 and 599",
+            "nowhere\" does not exist (in server 0.0.0.0:8080, location /gone)",
+            "interpreter\" is not executable",
+            "duplicate location /cgi",
+            "redirect loop /b -> /a -> /b",
+        ] {
+            assert!(problems.contains(expected), "{expected:?} not in:\n{problems}");
+        }
+        assert_eq!(validate(&bad).len(), 7, "{problems}");
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
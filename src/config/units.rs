@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+/// Parses a byte size: a plain number of bytes, or a number followed by
+/// `k`, `m` or `g` (either case, powers of 1024).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, unit) = split_number(value)?;
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "k" | "K" => 1024,
+        "m" | "M" => 1024 * 1024,
+        "g" | "G" => 1024 * 1024 * 1024,
+        _ => return Err(format!("unknown size unit {unit:?} in {value:?}, expected k, m or g")),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {value:?} is too large"))
+}
+
+/// Parses a duration: a plain number of seconds, or one or more
+/// number-unit pairs with units `ms`, `s`, `m`, `h` and `d`, e.g. `1m30s`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        let secs = value.parse().map_err(|_| format!("duration {value:?} is too large"))?;
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest)?;
+        let unit_len = after.find(|c: char| c.is_ascii_digit()).unwrap_or(after.len());
+        let unit = &after[..unit_len];
+        let part = match unit {
+            "ms" => Some(Duration::from_millis(number)),
+            "s" => Some(Duration::from_secs(number)),
+            "m" => number.checked_mul(60).map(Duration::from_secs),
+            "h" => number.checked_mul(3600).map(Duration::from_secs),
+            "d" => number.checked_mul(86_400).map(Duration::from_secs),
+            "" => return Err(format!("missing time unit after {number} in {value:?}, expected ms, s, m, h or d")),
+            _ => return Err(format!("unknown time unit {unit:?} in {value:?}, expected ms, s, m, h or d")),
+        };
+        total = part
+            .and_then(|p| total.checked_add(p))
+            .ok_or_else(|| format!("duration {value:?} is too large"))?;
+        rest = &after[unit_len..];
+    }
+    Ok(total)
+}
+
+/// Splits the leading decimal number off `value`.
+fn split_number(value: &str) -> Result<(u64, &str), String> {
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    if digits == 0 {
+        return Err(format!("expected a number in {value:?}"));
+    }
+    let number = value[..digits]
+        .parse()
+        .map_err(|_| format!("number in {value:?} is too large"))?;
+    Ok((number, &value[digits..]))
+}
@@ -0,0 +1,251 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+
+use super::ast::*;
+
+/// Checks a resolved config against the filesystem and for settings that
+/// parse but cannot work: missing roots, CGI interpreters and error pages,
+/// access and error logs that cannot be opened, missing or unreadable
+/// auth_basic user files, duplicate locations, servers competing for the
+/// same address and name, error_page codes outside 300-599 and redirect
+/// loops. Returns every problem found, each naming the block it was found in.
+pub fn validate(cfg: &Config) -> Vec<String> {
+    let mut v = Validator { problems: Vec::new(), checked: HashSet::new() };
+    v.default_servers(cfg);
+    v.error_log(&cfg.error_log, "top level");
+    for srv in &cfg.servers {
+        let block = server_label(srv);
+        if let Some(log) = &srv.error_log {
+            v.error_log(log, &block);
+        }
+        v.settings(&srv.effective, None, &block);
+        v.duplicate_locations(&srv.locations, &block);
+        for loc in &srv.locations {
+            v.location(loc, &srv.effective, &block);
+        }
+        v.redirect_loops(srv, &block);
+    }
+    v.problems
+}
+
+struct Validator {
+    problems: Vec<String>,
+    /// Paths already checked, so a root shared by many blocks is reported once
+    checked: HashSet<String>,
+}
+
+impl Validator {
+    fn report(&mut self, message: String) {
+        if !self.problems.contains(&message) {
+            self.problems.push(message);
+        }
+    }
+
+    fn error_log(&mut self, log: &ErrorLog, block: &str) {
+        let Some(path) = &log.path else { return };
+        let shown = path.to_string_lossy();
+        if self.checked.insert(format!("error_log {shown}"))
+            && let Err(err) = fs::OpenOptions::new().append(true).create(true).open(path)
+        {
+            self.report(format!("error_log {shown:?} cannot be opened: {err} (in {block})"));
+        }
+    }
+
+    fn location(&mut self, loc: &Location, parent: &EffectiveLocation, parent_block: &str) {
+        let block = format!("{parent_block}, location {}", loc.path);
+        self.settings(&loc.effective, Some(parent), &block);
+        self.duplicate_locations(&loc.locations, &block);
+        for child in &loc.locations {
+            self.location(child, &loc.effective, &block);
+        }
+    }
+
+    fn settings(&mut self, e: &EffectiveLocation, parent: Option<&EffectiveLocation>, block: &str) {
+        let root = e.root.to_string_lossy();
+        // Roots with regex captures are only known per request
+        if !root.contains('$') && self.checked.insert(format!("root {root}")) {
+            match fs::metadata(&e.root) {
+                Err(_) => self.report(format!("root {root:?} does not exist (in {block})")),
+                Ok(meta) if !meta.is_dir() => self.report(format!("root {root:?} is not a directory (in {block})")),
+                Ok(_) => {
+                    if let Err(err) = fs::read_dir(&e.root) {
+                        self.report(format!("root {root:?} is not readable: {err} (in {block})"));
+                    }
+                }
+            }
+        }
+
+        if let Some(cgi) = &e.cgi {
+            let interpreter = cgi.interpreter.to_string_lossy();
+            if !interpreter.contains('$') && self.checked.insert(format!("cgi {interpreter}")) {
+                match fs::metadata(&cgi.interpreter) {
+                    Err(_) => self.report(format!("CGI interpreter {interpreter:?} not found (in {block})")),
+                    Ok(meta) if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 => {
+                        self.report(format!("CGI interpreter {interpreter:?} is not executable (in {block})"));
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
+
+        for log in &e.access_logs {
+            let path = log.path.to_string_lossy();
+            if self.checked.insert(format!("access_log {path}"))
+                && let Err(err) = fs::OpenOptions::new().append(true).create(true).open(&log.path)
+            {
+                self.report(format!("access_log {path:?} cannot be opened: {err} (in {block})"));
+            }
+        }
+
+        if let Some(auth) = &e.auth_basic {
+            match &auth.user_file {
+                None => self.report(format!("auth_basic {:?} has no auth_basic_user_file (in {block})", auth.realm)),
+                Some(file) => {
+                    let shown = file.to_string_lossy();
+                    if self.checked.insert(format!("auth_basic_user_file {shown}"))
+                        && let Err(err) = fs::File::open(file)
+                    {
+                        self.report(format!("auth_basic_user_file {shown:?} cannot be read: {err} (in {block})"));
+                    }
+                }
+            }
+        }
+
+        for page in &e.errors {
+            // Pages are checked in the block that declares them: under another
+            // root an inherited page falls back to the built-in one
+            let inherited = parent.is_some_and(|p| p.errors.iter().any(|pp| pp.code == page.code && pp.path == page.path));
+            if inherited {
+                continue;
+            }
+            if !(300..=599).contains(&page.code) {
+                self.report(format!("error_page code {} must be between 300 and 599 (in {block})", page.code));
+                continue;
+            }
+            // A missing root is reported once instead of once per page
+            if !e.root.is_dir() {
+                continue;
+            }
+            let file = page.file(&e.root);
+            let shown = file.to_string_lossy();
+            if !shown.contains('$') && self.checked.insert(format!("error_page {shown}")) && !file.is_file() {
+                self.report(format!("error_page {} file {shown:?} not found (in {block})", page.code));
+            }
+        }
+    }
+
+    fn duplicate_locations(&mut self, locations: &[Location], block: &str) {
+        let mut seen = HashSet::new();
+        for loc in locations {
+            if !seen.insert((loc.modifier, loc.path.as_str())) {
+                self.report(format!("duplicate location {} (in {block})", loc.path));
+            }
+        }
+    }
+
+    /// Servers sharing an address must differ in name: a second server with the
+    /// same name, or a second unnamed default, is never selected.
+    fn default_servers(&mut self, cfg: &Config) {
+        let mut owners: HashMap<(SocketAddr, String), &Server> = HashMap::new();
+        for srv in &cfg.servers {
+            let names: Vec<String> = if srv.server_names.is_empty() {
+                vec![String::new()]
+            } else {
+                srv.server_names.iter().map(|n| n.to_ascii_lowercase()).collect()
+            };
+            for &addr in &srv.listen {
+                for name in &names {
+                    let Some(first) = owners.get(&(addr, name.clone())) else {
+                        owners.insert((addr, name.clone()), srv);
+                        continue;
+                    };
+                    if name.is_empty() {
+                        self.report(format!(
+                            "conflicting default servers on {addr}: more than one server has no server_name, only the first is used"
+                        ));
+                    } else {
+                        self.report(format!(
+                            "conflicting servers on {addr}: server_name {name:?} is used by {} and {}",
+                            server_label(first),
+                            server_label(srv)
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Follows local redirects (`redirect`, or a `return 3xx` before any
+    /// rewrite) from location to location and reports any that come back
+    /// to a path already visited.
+    fn redirect_loops(&mut self, srv: &Server, block: &str) {
+        let mut reported: HashSet<Vec<String>> = HashSet::new();
+        let mut starts = Vec::new();
+        collect_targets(&srv.locations, &mut starts);
+        if let Some(target) = redirect_target(&srv.effective) {
+            starts.push(target);
+        }
+
+        for start in starts {
+            let mut chain = vec![start];
+            loop {
+                let current = chain.last().unwrap();
+                let next = match srv.find_location(current) {
+                    Some(loc) => redirect_target(&loc),
+                    None => redirect_target(&srv.effective),
+                };
+                let Some(next) = next else {
+                    break;
+                };
+                if let Some(pos) = chain.iter().position(|p| *p == next) {
+                    let mut cycle = chain[pos..].to_vec();
+                    let mut key = cycle.clone();
+                    key.sort();
+                    if reported.insert(key) {
+                        cycle.push(next);
+                        self.report(format!("redirect loop {} (in {block})", cycle.join(" -> ")));
+                    }
+                    break;
+                }
+                chain.push(next);
+            }
+        }
+    }
+}
+
+fn collect_targets(locations: &[Location], out: &mut Vec<String>) {
+    for loc in locations {
+        out.extend(redirect_target(&loc.effective));
+        collect_targets(&loc.locations, out);
+    }
+}
+
+/// The local path a location always redirects to, if any. Targets with
+/// variables or another host are left out.
+fn redirect_target(loc: &EffectiveLocation) -> Option<String> {
+    let returned = loc
+        .rewrites
+        .iter()
+        .take_while(|op| !matches!(op, RewriteOp::Rewrite { .. }))
+        .find_map(|op| match op {
+            RewriteOp::Return { code, target } => Some((*code, target.as_deref())),
+            _ => None,
+        });
+    let target = match returned {
+        Some((300..=399, target)) => target?,
+        Some(_) => return None,
+        None => loc.redirect.as_deref()?,
+    };
+    let path = target.split('?').next().unwrap_or("");
+    (path.starts_with('/') && !path.contains('$')).then(|| path.to_string())
+}
+
+fn server_label(srv: &Server) -> String {
+    let addrs: Vec<String> = srv.listen.iter().map(|a| a.to_string()).collect();
+    match srv.server_names.first() {
+        Some(name) => format!("server {name} ({})", addrs.join(", ")),
+        None => format!("server {}", addrs.join(", ")),
+    }
+}
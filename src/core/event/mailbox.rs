@@ -0,0 +1,109 @@
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use crate::core::net::fd::Fd;
+
+/// Receiving end of a channel into an event loop. Every send also writes to
+/// a pipe the loop polls, so a loop waiting for events wakes up.
+pub struct Mailbox<T> {
+    messages: Receiver<T>,
+    wake: Fd,
+}
+
+pub struct MailboxSender<T> {
+    messages: Sender<T>,
+    wake: Arc<Fd>,
+}
+
+/// Creates a mailbox; register `Mailbox::fd` for reading in its loop.
+pub fn mailbox<T>() -> Result<(MailboxSender<T>, Mailbox<T>), String> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(format!("pipe: {}", io::Error::last_os_error()));
+    }
+    let (read, write) = (Fd(fds[0]), Fd(fds[1]));
+    for fd in fds {
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    let (tx, rx) = mpsc::channel();
+    Ok((MailboxSender { messages: tx, wake: Arc::new(write) }, Mailbox { messages: rx, wake: read }))
+}
+
+impl<T> Mailbox<T> {
+    pub fn fd(&self) -> RawFd {
+        self.wake.0
+    }
+
+    /// Empties the wake-up pipe and returns the messages received so far.
+    pub fn drain(&self) -> Vec<T> {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(self.wake.0, buf.as_mut_ptr() as *mut _, buf.len()) } > 0 {}
+        self.messages.try_iter().collect()
+    }
+}
+
+impl<T> MailboxSender<T> {
+    pub fn send(&self, message: T) {
+        if self.messages.send(message).is_ok() {
+            // A full pipe already has a wake-up pending
+            unsafe { libc::write(self.wake.0, [1u8].as_ptr() as *const _, 1) };
+        }
+    }
+}
+
+impl<T> Clone for MailboxSender<T> {
+    fn clone(&self) -> Self {
+        MailboxSender { messages: self.messages.clone(), wake: Arc::clone(&self.wake) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `fd` polls readable within `ms`.
+    fn readable(fd: RawFd, ms: i32) -> bool {
+        let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut pfd, 1, ms) == 1 }
+    }
+
+    #[test]
+    fn test_send_wakes_the_loop() {
+        let (tx, rx) = mailbox().unwrap();
+        assert!(!readable(rx.fd(), 0));
+        let sender = tx.clone();
+        std::thread::spawn(move || {
+            sender.send(1);
+            sender.send(2);
+        });
+        assert!(readable(rx.fd(), 5000));
+        // The messages may arrive after the first wake-up
+        let mut got = Vec::new();
+        while got.len() < 2 && readable(rx.fd(), 5000) {
+            got.extend(rx.drain());
+        }
+        assert_eq!(got, [1, 2]);
+        assert!(!readable(rx.fd(), 0));
+        assert!(rx.drain().is_empty());
+    }
+
+    #[test]
+    fn test_full_pipe_does_not_block() {
+        let (tx, rx) = mailbox().unwrap();
+        // More sends than the pipe holds bytes
+        for i in 0..200_000 {
+            tx.send(i);
+        }
+        assert_eq!(rx.drain().len(), 200_000);
+        assert!(!readable(rx.fd(), 0));
+        drop(rx);
+        // Sending to a closed mailbox is ignored
+        tx.send(0);
+    }
+}
@@ -1,6 +1,7 @@
 pub mod event;
 pub mod poller;
 pub mod event_loop;
+pub mod mailbox;
 
 pub use event::Event;
 pub use poller::Poller;
@@ -62,7 +62,12 @@ impl Poller {
             )
         };
         if n < 0 {
-            return Err(std::io::Error::last_os_error().to_string());
+            let err = std::io::Error::last_os_error();
+            // A signal arrived while waiting; the caller checks for it next
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(err.to_string());
         }
 
         let mut out = Vec::with_capacity(n as usize);
@@ -1,2 +1,5 @@
 pub mod event;
-pub mod net;
\ No newline at end of file
+pub mod net;
+pub mod pool;
+pub mod signal;
+pub mod workers;
\ No newline at end of file
@@ -2,46 +2,118 @@ use std::time::{Duration, Instant};
 use super::fd::Fd;
 use std::os::fd::AsRawFd;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::application::log::access::Entry;
+use crate::application::log::error::Context;
+use crate::application::metrics::ConnInfo;
+use crate::application::server::dispatch::Pending;
+use crate::config::Config;
 
 pub enum ConnState {
     Reading,
     Writing,
     Closing,
+    /// The request is being handled on the blocking pool
+    Dispatching,
     Cgi {
         pid: i32,
         input: Option<i32>,
         output: i32,
+        /// Closed once the process closes its stderr
+        errors: Option<i32>,
         data: Vec<u8>,
+        /// Start of a stderr line still missing its newline
+        stderr: Vec<u8>,
+        /// Set while it answers an `auth_request` subrequest: the request
+        /// that goes on with its response
+        subrequest: Option<Box<Pending>>,
     },
 }
 
+impl ConnState {
+    /// Names of the states, in the order of `index`
+    pub const NAMES: [&'static str; 5] = ["reading", "dispatching", "cgi", "writing", "closing"];
+
+    pub fn index(&self) -> usize {
+        match self {
+            ConnState::Reading => 0,
+            ConnState::Dispatching => 1,
+            ConnState::Cgi { .. } => 2,
+            ConnState::Writing => 3,
+            ConnState::Closing => 4,
+        }
+    }
+}
+
+/// Source of `Connection::id`.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
 pub struct Connection {
     pub fd: Fd,
     pub fd_raw: i32,
+    /// Unlike the fd, never reused: tells a pool result for a connection
+    /// closed in the meantime from one for the connection now on that fd
+    pub id: u64,
     pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
     /// Per-connection read buffer (NGINX-style, filled by one read per event)
     pub read_buf: Vec<u8>,
     /// Per-connection write buffer (NGINX-style, drained by one write per event)
     pub write_buf: Vec<u8>,
     pub state: ConnState,
+    /// When it was accepted
+    pub opened: Instant,
     pub last_activity: Instant,
     pub keep_alive: bool,
     pub timeout: Duration,
+    /// Config the current request is served under; a reload only affects
+    /// requests that start after it
+    pub config: Arc<Config>,
+    /// When the first byte of the request being read arrived
+    pub read_started: Option<Instant>,
+    /// Access log record of the request being served
+    pub log: Option<Entry>,
 }
 
 impl Connection {
-    pub fn new(fd: Fd, local_addr: SocketAddr, timeout: Duration) -> Self {
+    pub fn new(fd: Fd, local_addr: SocketAddr, remote_addr: SocketAddr, timeout: Duration, config: Arc<Config>) -> Self {
         let fd_raw = fd.as_raw_fd();
         Self {
             fd,
             fd_raw,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
             local_addr,
+            remote_addr,
             read_buf: Vec::with_capacity(8192), // 8KB buffer, typical for NGINX
             write_buf: Vec::new(),
             state: ConnState::Reading,
+            opened: Instant::now(),
             last_activity: Instant::now(),
             keep_alive: true,
             timeout,
+            config,
+            read_started: None,
+            log: None,
+        }
+    }
+
+    /// Error log context: the connection, and the request once it is parsed.
+    pub fn context(&self) -> Context {
+        match &self.log {
+            Some(entry) => entry.context(self.fd_raw),
+            None => Context { fd: Some(self.fd_raw), client: Some(self.remote_addr), ..Context::default() },
+        }
+    }
+
+    /// What the status page shows about it.
+    pub fn info(&self) -> ConnInfo {
+        ConnInfo {
+            client: self.remote_addr,
+            state: ConnState::NAMES[self.state.index()],
+            path: self.log.as_ref().map(|entry| entry.path.clone()).unwrap_or_default(),
+            opened: self.opened,
         }
     }
 
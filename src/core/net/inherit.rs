@@ -0,0 +1,102 @@
+use std::env;
+use std::net::SocketAddr;
+
+use libc::pid_t;
+
+use super::fd::Fd;
+use super::socket::adopt_listening_socket;
+
+/// Listening fds passed to a new binary on upgrade, as `3,4,5`.
+pub const LISTENERS_ENV: &str = "LOCALHOST_LISTENERS";
+/// Pid of the process being upgraded, told to drain once the new one is up.
+pub const UPGRADE_FROM_ENV: &str = "LOCALHOST_UPGRADE_FROM";
+
+/// First fd passed by systemd socket activation.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+pub struct Inherited {
+    pub listeners: Vec<(SocketAddr, Fd)>,
+    pub upgrade_from: Option<pid_t>,
+}
+
+/// Adopts the listening sockets left open by a previous server process or by
+/// systemd (`LISTEN_FDS`/`LISTEN_PID`). The variables are removed so that
+/// CGI children do not see them. Must run before any thread is started.
+pub fn take_inherited() -> Result<Inherited, String> {
+    let passed = take_var(LISTENERS_ENV);
+    let upgrade_from = take_var(UPGRADE_FROM_ENV);
+    let listen_pid = take_var("LISTEN_PID");
+    let listen_fds = take_var("LISTEN_FDS");
+    take_var("LISTEN_FDNAMES");
+
+    let fds = inherited_fds(passed.as_deref(), listen_pid.as_deref(), listen_fds.as_deref(), std::process::id())?;
+    let upgrade_from = upgrade_from.as_deref().map(parse_upgrade_from).transpose()?;
+    let mut listeners = Vec::new();
+    for fd in fds {
+        listeners.push(adopt_listening_socket(fd).map_err(|e| format!("cannot use inherited fd {fd}: {e}"))?);
+    }
+    Ok(Inherited { listeners, upgrade_from })
+}
+
+/// The fds listed in `LOCALHOST_LISTENERS`, then those systemd passed to
+/// process `pid`.
+fn inherited_fds(passed: Option<&str>, listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Result<Vec<i32>, String> {
+    let mut fds = Vec::new();
+    for item in passed.unwrap_or("").split(',').filter(|s| !s.is_empty()) {
+        let fd = item.parse().ok().filter(|&fd| fd >= 0).ok_or_else(|| format!("invalid fd {item:?} in {LISTENERS_ENV}"))?;
+        fds.push(fd);
+    }
+    // LISTEN_PID guards against variables meant for a parent process
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) == Some(pid) {
+        let count: u16 = listen_fds
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| format!("invalid LISTEN_FDS {listen_fds:?}"))?;
+        fds.extend(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + i32::from(count));
+    }
+    Ok(fds)
+}
+
+fn parse_upgrade_from(pid: &str) -> Result<pid_t, String> {
+    match pid.parse() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => Err(format!("invalid pid {pid:?} in {UPGRADE_FROM_ENV}")),
+    }
+}
+
+fn take_var(name: &str) -> Option<String> {
+    let value = env::var(name).ok()?;
+    // Safe while single-threaded, which `take_inherited` requires
+    unsafe { env::remove_var(name) };
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inherited_fds() {
+        assert_eq!(inherited_fds(None, None, None, 100), Ok(vec![]));
+        assert_eq!(inherited_fds(Some("5,6,"), None, None, 100), Ok(vec![5, 6]));
+        for bad in ["5,x", "-1", "5;6"] {
+            assert!(inherited_fds(Some(bad), None, None, 100).is_err(), "{bad}");
+        }
+
+        // systemd's fds start at 3, and only count for the process they were meant for
+        assert_eq!(inherited_fds(None, Some("100"), Some("2"), 100), Ok(vec![3, 4]));
+        assert_eq!(inherited_fds(Some("7"), Some("100"), Some("1"), 100), Ok(vec![7, 3]));
+        assert_eq!(inherited_fds(None, Some("99"), Some("2"), 100), Ok(vec![]));
+        assert_eq!(inherited_fds(None, Some("abc"), Some("2"), 100), Ok(vec![]));
+        for bad in [None, Some("-2"), Some("two")] {
+            assert!(inherited_fds(None, Some("100"), bad, 100).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn test_upgrade_from() {
+        assert_eq!(parse_upgrade_from("4242"), Ok(4242));
+        for bad in ["0", "-5", "", "12ab"] {
+            assert!(parse_upgrade_from(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -1,3 +1,4 @@
 pub mod fd;
 pub mod socket;
+pub mod inherit;
 pub mod connection;
\ No newline at end of file
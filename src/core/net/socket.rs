@@ -1,6 +1,6 @@
 use libc::{
-    accept, bind, c_int, fcntl, listen, sa_family_t, setsockopt, socket, sockaddr, sockaddr_in,
-    sockaddr_in6, sockaddr_storage, socklen_t, AF_INET, AF_INET6, F_GETFL, F_SETFL, O_NONBLOCK,
+    accept, bind, c_int, fcntl, getsockname, listen, sa_family_t, setsockopt, socket, sockaddr, sockaddr_in,
+    sockaddr_in6, sockaddr_storage, socklen_t, AF_INET, AF_INET6, FD_CLOEXEC, F_GETFL, F_SETFD, F_SETFL, O_NONBLOCK,
     SOCK_STREAM, SOL_SOCKET, SO_LINGER, SO_NOSIGPIPE, SO_REUSEADDR,
 };
 
@@ -54,6 +54,7 @@ pub fn create_listening_socket(addr: SocketAddr) -> Result<Fd, String> {
     }
 
     set_nonblocking(fd)?;
+    set_cloexec(fd)?;
 
     let res = unsafe {
         bind(
@@ -77,7 +78,24 @@ pub fn create_listening_socket(addr: SocketAddr) -> Result<Fd, String> {
     Ok(Fd(fd))
 }
 
-pub fn accept_nonblocking(listen_fd: RawFd) -> Result<Option<Fd>, String> {
+/// Takes over a listening socket opened by another process and returns the
+/// address it is bound to.
+pub fn adopt_listening_socket(fd: RawFd) -> Result<(SocketAddr, Fd), String> {
+    let mut addr: sockaddr_storage = unsafe { zeroed() };
+    let mut len = size_of::<sockaddr_storage>() as socklen_t;
+    if unsafe { getsockname(fd, &mut addr as *mut _ as *mut sockaddr, &mut len) } < 0 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+    let Some(local) = from_sockaddr(&addr) else {
+        return Err("not an IPv4 or IPv6 socket".into());
+    };
+    set_nonblocking(fd)?;
+    set_cloexec(fd)?;
+    Ok((local, Fd(fd)))
+}
+
+/// Accepts one pending connection, returning it with the peer's address.
+pub fn accept_nonblocking(listen_fd: RawFd) -> Result<Option<(Fd, SocketAddr)>, String> {
     let mut addr: sockaddr_storage = unsafe { zeroed() };
     let mut len = size_of::<sockaddr_storage>() as socklen_t;
     let fd = unsafe {
@@ -97,7 +115,12 @@ pub fn accept_nonblocking(listen_fd: RawFd) -> Result<Option<Fd>, String> {
         }
         return Err(err.to_string());
     }
+    let Some(peer) = from_sockaddr(&addr) else {
+        unsafe { libc::close(fd) };
+        return Err("accept: unsupported address family".into());
+    };
     set_nonblocking(fd)?;
+    set_cloexec(fd)?;
     let yes: i32 = 1;
     unsafe {
         libc::setsockopt(
@@ -126,7 +149,7 @@ pub fn accept_nonblocking(listen_fd: RawFd) -> Result<Option<Fd>, String> {
             size_of::<linger>() as socklen_t,
         );
     }
-    Ok(Some(Fd(fd)))
+    Ok(Some((Fd(fd), peer)))
 }
 
 fn set_nonblocking(fd: RawFd) -> Result<(), String> {
@@ -140,6 +163,31 @@ fn set_nonblocking(fd: RawFd) -> Result<(), String> {
     Ok(())
 }
 
+/// Keeps the socket out of CGI children, which would otherwise hold it open
+/// after the server closes it.
+fn set_cloexec(fd: RawFd) -> Result<(), String> {
+    if unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+fn from_sockaddr(storage: &sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as c_int {
+        AF_INET => {
+            let sa = unsafe { &*(storage as *const _ as *const sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr));
+            Some(SocketAddr::new(ip.into(), u16::from_be(sa.sin_port)))
+        }
+        AF_INET6 => {
+            let sa = unsafe { &*(storage as *const _ as *const sockaddr_in6) };
+            let ip = std::net::Ipv6Addr::from(sa.sin6_addr.s6_addr);
+            Some(SocketAddr::new(ip.into(), u16::from_be(sa.sin6_port)))
+        }
+        _ => None,
+    }
+}
+
 fn to_sockaddr(addr: &SocketAddr) -> Result<(sockaddr_storage, socklen_t, c_int), String> {
     let mut storage: sockaddr_storage = unsafe { zeroed() };
     match addr {
@@ -0,0 +1,71 @@
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A few threads for work that blocks, such as reading files, so that the
+/// event loop threads never wait on it. Jobs run in the order they were
+/// queued; the threads exit when the pool is dropped.
+pub struct BlockingPool {
+    jobs: Sender<Job>,
+}
+
+impl BlockingPool {
+    pub fn new(threads: usize) -> Result<Self, String> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("blocking-{i}"))
+                .spawn(move || {
+                    loop {
+                        // The lock is only held while waiting for the next job
+                        let job = match queue.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                            Ok(job) => job,
+                            Err(_) => return,
+                        };
+                        job();
+                    }
+                })
+                .map_err(|e| format!("cannot start a blocking thread: {e}"))?;
+        }
+        Ok(BlockingPool { jobs })
+    }
+
+    pub fn run(&self, job: impl FnOnce() + Send + 'static) {
+        let _ = self.jobs.send(Box::new(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_jobs_run_in_order() {
+        let pool = BlockingPool::new(1).unwrap();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..5 {
+            let tx = tx.clone();
+            pool.run(move || tx.send(i).unwrap());
+        }
+        let got: Vec<i32> = (0..5).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(got, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_blocked_job_does_not_hold_others() {
+        let pool = BlockingPool::new(2).unwrap();
+        let (release, wait) = mpsc::channel::<()>();
+        let (tx, rx) = mpsc::channel();
+        pool.run(move || {
+            let _ = wait.recv();
+        });
+        pool.run(move || tx.send("done").unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("done"));
+        release.send(()).unwrap();
+    }
+}
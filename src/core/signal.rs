@@ -0,0 +1,48 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libc::{c_int, sigaction, sigemptyset, SA_RESTART};
+
+/// Bit `n` is set when signal `n` arrived and has not been taken yet.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_signal(sig: c_int) {
+    PENDING.fetch_or(1 << sig, Ordering::SeqCst);
+}
+
+/// Replaces the default action of `signals` with a handler that only records
+/// them; the event loop picks them up with `take`.
+pub fn install(signals: &[c_int]) -> Result<(), String> {
+    for &sig in signals {
+        unsafe {
+            let mut action: sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as usize;
+            action.sa_flags = SA_RESTART;
+            sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(sig, &action, std::ptr::null_mut()) < 0 {
+                return Err(io::Error::last_os_error().to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f` with every signal blocked in the calling thread. Threads spawned
+/// by `f` start with them blocked, so signals keep going to this thread,
+/// whose event loop checks for them.
+pub fn blocked<T>(f: impl FnOnce() -> T) -> T {
+    unsafe {
+        let mut all: libc::sigset_t = std::mem::zeroed();
+        let mut previous: libc::sigset_t = std::mem::zeroed();
+        libc::sigfillset(&mut all);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &all, &mut previous);
+        let result = f();
+        libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+        result
+    }
+}
+
+/// Returns whether `sig` arrived since the last call, and clears it.
+pub fn take(sig: c_int) -> bool {
+    PENDING.fetch_and(!(1 << sig), Ordering::SeqCst) & (1 << sig) != 0
+}
@@ -0,0 +1,147 @@
+use std::io;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+use super::net::connection::ConnState;
+
+/// Number of connection states counted per worker.
+pub const STATES: usize = ConnState::NAMES.len();
+
+/// Counters a worker process publishes about itself.
+#[repr(C)]
+pub struct WorkerSlot {
+    /// 0 when the slot is free
+    pub pid: AtomicI32,
+    /// Client connections currently open
+    pub connections: AtomicU64,
+    /// Requests read since the worker started
+    pub requests: AtomicU64,
+    /// Open connections by `ConnState::index`
+    pub states: [AtomicU64; STATES],
+}
+
+/// One slot per worker in memory shared by the master and all workers. It is
+/// mapped before the first fork, so every process sees the same slots.
+#[derive(Clone, Copy)]
+pub struct WorkerTable {
+    slots: &'static [WorkerSlot],
+}
+
+impl WorkerTable {
+    pub fn new(len: usize) -> Result<Self, String> {
+        // Zeroed, which is a free slot
+        let slots = map_shared(len).map_err(|e| format!("cannot map the worker table: {e}"))?;
+        Ok(WorkerTable { slots })
+    }
+
+    /// Reserves a free slot for a worker about to be forked.
+    pub fn claim(&self) -> Option<usize> {
+        let index = self.slots.iter().position(|s| s.pid.load(Ordering::Relaxed) == 0)?;
+        let slot = &self.slots[index];
+        slot.pid.store(-1, Ordering::Relaxed);
+        slot.connections.store(0, Ordering::Relaxed);
+        slot.requests.store(0, Ordering::Relaxed);
+        for state in &slot.states {
+            state.store(0, Ordering::Relaxed);
+        }
+        Some(index)
+    }
+
+    pub fn release(&self, index: usize) {
+        self.slots[index].pid.store(0, Ordering::Relaxed);
+    }
+
+    pub fn slot(&self, index: usize) -> &'static WorkerSlot {
+        &self.slots[index]
+    }
+
+    /// Sums the slots in use: (workers, open connections, requests).
+    pub fn totals(&self) -> (usize, u64, u64) {
+        let used = self.slots.iter().filter(|s| s.pid.load(Ordering::Relaxed) > 0);
+        used.fold((0, 0, 0), |(w, c, r), s| {
+            (w + 1, c + s.connections.load(Ordering::Relaxed), r + s.requests.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Sums the open connections of the slots in use by state.
+    pub fn states(&self) -> [u64; STATES] {
+        let mut totals = [0; STATES];
+        for slot in self.slots.iter().filter(|s| s.pid.load(Ordering::Relaxed) > 0) {
+            for (total, state) in totals.iter_mut().zip(&slot.states) {
+                *total += state.load(Ordering::Relaxed);
+            }
+        }
+        totals
+    }
+}
+
+/// Maps `len` zeroed values of `T` in memory shared with the processes
+/// forked afterwards. `T` must be valid when zeroed, like atomics. The
+/// mapping lives as long as the process.
+pub fn map_shared<T>(len: usize) -> Result<&'static [T], io::Error> {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len * size_of::<T>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANON,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { std::slice::from_raw_parts(ptr as *const T, len) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_and_release() {
+        let table = WorkerTable::new(2).unwrap();
+        let (a, b) = (table.claim().unwrap(), table.claim().unwrap());
+        assert_ne!(a, b);
+        assert_eq!(table.claim(), None);
+
+        // A claimed slot only counts once its worker has set its pid
+        table.slot(a).connections.store(5, Ordering::Relaxed);
+        assert_eq!(table.totals(), (0, 0, 0));
+        table.slot(a).pid.store(100, Ordering::Relaxed);
+        table.slot(a).requests.store(7, Ordering::Relaxed);
+        table.slot(a).states[1].store(2, Ordering::Relaxed);
+        table.slot(b).pid.store(101, Ordering::Relaxed);
+        table.slot(b).connections.store(1, Ordering::Relaxed);
+        table.slot(b).states[1].store(1, Ordering::Relaxed);
+        assert_eq!(table.totals(), (2, 6, 7));
+        assert_eq!(table.states()[1], 3);
+
+        // A released slot is reused with its counters cleared
+        table.release(a);
+        assert_eq!(table.totals(), (1, 1, 0));
+        assert_eq!(table.claim(), Some(a));
+        assert_eq!(table.slot(a).requests.load(Ordering::Relaxed), 0);
+        assert_eq!(table.slot(a).states[1].load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_shared_with_children() {
+        let table = WorkerTable::new(1).unwrap();
+        let index = table.claim().unwrap();
+        match unsafe { libc::fork() } {
+            0 => {
+                let slot = table.slot(index);
+                slot.pid.store(unsafe { libc::getpid() }, Ordering::Relaxed);
+                slot.requests.store(3, Ordering::Relaxed);
+                unsafe { libc::_exit(0) };
+            }
+            pid => {
+                assert!(pid > 0);
+                unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+                assert_eq!(table.slot(index).pid.load(Ordering::Relaxed), pid);
+                assert_eq!(table.totals(), (1, 0, 3));
+            }
+        }
+    }
+}
@@ -89,6 +89,7 @@ pub fn parse_request(buf: &[u8], body_limit: usize) -> ParseResult {
                     Request {
                         method,
                         path,
+                        version,
                         headers,
                         body,
                         content_length: None,
@@ -121,6 +122,7 @@ pub fn parse_request(buf: &[u8], body_limit: usize) -> ParseResult {
             Request {
                 method,
                 path,
+                version,
                 headers,
                 body,
                 content_length,
@@ -5,8 +5,36 @@ use super::method::Method;
 pub struct Request {
     pub method: Method,
     pub path: String,
+    /// `HTTP/1.0` or `HTTP/1.1`, as sent
+    pub version: String,
     pub headers: Headers,
     pub body: Vec<u8>,
     pub content_length: Option<usize>,
     pub keep_alive: bool,
-}
\ No newline at end of file
+}
+
+impl Request {
+    /// Case-insensitive header lookup.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg(test)]
+impl Request {
+    /// A keep-alive HTTP/1.1 GET without a body, for handler tests.
+    pub fn get(path: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: Method::Get,
+            path: path.into(),
+            version: "HTTP/1.1".into(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: Vec::new(),
+            content_length: None,
+            keep_alive: true,
+        }
+    }
+}
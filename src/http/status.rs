@@ -1,41 +1,87 @@
 #[derive(Debug, Clone, Copy)]
 pub enum StatusCode {
     Ok,
+    NoContent,
     MovedPermanently,
+    Found,
     SeeOther,
+    TemporaryRedirect,
+    PermanentRedirect,
     BadRequest,
+    Unauthorized,
     Forbidden,
     NotFound,
     MethodNotAllowed,
+    Gone,
     PayloadTooLarge,
     InternalServerError,
+    ServiceUnavailable,
 }
 
 impl StatusCode {
+    pub fn from_u16(code: u16) -> Option<Self> {
+        match code {
+            200 => Some(StatusCode::Ok),
+            204 => Some(StatusCode::NoContent),
+            301 => Some(StatusCode::MovedPermanently),
+            302 => Some(StatusCode::Found),
+            303 => Some(StatusCode::SeeOther),
+            307 => Some(StatusCode::TemporaryRedirect),
+            308 => Some(StatusCode::PermanentRedirect),
+            400 => Some(StatusCode::BadRequest),
+            401 => Some(StatusCode::Unauthorized),
+            403 => Some(StatusCode::Forbidden),
+            404 => Some(StatusCode::NotFound),
+            405 => Some(StatusCode::MethodNotAllowed),
+            410 => Some(StatusCode::Gone),
+            413 => Some(StatusCode::PayloadTooLarge),
+            500 => Some(StatusCode::InternalServerError),
+            503 => Some(StatusCode::ServiceUnavailable),
+            _ => None,
+        }
+    }
     pub fn as_u16(self) -> u16 {
         match self {
             StatusCode::Ok => 200,
+            StatusCode::NoContent => 204,
             StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
             StatusCode::SeeOther => 303,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
             StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
             StatusCode::Forbidden => 403,
             StatusCode::NotFound => 404,
             StatusCode::MethodNotAllowed => 405,
+            StatusCode::Gone => 410,
             StatusCode::PayloadTooLarge => 413,
             StatusCode::InternalServerError => 500,
+            StatusCode::ServiceUnavailable => 503,
         }
     }
+    /// 3xx codes that carry a `Location` header.
+    pub fn is_redirect(self) -> bool {
+        matches!(self.as_u16(), 301..=303 | 307 | 308)
+    }
     pub fn reason(self) -> &'static str {
         match self {
             StatusCode::Ok => "OK",
+            StatusCode::NoContent => "No Content",
             StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
             StatusCode::SeeOther => "See Other",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
             StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
             StatusCode::Forbidden => "Forbidden",
             StatusCode::NotFound => "Not Found",
             StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::Gone => "Gone",
             StatusCode::PayloadTooLarge => "Payload Too Large",
             StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
         }
     }
 }
\ No newline at end of file